@@ -0,0 +1,442 @@
+extern crate beanstalkdrs_client;
+extern crate serde;
+
+#[macro_use]
+extern crate serde_json;
+
+use std::env;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::process;
+
+use beanstalkdrs_client::{Client, Error, Job};
+use serde::Serialize;
+use serde_json::Value;
+
+const DEFAULT_ADDR: &str = "127.0.0.1:11300";
+// Where the password is taken from unless given with --password, keeping it
+// out of the process list
+const PASSWORD_VAR: &str = "BEANSTALK_PASSWORD";
+
+const USAGE: &str = "\
+Usage: beanstalk-cli [--addr HOST:PORT] [--user USER [--password PASSWORD]] [--json] COMMAND [ARGS]
+
+Commands:
+    put [--tube TUBE] [--pri PRI] [--delay SECONDS] [--ttr SECONDS] [FILE]
+                            put the contents of FILE (or stdin) as a job
+    peek ID                 show a job
+    peek-ready [TUBE]       show the next ready job
+    peek-delayed [TUBE]     show the next delayed job
+    peek-buried [TUBE]      show the next buried job
+    delete ID               delete a job
+    kick BOUND [TUBE]       kick up to BOUND buried or delayed jobs
+    kick-job ID             kick a single job
+    pause TUBE SECONDS      stop handing out jobs from a tube for a while
+    list-tubes              list existing tubes
+    stats                   show server statistics
+    stats-tube TUBE         show tube statistics
+    stats-job ID            show job statistics
+    drain                   make the server refuse new jobs
+    undrain                 make the server accept new jobs again
+
+Options:
+    --addr HOST:PORT        server to talk to (default 127.0.0.1:11300)
+    --user USER             authenticate as USER after connecting
+    --password PASSWORD     password of USER (default $BEANSTALK_PASSWORD)
+    --json                  print JSON instead of text
+";
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    Put {tube: Option<String>, pri: u32, delay: u32, ttr: u32, file: Option<String>},
+    Peek {id: u64},
+    PeekReady {tube: Option<String>},
+    PeekDelayed {tube: Option<String>},
+    PeekBuried {tube: Option<String>},
+    Delete {id: u64},
+    Kick {bound: u64, tube: Option<String>},
+    KickJob {id: u64},
+    Pause {tube: String, delay: u32},
+    ListTubes,
+    Stats,
+    StatsTube {tube: String},
+    StatsJob {id: u64},
+    Drain,
+    Undrain,
+}
+
+#[derive(Debug, PartialEq)]
+struct Options {
+    addr: String,
+    user: Option<String>,
+    password: Option<String>,
+    json: bool,
+    command: Command,
+}
+
+// What a command prints, in a shape that can be rendered as text or JSON
+#[derive(Debug, PartialEq)]
+enum Output {
+    Message(String, Value),
+    Job(Job),
+    List(Vec<String>),
+    Table(Value),
+}
+
+fn main() {
+    let options = match parse_args(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("error: {}\n\n{}", message, USAGE);
+            process::exit(2);
+        },
+    };
+
+    let Options {addr, user, password, json, command} = options;
+    let credentials = match (user, password.or_else(|| env::var(PASSWORD_VAR).ok())) {
+        (Some(user), Some(password)) => Some((user, password)),
+        (Some(_), None) => {
+            eprintln!("error: --user needs --password or {} to be set\n\n{}", PASSWORD_VAR, USAGE);
+            process::exit(2);
+        },
+        (None, _) => None,
+    };
+
+    let output = Client::connect(addr.as_str()).and_then(|mut client| {
+        if let Some((user, password)) = credentials {
+            client.auth(&user, &password)?;
+        }
+        run(&mut client, command)
+    });
+
+    match output {
+        Ok(output) => {
+            let stdout = io::stdout();
+            let mut stdout = stdout.lock();
+            let _ = stdout.write_all(&render(output, json));
+        },
+        Err(err) => {
+            eprintln!("error: {}", err);
+            process::exit(1);
+        },
+    }
+}
+
+fn parse_args<I: Iterator<Item = String>>(args: I) -> Result<Options, String> {
+    let mut addr = DEFAULT_ADDR.to_string();
+    let mut user = None;
+    let mut password = None;
+    let mut json = false;
+    let mut args = args.peekable();
+
+    loop {
+        match args.peek().map(|arg| arg.as_str()) {
+            Some("--addr") => {
+                args.next();
+                addr = args.next().ok_or("--addr needs a value")?;
+            },
+            Some("--user") => {
+                args.next();
+                user = Some(args.next().ok_or("--user needs a value")?);
+            },
+            Some("--password") => {
+                args.next();
+                password = Some(args.next().ok_or("--password needs a value")?);
+            },
+            Some("--json") => {
+                args.next();
+                json = true;
+            },
+            Some("--help") | Some("-h") | None => return Err("no command given".to_string()),
+            Some(_) => break,
+        }
+    }
+
+    let name = args.next().unwrap();
+    let mut rest: Vec<String> = args.collect();
+
+    let command = match name.as_str() {
+        "put" => {
+            let tube = take_option(&mut rest, "--tube")?;
+            let pri = take_option(&mut rest, "--pri")?.map_or(Ok(1024), |pri| number(&pri))?;
+            let delay = take_option(&mut rest, "--delay")?.map_or(Ok(0), |delay| number(&delay))?;
+            let ttr = take_option(&mut rest, "--ttr")?.map_or(Ok(60), |ttr| number(&ttr))?;
+            let file = optional(&mut rest).filter(|file| file != "-");
+
+            Command::Put {tube, pri, delay, ttr, file}
+        },
+        "peek" => Command::Peek {id: number(&required(&mut rest, "ID")?)?},
+        "peek-ready" => Command::PeekReady {tube: optional(&mut rest)},
+        "peek-delayed" => Command::PeekDelayed {tube: optional(&mut rest)},
+        "peek-buried" => Command::PeekBuried {tube: optional(&mut rest)},
+        "delete" => Command::Delete {id: number(&required(&mut rest, "ID")?)?},
+        "kick" => {
+            let bound = number(&required(&mut rest, "BOUND")?)?;
+            Command::Kick {bound, tube: optional(&mut rest)}
+        },
+        "kick-job" => Command::KickJob {id: number(&required(&mut rest, "ID")?)?},
+        "pause" => {
+            let tube = required(&mut rest, "TUBE")?;
+            let delay = number(&required(&mut rest, "SECONDS")?)?;
+            Command::Pause {tube, delay}
+        },
+        "list-tubes" => Command::ListTubes,
+        "stats" => Command::Stats,
+        "stats-tube" => Command::StatsTube {tube: required(&mut rest, "TUBE")?},
+        "stats-job" => Command::StatsJob {id: number(&required(&mut rest, "ID")?)?},
+        "drain" => Command::Drain,
+        "undrain" => Command::Undrain,
+        other => return Err(format!("unknown command {}", other)),
+    };
+
+    if !rest.is_empty() {
+        return Err(format!("unexpected arguments: {}", rest.join(" ")));
+    }
+
+    if password.is_some() && user.is_none() {
+        return Err("--password needs --user".to_string());
+    }
+
+    Ok(Options {addr, user, password, json, command})
+}
+
+// Removes `--name value` from the arguments and returns the value.
+fn take_option(args: &mut Vec<String>, name: &str) -> Result<Option<String>, String> {
+    match args.iter().position(|arg| arg == name) {
+        Some(pos) if pos + 1 < args.len() => {
+            args.remove(pos);
+            Ok(Some(args.remove(pos)))
+        },
+        Some(_) => Err(format!("{} needs a value", name)),
+        None => Ok(None),
+    }
+}
+
+fn required(args: &mut Vec<String>, name: &str) -> Result<String, String> {
+    optional(args).ok_or_else(|| format!("missing {}", name))
+}
+
+fn optional(args: &mut Vec<String>) -> Option<String> {
+    if args.is_empty() {
+        None
+    } else {
+        Some(args.remove(0))
+    }
+}
+
+fn number<T: std::str::FromStr>(arg: &str) -> Result<T, String> {
+    arg.parse().map_err(|_| format!("{} is not a valid number", arg))
+}
+
+fn run<S: Read + Write>(client: &mut Client<S>, command: Command) -> Result<Output, Error> {
+    match command {
+        Command::Put {tube, pri, delay, ttr, file} => {
+            if let Some(tube) = tube {
+                client.use_tube(&tube)?;
+            }
+
+            let mut data = vec![];
+            match file {
+                Some(path) => File::open(path)?.read_to_end(&mut data)?,
+                None => io::stdin().read_to_end(&mut data)?,
+            };
+
+            let id = client.put(pri, delay, ttr, &data)?;
+            Ok(Output::Message(format!("inserted job {}", id), json!({"id": id})))
+        },
+        Command::Peek {id} => Ok(Output::Job(client.peek(id)?)),
+        Command::PeekReady {tube} => {
+            use_tube(client, tube)?;
+            Ok(Output::Job(client.peek_ready()?))
+        },
+        Command::PeekDelayed {tube} => {
+            use_tube(client, tube)?;
+            Ok(Output::Job(client.peek_delayed()?))
+        },
+        Command::PeekBuried {tube} => {
+            use_tube(client, tube)?;
+            Ok(Output::Job(client.peek_buried()?))
+        },
+        Command::Delete {id} => {
+            client.delete(id)?;
+            Ok(Output::Message(format!("deleted job {}", id), json!({"deleted": id})))
+        },
+        Command::Kick {bound, tube} => {
+            use_tube(client, tube)?;
+            let count = client.kick(bound)?;
+            Ok(Output::Message(format!("kicked {} jobs", count), json!({"kicked": count})))
+        },
+        Command::KickJob {id} => {
+            client.kick_job(id)?;
+            Ok(Output::Message(format!("kicked job {}", id), json!({"kicked": id})))
+        },
+        Command::Pause {tube, delay} => {
+            client.pause_tube(&tube, delay)?;
+            Ok(Output::Message(
+                format!("paused tube {} for {} seconds", tube, delay),
+                json!({"paused": tube, "delay": delay})
+            ))
+        },
+        Command::ListTubes => Ok(Output::List(client.list_tubes()?)),
+        Command::Stats => Ok(Output::Table(table(&client.stats()?))),
+        Command::StatsTube {tube} => Ok(Output::Table(table(&client.stats_tube(&tube)?))),
+        Command::StatsJob {id} => Ok(Output::Table(table(&client.stats_job(id)?))),
+        Command::Drain => {
+            client.drain()?;
+            Ok(Output::Message("draining".to_string(), json!({"draining": true})))
+        },
+        Command::Undrain => {
+            client.undrain()?;
+            Ok(Output::Message("not draining".to_string(), json!({"draining": false})))
+        },
+    }
+}
+
+fn use_tube<S: Read + Write>(client: &mut Client<S>, tube: Option<String>) -> Result<(), Error> {
+    if let Some(tube) = tube {
+        client.use_tube(&tube)?;
+    }
+
+    Ok(())
+}
+
+fn table<T: Serialize>(stats: &T) -> Value {
+    serde_json::to_value(stats).expect("stats are always representable as JSON")
+}
+
+fn render(output: Output, json: bool) -> Vec<u8> {
+    if json {
+        let value = match output {
+            Output::Message(_, value) | Output::Table(value) => value,
+            Output::Job(job) => json!({
+                "id": job.id,
+                "data": String::from_utf8_lossy(&job.data),
+            }),
+            Output::List(items) => json!(items),
+        };
+
+        return format!("{}\n", value).into_bytes();
+    }
+
+    match output {
+        Output::Message(message, _) => format!("{}\n", message).into_bytes(),
+        // The body goes out untouched so it can be piped somewhere else
+        Output::Job(job) => job.data,
+        Output::List(items) => items.iter()
+            .map(|item| format!("{}\n", item))
+            .collect::<String>()
+            .into_bytes(),
+        Output::Table(value) => {
+            let mut text = String::new();
+            render_table(&value, "", &mut text);
+            text.into_bytes()
+        },
+    }
+}
+
+// Prints keys and values aligned in columns, nested objects are indented.
+fn render_table(value: &Value, indent: &str, text: &mut String) {
+    let fields = match *value {
+        Value::Object(ref fields) => fields,
+        ref other => {
+            text.push_str(&format!("{}{}\n", indent, other));
+            return;
+        },
+    };
+
+    let width = fields.keys().map(|key| key.len()).max().unwrap_or(0);
+
+    for (key, value) in fields {
+        match *value {
+            Value::Object(_) => {
+                text.push_str(&format!("{}{}:\n", indent, key));
+                render_table(value, &format!("{}  ", indent), text);
+            },
+            Value::String(ref string) => {
+                text.push_str(&format!("{}{:width$}  {}\n", indent, key, string, width = width));
+            },
+            ref other => {
+                text.push_str(&format!("{}{:width$}  {}\n", indent, key, other, width = width));
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Result<Options, String> {
+        parse_args(line.split_whitespace().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn parsing_global_options() {
+        assert_eq!(
+            args("--json --addr 10.0.0.1:11300 --user worker --password secret stats"),
+            Ok(Options {
+                addr: "10.0.0.1:11300".to_string(),
+                user: Some("worker".to_string()),
+                password: Some("secret".to_string()),
+                json: true,
+                command: Command::Stats,
+            })
+        );
+        assert_eq!(args("stats").unwrap().addr, DEFAULT_ADDR);
+        assert_eq!(args("stats").unwrap().user, None);
+        assert!(args("").is_err());
+        assert!(args("--addr").is_err());
+        assert!(args("--user").is_err());
+        assert!(args("--password secret stats").is_err());
+    }
+
+    #[test]
+    fn parsing_put() {
+        assert_eq!(
+            args("put --ttr 10 --tube emails job.json").unwrap().command,
+            Command::Put {
+                tube: Some("emails".to_string()),
+                pri: 1024,
+                delay: 0,
+                ttr: 10,
+                file: Some("job.json".to_string()),
+            }
+        );
+        assert_eq!(
+            args("put -").unwrap().command,
+            Command::Put {tube: None, pri: 1024, delay: 0, ttr: 60, file: None}
+        );
+        assert!(args("put --pri high").is_err());
+    }
+
+    #[test]
+    fn parsing_commands_with_arguments() {
+        assert_eq!(args("kick 10 emails").unwrap().command, Command::Kick {bound: 10, tube: Some("emails".to_string())});
+        assert_eq!(args("pause emails 30").unwrap().command, Command::Pause {tube: "emails".to_string(), delay: 30});
+        assert!(args("delete").is_err());
+        assert!(args("delete 1 2").is_err());
+        assert!(args("frobnicate").is_err());
+    }
+
+    #[test]
+    fn rendering_tables_aligns_values() {
+        let value = json!({"id": 1, "state": "ready", "commands": {"put": 2}});
+
+        assert_eq!(
+            String::from_utf8(render(Output::Table(value.clone()), false)).unwrap(),
+            "id        1\nstate     ready\ncommands:\n  put  2\n"
+        );
+        assert_eq!(
+            String::from_utf8(render(Output::Table(value), true)).unwrap(),
+            "{\"id\":1,\"state\":\"ready\",\"commands\":{\"put\":2}}\n"
+        );
+    }
+
+    #[test]
+    fn rendering_jobs() {
+        let job = Job {id: 3, data: b"body".to_vec()};
+
+        assert_eq!(render(Output::Job(job.clone()), false), b"body".to_vec());
+        assert_eq!(render(Output::Job(job), true), b"{\"id\":3,\"data\":\"body\"}\n".to_vec());
+    }
+}
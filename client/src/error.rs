@@ -0,0 +1,107 @@
+use std::error;
+use std::fmt;
+use std::io;
+
+use response::Response;
+
+// Everything a command can fail with. Besides I/O problems there is a variant
+// for each error line of the protocol, and ones for arguments that are refused
+// before sending.
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    // The server buried the job instead of accepting it (put) or putting it
+    // back (release), because it ran out of memory
+    Buried(u64),
+    NotFound,
+    NotIgnored,
+    TimedOut,
+    DeadlineSoon,
+    ExpectedCrlf,
+    JobTooBig,
+    Draining,
+    AuthFailed,
+    // The server wants an `auth` first
+    NotAuthenticated,
+    // The server's ACL doesn't allow the command on the tube
+    PermissionDenied,
+    OutOfMemory,
+    // The tube holds as many jobs or bytes as the server lets it
+    TubeFull,
+    InternalError,
+    BadFormat,
+    UnknownCommand,
+    // The server answered with something that makes no sense for the command
+    UnexpectedResponse(String),
+    // Not sent, the server wouldn't take the name
+    InvalidTubeName(String),
+    // Not sent, user names and passwords can't be empty or contain whitespace
+    InvalidCredentials,
+}
+
+impl Error {
+    // Converts a response that does not mean success for the command sent.
+    pub fn from_response(response: Response) -> Error {
+        match response {
+            Response::BuriedWithId(id) => Error::Buried(id),
+            Response::NotFound => Error::NotFound,
+            Response::NotIgnored => Error::NotIgnored,
+            Response::TimedOut => Error::TimedOut,
+            Response::DeadlineSoon => Error::DeadlineSoon,
+            Response::ExpectedCrlf => Error::ExpectedCrlf,
+            Response::JobTooBig => Error::JobTooBig,
+            Response::Draining => Error::Draining,
+            Response::AuthFailed => Error::AuthFailed,
+            Response::NotAuthenticated => Error::NotAuthenticated,
+            Response::PermissionDenied => Error::PermissionDenied,
+            Response::OutOfMemory => Error::OutOfMemory,
+            Response::TubeFull => Error::TubeFull,
+            Response::InternalError => Error::InternalError,
+            Response::BadFormat => Error::BadFormat,
+            Response::UnknownCommand => Error::UnknownCommand,
+            other => Error::UnexpectedResponse(format!("{:?}", other)),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref err) => write!(f, "I/O error: {}", err),
+            Error::Buried(id) => write!(f, "job {} was buried", id),
+            Error::NotFound => write!(f, "not found"),
+            Error::NotIgnored => write!(f, "cannot ignore the only watched tube"),
+            Error::TimedOut => write!(f, "timed out"),
+            Error::DeadlineSoon => write!(f, "deadline of a reserved job is soon"),
+            Error::ExpectedCrlf => write!(f, "job body was not followed by CRLF"),
+            Error::JobTooBig => write!(f, "job is too big"),
+            Error::Draining => write!(f, "server is draining"),
+            Error::AuthFailed => write!(f, "authentication failed"),
+            Error::NotAuthenticated => write!(f, "not authenticated"),
+            Error::PermissionDenied => write!(f, "permission denied"),
+            Error::OutOfMemory => write!(f, "server is out of memory"),
+            Error::TubeFull => write!(f, "tube is full"),
+            Error::InternalError => write!(f, "internal server error"),
+            Error::BadFormat => write!(f, "bad command format"),
+            Error::UnknownCommand => write!(f, "unknown command"),
+            Error::UnexpectedResponse(ref response) => write!(f, "unexpected response: {}", response),
+            Error::InvalidTubeName(ref tube) => write!(f, "invalid tube name: {:?}", tube),
+            Error::InvalidCredentials => write!(f, "user names and passwords can't be empty or contain whitespace"),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Io(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
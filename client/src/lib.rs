@@ -0,0 +1,422 @@
+//! Client for beanstalkd compatible servers.
+//!
+//! ```no_run
+//! extern crate beanstalkdrs_client;
+//!
+//! use beanstalkdrs_client::Client;
+//!
+//! fn main() {
+//!     let mut client = Client::connect("127.0.0.1:11300").unwrap();
+//!
+//!     client.put(1024, 0, 60, b"hello").unwrap();
+//!
+//!     let job = client.reserve().unwrap();
+//!     client.delete(job.id).unwrap();
+//! }
+//! ```
+
+#[macro_use]
+extern crate nom;
+
+#[cfg(feature = "serde")]
+extern crate serde;
+
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde_derive;
+
+pub mod error;
+pub mod response;
+pub mod stats;
+
+pub use error::Error;
+pub use stats::{JobStats, ListenerStats, Stats, TubeStats};
+
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+
+use nom::IResult;
+
+use response::{parse_beanstalk_response, Response};
+
+// Size of the chunks the input buffer grows by while waiting for a response
+const READ_SIZE: usize = 4096;
+
+// Longest tube name servers take
+const MAX_TUBE_NAME_LENGTH: usize = 200;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Job {
+    pub id: u64,
+    pub data: Vec<u8>,
+}
+
+pub struct Client<S: Read + Write = TcpStream> {
+    stream: S,
+    buffer: Vec<u8>,
+    written: usize,
+}
+
+impl Client<TcpStream> {
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Client<TcpStream>, Error> {
+        Ok(Client::new(TcpStream::connect(addr)?))
+    }
+}
+
+impl<S: Read + Write> Client<S> {
+    // Talks to a server over an already established stream.
+    pub fn new(stream: S) -> Client<S> {
+        Client {
+            stream,
+            buffer: vec![],
+            written: 0,
+        }
+    }
+
+    pub fn put(&mut self, pri: u32, delay: u32, ttr: u32, data: &[u8]) -> Result<u64, Error> {
+        let mut command = format!("put {} {} {} {}\r\n", pri, delay, ttr, data.len()).into_bytes();
+        command.extend_from_slice(data);
+        command.extend_from_slice(b"\r\n");
+
+        self.command(&command, |response| match response {
+            Response::Inserted(id) => Ok(id),
+            other => Err(Error::from_response(other)),
+        })
+    }
+
+    pub fn use_tube(&mut self, tube: &str) -> Result<String, Error> {
+        check_tube_name(tube)?;
+        self.command(format!("use {}\r\n", tube).as_bytes(), |response| match response {
+            Response::Using(tube) => Ok(String::from_utf8_lossy(tube).into_owned()),
+            other => Err(Error::from_response(other)),
+        })
+    }
+
+    pub fn reserve(&mut self) -> Result<Job, Error> {
+        self.command(b"reserve\r\n", reserved_job)
+    }
+
+    // Fails with `Error::TimedOut` if no job became available in time.
+    pub fn reserve_with_timeout(&mut self, timeout: u32) -> Result<Job, Error> {
+        self.command(format!("reserve-with-timeout {}\r\n", timeout).as_bytes(), reserved_job)
+    }
+
+    pub fn delete(&mut self, id: u64) -> Result<(), Error> {
+        self.command(format!("delete {}\r\n", id).as_bytes(), |response| match response {
+            Response::Deleted => Ok(()),
+            other => Err(Error::from_response(other)),
+        })
+    }
+
+    pub fn release(&mut self, id: u64, pri: u32, delay: u32) -> Result<(), Error> {
+        self.command(format!("release {} {} {}\r\n", id, pri, delay).as_bytes(), |response| match response {
+            Response::Released => Ok(()),
+            Response::Buried => Err(Error::Buried(id)),
+            other => Err(Error::from_response(other)),
+        })
+    }
+
+    pub fn bury(&mut self, id: u64, pri: u32) -> Result<(), Error> {
+        self.command(format!("bury {} {}\r\n", id, pri).as_bytes(), |response| match response {
+            Response::Buried => Ok(()),
+            other => Err(Error::from_response(other)),
+        })
+    }
+
+    pub fn touch(&mut self, id: u64) -> Result<(), Error> {
+        self.command(format!("touch {}\r\n", id).as_bytes(), |response| match response {
+            Response::Touched => Ok(()),
+            other => Err(Error::from_response(other)),
+        })
+    }
+
+    // Returns the number of tubes watched afterwards.
+    pub fn watch(&mut self, tube: &str) -> Result<u64, Error> {
+        check_tube_name(tube)?;
+        self.command(format!("watch {}\r\n", tube).as_bytes(), watching_count)
+    }
+
+    // Returns the number of tubes watched afterwards.
+    pub fn ignore(&mut self, tube: &str) -> Result<u64, Error> {
+        check_tube_name(tube)?;
+        self.command(format!("ignore {}\r\n", tube).as_bytes(), watching_count)
+    }
+
+    pub fn peek(&mut self, id: u64) -> Result<Job, Error> {
+        self.command(format!("peek {}\r\n", id).as_bytes(), found_job)
+    }
+
+    pub fn peek_ready(&mut self) -> Result<Job, Error> {
+        self.command(b"peek-ready\r\n", found_job)
+    }
+
+    pub fn peek_delayed(&mut self) -> Result<Job, Error> {
+        self.command(b"peek-delayed\r\n", found_job)
+    }
+
+    pub fn peek_buried(&mut self) -> Result<Job, Error> {
+        self.command(b"peek-buried\r\n", found_job)
+    }
+
+    // Kicks up to `bound` jobs in the used tube, returns how many were kicked.
+    pub fn kick(&mut self, bound: u64) -> Result<u64, Error> {
+        self.command(format!("kick {}\r\n", bound).as_bytes(), |response| match response {
+            Response::KickedCount(count) => Ok(count),
+            other => Err(Error::from_response(other)),
+        })
+    }
+
+    pub fn kick_job(&mut self, id: u64) -> Result<(), Error> {
+        self.command(format!("kick-job {}\r\n", id).as_bytes(), |response| match response {
+            Response::Kicked => Ok(()),
+            other => Err(Error::from_response(other)),
+        })
+    }
+
+    pub fn stats(&mut self) -> Result<Stats, Error> {
+        self.command(b"stats\r\n", |response| Stats::parse(yaml(response)?))
+    }
+
+    pub fn stats_tube(&mut self, tube: &str) -> Result<TubeStats, Error> {
+        check_tube_name(tube)?;
+        self.command(format!("stats-tube {}\r\n", tube).as_bytes(), |response| {
+            TubeStats::parse(yaml(response)?)
+        })
+    }
+
+    pub fn stats_job(&mut self, id: u64) -> Result<JobStats, Error> {
+        self.command(format!("stats-job {}\r\n", id).as_bytes(), |response| {
+            JobStats::parse(yaml(response)?)
+        })
+    }
+
+    pub fn list_tubes(&mut self) -> Result<Vec<String>, Error> {
+        self.command(b"list-tubes\r\n", |response| stats::parse_list(yaml(response)?))
+    }
+
+    pub fn list_tubes_watched(&mut self) -> Result<Vec<String>, Error> {
+        self.command(b"list-tubes-watched\r\n", |response| stats::parse_list(yaml(response)?))
+    }
+
+    pub fn list_tube_used(&mut self) -> Result<String, Error> {
+        self.command(b"list-tube-used\r\n", |response| match response {
+            Response::Using(tube) => Ok(String::from_utf8_lossy(tube).into_owned()),
+            other => Err(Error::from_response(other)),
+        })
+    }
+
+    pub fn pause_tube(&mut self, tube: &str, delay: u32) -> Result<(), Error> {
+        check_tube_name(tube)?;
+        self.command(format!("pause-tube {} {}\r\n", tube, delay).as_bytes(), |response| match response {
+            Response::Paused => Ok(()),
+            other => Err(Error::from_response(other)),
+        })
+    }
+
+    // Makes the server refuse new jobs until `undrain` is sent. Only
+    // beanstalkdrs understands this.
+    pub fn drain(&mut self) -> Result<(), Error> {
+        self.command(b"drain\r\n", |response| match response {
+            Response::Draining => Ok(()),
+            other => Err(Error::from_response(other)),
+        })
+    }
+
+    pub fn undrain(&mut self) -> Result<(), Error> {
+        self.command(b"undrain\r\n", |response| match response {
+            Response::NotDraining => Ok(()),
+            other => Err(Error::from_response(other)),
+        })
+    }
+
+    // Identifies the client to a server requiring authentication. Only
+    // beanstalkdrs understands this.
+    pub fn auth(&mut self, user: &str, password: &str) -> Result<(), Error> {
+        if !is_credential(user) || !is_credential(password) {
+            return Err(Error::InvalidCredentials);
+        }
+
+        self.command(format!("auth {} {}\r\n", user, password).as_bytes(), |response| match response {
+            Response::Authenticated => Ok(()),
+            other => Err(Error::from_response(other)),
+        })
+    }
+
+    // Tells the server to close the connection.
+    pub fn quit(mut self) -> Result<(), Error> {
+        self.stream.write_all(b"quit\r\n")?;
+        self.stream.flush()?;
+        Ok(())
+    }
+
+    // Sends the command and hands its response to `handle`.
+    fn command<T, F>(&mut self, command: &[u8], handle: F) -> Result<T, Error>
+        where F: FnOnce(Response) -> Result<T, Error>
+    {
+        self.stream.write_all(command)?;
+        self.stream.flush()?;
+
+        loop {
+            let (consumed, result) = match parse_beanstalk_response(&self.buffer[0..self.written]) {
+                IResult::Done(remaining, response) => {
+                    (self.written - remaining.len(), handle(response))
+                },
+                IResult::Incomplete(_) => {
+                    self.buffer.resize(self.written + READ_SIZE, 0);
+
+                    let len = self.stream.read(&mut self.buffer[self.written..])?;
+                    if len == 0 {
+                        return Err(Error::UnexpectedResponse("connection closed".to_string()));
+                    }
+
+                    self.written += len;
+                    continue;
+                },
+                IResult::Error(_) => {
+                    let response = String::from_utf8_lossy(&self.buffer[0..self.written]).into_owned();
+                    self.written = 0;
+                    return Err(Error::UnexpectedResponse(response));
+                },
+            };
+
+            self.buffer.drain(0..consumed);
+            self.written -= consumed;
+
+            return result;
+        }
+    }
+}
+
+// Refuses names the server wouldn't take as tube names, which could also
+// smuggle in other arguments or commands. Same rules as the server's: up to 200
+// bytes of letters, digits and `-+/;.$_()`, not starting with a hyphen.
+fn check_tube_name(tube: &str) -> Result<(), Error> {
+    let valid = !tube.is_empty()
+        && tube.len() <= MAX_TUBE_NAME_LENGTH
+        && !tube.starts_with('-')
+        && tube.bytes().all(|c| c.is_ascii_alphanumeric() || b"-+/;.$_()".contains(&c));
+
+    if valid {
+        Ok(())
+    } else {
+        Err(Error::InvalidTubeName(tube.to_string()))
+    }
+}
+
+// User names and passwords are sent as single words of printable characters.
+fn is_credential(credential: &str) -> bool {
+    !credential.is_empty() && credential.bytes().all(|c| c.is_ascii_graphic())
+}
+
+fn reserved_job(response: Response) -> Result<Job, Error> {
+    match response {
+        Response::Reserved {id, data} => Ok(Job {id, data: data.to_vec()}),
+        other => Err(Error::from_response(other)),
+    }
+}
+
+fn found_job(response: Response) -> Result<Job, Error> {
+    match response {
+        Response::Found {id, data} => Ok(Job {id, data: data.to_vec()}),
+        other => Err(Error::from_response(other)),
+    }
+}
+
+fn watching_count(response: Response) -> Result<u64, Error> {
+    match response {
+        Response::Watching(count) => Ok(count),
+        other => Err(Error::from_response(other)),
+    }
+}
+
+fn yaml(response: Response<'_>) -> Result<&[u8], Error> {
+    match response {
+        Response::Ok(data) => Ok(data),
+        other => Err(Error::from_response(other)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{self, Cursor};
+
+    // Replays canned server output and records what the client sent.
+    struct FakeStream {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl FakeStream {
+        fn new(input: &[u8]) -> FakeStream {
+            FakeStream { input: Cursor::new(input.to_vec()), output: vec![] }
+        }
+    }
+
+    impl Read for FakeStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            // Hand out tiny chunks so responses arrive in pieces
+            let len = buf.len().min(3);
+            self.input.read(&mut buf[..len])
+        }
+    }
+
+    impl Write for FakeStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn frames_commands_and_responses() {
+        let mut sut = Client::new(FakeStream::new(b"INSERTED 7\r\nRESERVED 7 5\r\nhello\r\n"));
+
+        assert_eq!(sut.put(10, 0, 60, b"hello").unwrap(), 7);
+        assert_eq!(sut.reserve().unwrap(), Job {id: 7, data: b"hello".to_vec()});
+        assert_eq!(&sut.stream.output[..], &b"put 10 0 60 5\r\nhello\r\nreserve\r\n"[..]);
+    }
+
+    #[test]
+    fn maps_error_lines_to_errors() {
+        let mut sut = Client::new(FakeStream::new(b"NOT_FOUND\r\nBURIED\r\nBURIED 4\r\nKICKED 2\r\n"));
+
+        match sut.delete(1) {
+            Err(Error::NotFound) => {},
+            other => panic!("unexpected {:?}", other),
+        }
+        match sut.release(3, 1, 0) {
+            Err(Error::Buried(3)) => {},
+            other => panic!("unexpected {:?}", other),
+        }
+        match sut.put(1, 0, 1, b"") {
+            Err(Error::Buried(4)) => {},
+            other => panic!("unexpected {:?}", other),
+        }
+        match sut.kick_job(1) {
+            Err(Error::UnexpectedResponse(_)) => {},
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn refuses_arguments_the_server_would_not_take() {
+        let mut sut = Client::new(FakeStream::new(b""));
+
+        for tube in &["", "-tube", "tu be", "emails\r\nput 1 0 1 0", &"a".repeat(201)] {
+            match sut.watch(tube) {
+                Err(Error::InvalidTubeName(ref name)) if name == tube => {},
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+        match sut.auth("admin", "secret\r\ndrain") {
+            Err(Error::InvalidCredentials) => {},
+            other => panic!("unexpected {:?}", other),
+        }
+
+        assert!(sut.stream.output.is_empty());
+    }
+}
@@ -0,0 +1,188 @@
+use nom::{IResult, digit};
+use std::str;
+use std::str::FromStr;
+
+// Every line a beanstalkd server can answer with
+named!(beanstalk_response <&[u8], Response<'_>>, alt!(
+    inserted_response |
+    buried_with_id_response |
+    value!(Response::Buried, tag!("BURIED\r\n")) |
+    using_response |
+    reserved_response |
+    found_response |
+    value!(Response::Deleted, tag!("DELETED\r\n")) |
+    value!(Response::Released, tag!("RELEASED\r\n")) |
+    value!(Response::Touched, tag!("TOUCHED\r\n")) |
+    watching_response |
+    kicked_count_response |
+    value!(Response::Kicked, tag!("KICKED\r\n")) |
+    ok_response |
+    value!(Response::Paused, tag!("PAUSED\r\n")) |
+    value!(Response::NotFound, tag!("NOT_FOUND\r\n")) |
+    value!(Response::NotIgnored, tag!("NOT_IGNORED\r\n")) |
+    value!(Response::TimedOut, tag!("TIMED_OUT\r\n")) |
+    value!(Response::DeadlineSoon, tag!("DEADLINE_SOON\r\n")) |
+    value!(Response::ExpectedCrlf, tag!("EXPECTED_CRLF\r\n")) |
+    value!(Response::JobTooBig, tag!("JOB_TOO_BIG\r\n")) |
+    value!(Response::Draining, tag!("DRAINING\r\n")) |
+    value!(Response::NotDraining, tag!("NOT_DRAINING\r\n")) |
+    value!(Response::Authenticated, tag!("AUTHENTICATED\r\n")) |
+    value!(Response::AuthFailed, tag!("AUTH_FAILED\r\n")) |
+    value!(Response::NotAuthenticated, tag!("NOT_AUTHENTICATED\r\n")) |
+    value!(Response::PermissionDenied, tag!("PERMISSION_DENIED\r\n")) |
+    value!(Response::OutOfMemory, tag!("OUT_OF_MEMORY\r\n")) |
+    value!(Response::TubeFull, tag!("TUBE_FULL\r\n")) |
+    value!(Response::InternalError, tag!("INTERNAL_ERROR\r\n")) |
+    value!(Response::BadFormat, tag!("BAD_FORMAT\r\n")) |
+    value!(Response::UnknownCommand, tag!("UNKNOWN_COMMAND\r\n"))
+));
+
+named!(number <u64>, map_res!(
+    map_res!(digit, str::from_utf8),
+    u64::from_str
+));
+
+named!(inserted_response <Response<'a>>, do_parse!(
+    tag!("INSERTED ") >>
+    id: number >>
+    tag!("\r\n") >>
+    (Response::Inserted(id))
+));
+
+named!(buried_with_id_response <Response<'a>>, do_parse!(
+    tag!("BURIED ") >>
+    id: number >>
+    tag!("\r\n") >>
+    (Response::BuriedWithId(id))
+));
+
+named!(using_response <Response<'a>>, do_parse!(
+    tag!("USING ") >>
+    tube: take_until!("\r\n") >>
+    tag!("\r\n") >>
+    (Response::Using(tube))
+));
+
+named!(reserved_response <Response<'a>>, do_parse!(
+    tag!("RESERVED ") >>
+    id: number >>
+    tag!(" ") >>
+    len: number >>
+    tag!("\r\n") >>
+    data: take!(len) >>
+    tag!("\r\n") >>
+    (Response::Reserved {id, data})
+));
+
+named!(found_response <Response<'a>>, do_parse!(
+    tag!("FOUND ") >>
+    id: number >>
+    tag!(" ") >>
+    len: number >>
+    tag!("\r\n") >>
+    data: take!(len) >>
+    tag!("\r\n") >>
+    (Response::Found {id, data})
+));
+
+named!(watching_response <Response<'a>>, do_parse!(
+    tag!("WATCHING ") >>
+    count: number >>
+    tag!("\r\n") >>
+    (Response::Watching(count))
+));
+
+named!(kicked_count_response <Response<'a>>, do_parse!(
+    tag!("KICKED ") >>
+    count: number >>
+    tag!("\r\n") >>
+    (Response::KickedCount(count))
+));
+
+named!(ok_response <Response<'a>>, do_parse!(
+    tag!("OK ") >>
+    len: number >>
+    tag!("\r\n") >>
+    data: take!(len) >>
+    tag!("\r\n") >>
+    (Response::Ok(data))
+));
+
+pub fn parse_beanstalk_response(data: &[u8]) -> IResult<&[u8], Response<'_>> {
+    beanstalk_response(data)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Response<'a> {
+    Inserted(u64),
+    BuriedWithId(u64),
+    Buried,
+    Using(&'a [u8]),
+    Reserved {id: u64, data: &'a [u8]},
+    Found {id: u64, data: &'a [u8]},
+    Deleted,
+    Released,
+    Touched,
+    Watching(u64),
+    KickedCount(u64),
+    Kicked,
+    Ok(&'a [u8]),
+    Paused,
+    NotFound,
+    NotIgnored,
+    TimedOut,
+    DeadlineSoon,
+    ExpectedCrlf,
+    JobTooBig,
+    Draining,
+    NotDraining,
+    Authenticated,
+    AuthFailed,
+    NotAuthenticated,
+    PermissionDenied,
+    OutOfMemory,
+    TubeFull,
+    InternalError,
+    BadFormat,
+    UnknownCommand,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nom::{IResult, ErrorKind};
+
+    #[test]
+    fn parsing_responses_with_a_body() {
+        assert_eq!(
+            beanstalk_response(b"RESERVED 12 7\r\nlab\r\nas\r\n"),
+            IResult::Done(&b""[..], Response::Reserved {id: 12, data: &b"lab\r\nas"[..]})
+        );
+        assert_eq!(
+            beanstalk_response(b"OK 4\r\n---\n\r\n"),
+            IResult::Done(&b""[..], Response::Ok(&b"---\n"[..]))
+        );
+        assert!(beanstalk_response(b"FOUND 1 5\r\nla").is_incomplete());
+    }
+
+    #[test]
+    fn parsing_responses_sharing_a_prefix() {
+        assert_eq!(
+            beanstalk_response(b"BURIED 3\r\n"),
+            IResult::Done(&b""[..], Response::BuriedWithId(3))
+        );
+        assert_eq!(beanstalk_response(b"BURIED\r\n"), IResult::Done(&b""[..], Response::Buried));
+        assert_eq!(
+            beanstalk_response(b"KICKED 10\r\n"),
+            IResult::Done(&b""[..], Response::KickedCount(10))
+        );
+        assert_eq!(beanstalk_response(b"KICKED\r\n"), IResult::Done(&b""[..], Response::Kicked));
+    }
+
+    #[test]
+    fn parsing_error_responses() {
+        assert_eq!(beanstalk_response(b"NOT_FOUND\r\n"), IResult::Done(&b""[..], Response::NotFound));
+        assert_eq!(beanstalk_response(b"DRAINING\r\n"), IResult::Done(&b""[..], Response::Draining));
+        assert_eq!(beanstalk_response(b"WHATEVER\r\n"), IResult::Error(ErrorKind::Alt));
+    }
+}
@@ -0,0 +1,364 @@
+use std::collections::{BTreeMap, HashMap};
+use std::str;
+use std::str::FromStr;
+
+use error::Error;
+
+// Parsed `stats` response
+#[derive(Debug, Clone, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "kebab-case"))]
+pub struct Stats {
+    pub current_jobs_urgent: u64,
+    pub current_jobs_ready: u64,
+    pub current_jobs_reserved: u64,
+    pub current_jobs_delayed: u64,
+    pub current_jobs_buried: u64,
+    pub job_timeouts: u64,
+    pub total_jobs: u64,
+    pub max_job_size: u64,
+    pub current_tubes: u64,
+    pub current_connections: u64,
+    pub current_producers: u64,
+    pub current_workers: u64,
+    pub current_waiting: u64,
+    pub total_connections: u64,
+    pub pid: u64,
+    pub version: String,
+    pub uptime: u64,
+    pub draining: bool,
+    // Bytes taken up by jobs, bodies included
+    pub memory_used: u64,
+    // Zero if the server has no limit
+    pub max_memory: u64,
+    // Bytes of bodies the server keeps on disk, zero if it keeps them all in
+    // memory
+    pub spilled_bytes: u64,
+    // Every `cmd-<name>` counter, keyed by the command name
+    pub commands: BTreeMap<String, u64>,
+    // Connections by the name of the listener they came through, only
+    // beanstalkdrs reports them
+    pub listeners: BTreeMap<String, ListenerStats>,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "kebab-case"))]
+pub struct ListenerStats {
+    pub current_connections: u64,
+    pub total_connections: u64,
+}
+
+// Parsed `stats-tube` response
+#[derive(Debug, Clone, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "kebab-case"))]
+pub struct TubeStats {
+    pub name: String,
+    pub current_jobs_urgent: u64,
+    pub current_jobs_ready: u64,
+    pub current_jobs_reserved: u64,
+    pub current_jobs_delayed: u64,
+    pub current_jobs_buried: u64,
+    pub total_jobs: u64,
+    pub current_using: u64,
+    pub current_waiting: u64,
+    pub current_watching: u64,
+    pub pause: u64,
+    pub cmd_delete: u64,
+    pub cmd_pause_tube: u64,
+    pub pause_time_left: u64,
+    // Zero if the tube has no limit
+    pub max_jobs: u64,
+    pub current_bytes: u64,
+    pub max_bytes: u64,
+}
+
+// Parsed `stats-job` response
+#[derive(Debug, Clone, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "kebab-case"))]
+pub struct JobStats {
+    pub id: u64,
+    pub tube: String,
+    pub state: String,
+    pub pri: u32,
+    pub age: u64,
+    pub delay: u64,
+    pub ttr: u64,
+    pub time_left: u64,
+    pub file: u64,
+    pub reserves: u64,
+    pub timeouts: u64,
+    pub releases: u64,
+    pub buries: u64,
+    pub kicks: u64,
+}
+
+impl Stats {
+    pub fn parse(yaml: &[u8]) -> Result<Stats, Error> {
+        let dict = Dict::parse(yaml)?;
+
+        let commands = dict.fields.iter()
+            .filter(|&(key, _)| key.starts_with("cmd-"))
+            .map(|(key, value)| Ok((key["cmd-".len()..].to_string(), parse_value(key, value)?)))
+            .collect::<Result<_, Error>>()?;
+
+        let mut listeners = BTreeMap::new();
+        for (key, value) in &dict.fields {
+            let name = match key.strip_prefix("listener-") {
+                Some(name) => name,
+                None => continue,
+            };
+
+            if let Some(name) = name.strip_suffix("-current-connections") {
+                let listener: &mut ListenerStats = listeners.entry(name.to_string()).or_default();
+                listener.current_connections = parse_value(key, value)?;
+            } else if let Some(name) = name.strip_suffix("-total-connections") {
+                let listener: &mut ListenerStats = listeners.entry(name.to_string()).or_default();
+                listener.total_connections = parse_value(key, value)?;
+            }
+        }
+
+        Ok(Stats {
+            current_jobs_urgent: dict.number("current-jobs-urgent")?,
+            current_jobs_ready: dict.number("current-jobs-ready")?,
+            current_jobs_reserved: dict.number("current-jobs-reserved")?,
+            current_jobs_delayed: dict.number("current-jobs-delayed")?,
+            current_jobs_buried: dict.number("current-jobs-buried")?,
+            job_timeouts: dict.number("job-timeouts")?,
+            total_jobs: dict.number("total-jobs")?,
+            max_job_size: dict.number("max-job-size")?,
+            current_tubes: dict.number("current-tubes")?,
+            current_connections: dict.number("current-connections")?,
+            current_producers: dict.number("current-producers")?,
+            current_workers: dict.number("current-workers")?,
+            current_waiting: dict.number("current-waiting")?,
+            total_connections: dict.number("total-connections")?,
+            pid: dict.number("pid")?,
+            version: dict.string("version"),
+            uptime: dict.number("uptime")?,
+            draining: dict.string("draining") == "true",
+            memory_used: dict.number("memory-used")?,
+            max_memory: dict.number("max-memory")?,
+            spilled_bytes: dict.number("spilled-bytes")?,
+            commands,
+            listeners,
+        })
+    }
+}
+
+impl TubeStats {
+    pub fn parse(yaml: &[u8]) -> Result<TubeStats, Error> {
+        let dict = Dict::parse(yaml)?;
+
+        Ok(TubeStats {
+            name: dict.string("name"),
+            current_jobs_urgent: dict.number("current-jobs-urgent")?,
+            current_jobs_ready: dict.number("current-jobs-ready")?,
+            current_jobs_reserved: dict.number("current-jobs-reserved")?,
+            current_jobs_delayed: dict.number("current-jobs-delayed")?,
+            current_jobs_buried: dict.number("current-jobs-buried")?,
+            total_jobs: dict.number("total-jobs")?,
+            current_using: dict.number("current-using")?,
+            current_waiting: dict.number("current-waiting")?,
+            current_watching: dict.number("current-watching")?,
+            pause: dict.number("pause")?,
+            cmd_delete: dict.number("cmd-delete")?,
+            cmd_pause_tube: dict.number("cmd-pause-tube")?,
+            pause_time_left: dict.number("pause-time-left")?,
+            max_jobs: dict.number("max-jobs")?,
+            current_bytes: dict.number("current-bytes")?,
+            max_bytes: dict.number("max-bytes")?,
+        })
+    }
+}
+
+impl JobStats {
+    pub fn parse(yaml: &[u8]) -> Result<JobStats, Error> {
+        let dict = Dict::parse(yaml)?;
+
+        Ok(JobStats {
+            id: dict.number("id")?,
+            tube: dict.string("tube"),
+            state: dict.string("state"),
+            pri: dict.number("pri")?,
+            age: dict.number("age")?,
+            delay: dict.number("delay")?,
+            ttr: dict.number("ttr")?,
+            time_left: dict.number("time-left")?,
+            file: dict.number("file")?,
+            reserves: dict.number("reserves")?,
+            timeouts: dict.number("timeouts")?,
+            releases: dict.number("releases")?,
+            buries: dict.number("buries")?,
+            kicks: dict.number("kicks")?,
+        })
+    }
+}
+
+// Parses a YAML list of scalars, as sent by `list-tubes`.
+pub fn parse_list(yaml: &[u8]) -> Result<Vec<String>, Error> {
+    lines(yaml)?
+        .map(|line| {
+            if let Some(item) = line.strip_prefix("- ") {
+                Ok(unquote(item))
+            } else {
+                Err(Error::UnexpectedResponse(format!("not a list item: {}", line)))
+            }
+        })
+        .collect()
+}
+
+// Flat YAML mapping of scalars, which is all beanstalkd sends
+struct Dict {
+    fields: HashMap<String, String>,
+}
+
+impl Dict {
+    fn parse(yaml: &[u8]) -> Result<Dict, Error> {
+        let fields = lines(yaml)?
+            .map(|line| {
+                let field = key_len(line).and_then(|len| {
+                    line[len..].strip_prefix(": ").map(|value| (unquote(&line[..len]), unquote(value)))
+                });
+                field.ok_or_else(|| Error::UnexpectedResponse(format!("not a key-value pair: {}", line)))
+            })
+            .collect::<Result<_, Error>>()?;
+
+        Ok(Dict { fields })
+    }
+
+    // Missing fields count as zero, servers differ in what they report
+    fn number<T: FromStr + Default>(&self, key: &str) -> Result<T, Error> {
+        match self.fields.get(key) {
+            Some(value) => parse_value(key, value),
+            None => Ok(T::default()),
+        }
+    }
+
+    fn string(&self, key: &str) -> String {
+        self.fields.get(key).cloned().unwrap_or_default()
+    }
+}
+
+fn parse_value<T: FromStr>(key: &str, value: &str) -> Result<T, Error> {
+    value.parse().map_err(|_| {
+        Error::UnexpectedResponse(format!("{} is not a number: {}", key, value))
+    })
+}
+
+// Content lines of a YAML document, without the leading `---`.
+fn lines(yaml: &[u8]) -> Result<impl Iterator<Item = &str>, Error> {
+    let yaml = str::from_utf8(yaml)
+        .map_err(|_| Error::UnexpectedResponse("YAML is not valid UTF-8".to_string()))?;
+
+    Ok(yaml.lines().filter(|line| !line.is_empty() && *line != "---"))
+}
+
+// Length of the key the line starts with. Keys made up from names, such as
+// listener addresses, come quoted and may contain `: ` themselves.
+fn key_len(line: &str) -> Option<usize> {
+    if !line.starts_with('"') {
+        return line.find(": ");
+    }
+
+    let mut escaped = false;
+    for (i, c) in line.char_indices().skip(1) {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => return Some(i + 1),
+            _ => {},
+        }
+    }
+
+    None
+}
+
+fn unquote(value: &str) -> String {
+    let value = value.trim();
+
+    if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+        let mut unquoted = String::new();
+        let mut chars = value[1..value.len() - 1].chars();
+
+        while let Some(c) = chars.next() {
+            if c == '\\' {
+                match chars.next() {
+                    Some('n') => unquoted.push('\n'),
+                    Some('t') => unquoted.push('\t'),
+                    Some(escaped) => unquoted.push(escaped),
+                    None => {},
+                }
+            } else {
+                unquoted.push(c);
+            }
+        }
+
+        unquoted
+    } else if value.len() >= 2 && value.starts_with('\'') && value.ends_with('\'') {
+        value[1..value.len() - 1].replace("''", "'")
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parsing_job_stats() {
+        let stats = JobStats::parse(b"---\nid: 3\ntube: \"my tube\"\nstate: reserved\npri: 1024\n\
+age: 5\ndelay: 0\nttr: 60\ntime-left: 59\nfile: 0\nreserves: 1\ntimeouts: 0\n\
+releases: 0\nburies: 0\nkicks: 0\n").unwrap();
+
+        assert_eq!(stats.id, 3);
+        assert_eq!(stats.tube, "my tube");
+        assert_eq!(stats.state, "reserved");
+        assert_eq!(stats.pri, 1024);
+        assert_eq!(stats.time_left, 59);
+        assert_eq!(stats.reserves, 1);
+    }
+
+    #[test]
+    fn parsing_server_stats_collects_command_counters() {
+        let stats = Stats::parse(b"---\ncurrent-jobs-ready: 2\ncmd-put: 7\ncmd-reserve: 1\n\
+version: \"1.12\"\ndraining: false\n").unwrap();
+
+        assert_eq!(stats.current_jobs_ready, 2);
+        assert_eq!(stats.version, "1.12");
+        assert!(!stats.draining);
+        assert_eq!(stats.commands.get("put"), Some(&7));
+        assert_eq!(stats.commands.get("reserve"), Some(&1));
+    }
+
+    #[test]
+    fn parsing_server_stats_collects_listener_counts() {
+        let stats = Stats::parse(b"---\nmemory-used: 300\nmax-memory: 1024\n\
+\"listener-127.0.0.1:11300-current-connections\": 2\n\"listener-127.0.0.1:11300-total-connections\": 5\n\
+\"listener-unix:/run/beanstalk: jobs.sock-current-connections\": 0\n").unwrap();
+
+        assert_eq!((stats.memory_used, stats.max_memory, stats.spilled_bytes), (300, 1024, 0));
+        assert_eq!(
+            stats.listeners.get("127.0.0.1:11300"),
+            Some(&ListenerStats {current_connections: 2, total_connections: 5})
+        );
+        assert_eq!(stats.listeners.get("unix:/run/beanstalk: jobs.sock"), Some(&ListenerStats::default()));
+    }
+
+    #[test]
+    fn parsing_invalid_numbers_fails() {
+        assert!(TubeStats::parse(b"---\nname: default\ntotal-jobs: many\n").is_err());
+    }
+
+    #[test]
+    fn parsing_lists() {
+        assert_eq!(
+            parse_list(b"---\n- default\n- \"with \\\"quotes\\\"\"\n").unwrap(),
+            vec!["default".to_string(), "with \"quotes\"".to_string()]
+        );
+        assert!(parse_list(b"---\ndefault\n").is_err());
+    }
+}
@@ -0,0 +1,358 @@
+extern crate beanstalkdrs;
+extern crate beanstalkdrs_client;
+
+use std::thread;
+use std::time::Duration;
+
+use beanstalkdrs::acl::Acl;
+use beanstalkdrs::auth::{self, Authenticator};
+use beanstalkdrs::job_queue::TubeLimit;
+use beanstalkdrs::{EmbeddedServer, JobQueue, Server};
+use beanstalkdrs_client::{Client, Error, Job, ListenerStats};
+
+#[test]
+fn producing_and_consuming_jobs() {
+    let server = EmbeddedServer::start().unwrap();
+    let mut producer = Client::connect(server.addr()).unwrap();
+    let mut consumer = Client::connect(server.addr()).unwrap();
+
+    assert_eq!(producer.use_tube("emails").unwrap(), "emails");
+    let id = producer.put(1, 0, 60, b"hello").unwrap();
+
+    assert_eq!(consumer.watch("emails").unwrap(), 2);
+    assert_eq!(consumer.ignore("default").unwrap(), 1);
+    match consumer.ignore("emails") {
+        Err(Error::NotIgnored) => {},
+        other => panic!("unexpected {:?}", other),
+    }
+
+    let job = consumer.reserve().unwrap();
+    assert_eq!(job, Job {id, data: b"hello".to_vec()});
+
+    consumer.touch(id).unwrap();
+    assert_eq!(consumer.stats_job(id).unwrap().state, "reserved");
+
+    consumer.release(id, 1, 0).unwrap();
+    assert_eq!(producer.peek_ready().unwrap().id, id);
+
+    consumer.delete(id).unwrap();
+    match consumer.delete(id) {
+        Err(Error::NotFound) => {},
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+#[test]
+fn reserving_with_timeout() {
+    let server = EmbeddedServer::start().unwrap();
+    let mut client = Client::connect(server.addr()).unwrap();
+
+    match client.reserve_with_timeout(0) {
+        Err(Error::TimedOut) => {},
+        other => panic!("unexpected {:?}", other),
+    }
+
+    let id = client.put(1, 0, 60, b"").unwrap();
+    assert_eq!(client.reserve_with_timeout(0).unwrap().id, id);
+}
+
+#[test]
+fn delaying_and_pausing() {
+    let server = EmbeddedServer::start().unwrap();
+    let mut client = Client::connect(server.addr()).unwrap();
+
+    let id = client.put(1, 60, 60, b"later").unwrap();
+    assert_eq!(client.peek_delayed().unwrap().data, b"later".to_vec());
+    assert_eq!(client.stats_job(id).unwrap().state, "delayed");
+
+    client.pause_tube("default", 60).unwrap();
+    match client.pause_tube("unknown", 60) {
+        Err(Error::NotFound) => {},
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+#[test]
+fn burying_and_kicking() {
+    let server = EmbeddedServer::start().unwrap();
+    let mut client = Client::connect(server.addr()).unwrap();
+
+    let id = client.put(1, 0, 60, b"broken").unwrap();
+    client.reserve().unwrap();
+    client.bury(id, 10).unwrap();
+
+    assert_eq!(client.peek_buried().unwrap().id, id);
+    assert_eq!(client.peek(id).unwrap().data, b"broken".to_vec());
+
+    let stats = client.stats_job(id).unwrap();
+    assert_eq!((stats.state.as_str(), stats.pri, stats.reserves, stats.buries), ("buried", 10, 1, 1));
+
+    assert_eq!(client.kick(5).unwrap(), 1);
+    match client.kick_job(id) {
+        Err(Error::NotFound) => {},
+        other => panic!("unexpected {:?}", other),
+    }
+    assert_eq!(client.stats_job(id).unwrap().kicks, 1);
+}
+
+#[test]
+fn tube_stats_count_connections() {
+    let server = EmbeddedServer::start().unwrap();
+    let mut producer = Client::connect(server.addr()).unwrap();
+    let mut consumer = Client::connect(server.addr()).unwrap();
+
+    producer.use_tube("emails").unwrap();
+    producer.put(1, 0, 60, b"urgent").unwrap();
+    producer.put(2000, 0, 60, b"whenever").unwrap();
+    consumer.watch("emails").unwrap();
+
+    let stats = producer.stats_tube("emails").unwrap();
+    assert_eq!(stats.name, "emails");
+    assert_eq!((stats.current_jobs_urgent, stats.current_jobs_ready, stats.total_jobs), (1, 2, 2));
+    assert_eq!((stats.current_using, stats.current_watching), (1, 1));
+
+    assert_eq!(producer.list_tubes().unwrap(), vec!["default".to_string(), "emails".to_string()]);
+    assert_eq!(producer.list_tube_used().unwrap(), "emails");
+    assert_eq!(consumer.list_tubes_watched().unwrap(), vec!["default".to_string(), "emails".to_string()]);
+
+    match producer.stats_tube("unknown") {
+        Err(Error::NotFound) => {},
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+#[test]
+fn draining_refuses_new_jobs() {
+    let server = EmbeddedServer::start().unwrap();
+    let mut client = Client::connect(server.addr()).unwrap();
+
+    let id = client.put(1, 0, 60, b"before").unwrap();
+    client.drain().unwrap();
+    assert!(client.stats().unwrap().draining);
+
+    match client.put(1, 0, 60, b"during") {
+        Err(Error::Draining) => {},
+        other => panic!("unexpected {:?}", other),
+    }
+    assert_eq!(client.reserve().unwrap().id, id);
+
+    client.undrain().unwrap();
+    assert!(!client.stats().unwrap().draining);
+    client.put(1, 0, 60, b"after").unwrap();
+}
+
+#[test]
+fn refusing_jobs_beyond_the_memory_limit() {
+    let mut job_queue = JobQueue::new();
+    job_queue.set_max_memory(Some(4096));
+    let server = EmbeddedServer::with_job_queue(job_queue).unwrap();
+    let mut client = Client::connect(server.addr()).unwrap();
+
+    let id = client.put(1, 0, 60, &[b'x'; 2000]).unwrap();
+    match client.put(1, 0, 60, &[b'x'; 2000]) {
+        Err(Error::OutOfMemory) => {},
+        other => panic!("unexpected {:?}", other),
+    }
+
+    client.delete(id).unwrap();
+    client.put(1, 0, 60, &[b'x'; 2000]).unwrap();
+}
+
+#[test]
+fn refusing_jobs_too_big() {
+    let mut job_queue = JobQueue::new();
+    job_queue.set_max_job_size(10);
+    let server = EmbeddedServer::with_job_queue(job_queue).unwrap();
+    let mut client = Client::connect(server.addr()).unwrap();
+
+    // Bodies are skipped whether or not they arrived with the command
+    for size in &[11, 100_000] {
+        match client.put(1, 0, 60, &vec![b'x'; *size]) {
+            Err(Error::JobTooBig) => {},
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    let id = client.put(1, 0, 60, b"0123456789").unwrap();
+    assert_eq!(client.reserve().unwrap().id, id);
+}
+
+#[test]
+fn refusing_jobs_beyond_the_tube_limit() {
+    let mut job_queue = JobQueue::new();
+    job_queue.limit_tubes("emails", TubeLimit {max_jobs: Some(1), max_bytes: Some(100)});
+    let server = EmbeddedServer::with_job_queue(job_queue).unwrap();
+    let mut client = Client::connect(server.addr()).unwrap();
+
+    client.use_tube("emails").unwrap();
+    let id = client.put(1, 0, 60, b"hello").unwrap();
+    match client.put(1, 0, 60, b"world") {
+        Err(Error::TubeFull) => {},
+        other => panic!("unexpected {:?}", other),
+    }
+
+    let stats = client.stats_tube("emails").unwrap();
+    assert_eq!((stats.max_jobs, stats.current_bytes, stats.max_bytes), (1, 5, 100));
+
+    client.delete(id).unwrap();
+    client.put(1, 0, 60, b"world").unwrap();
+}
+
+#[test]
+fn waiting_for_room_in_full_tubes() {
+    let mut job_queue = JobQueue::new();
+    job_queue.limit_tubes("*", TubeLimit {max_jobs: Some(1), max_bytes: None});
+    let server = EmbeddedServer::with_server(Server::new(job_queue).wait_for_room(1)).unwrap();
+    let mut producer = Client::connect(server.addr()).unwrap();
+    let mut worker = Client::connect(server.addr()).unwrap();
+
+    let id = producer.put(1, 0, 60, b"first").unwrap();
+    match producer.put(1, 0, 60, b"second") {
+        Err(Error::TubeFull) => {},
+        other => panic!("unexpected {:?}", other),
+    }
+
+    let waiting = thread::spawn(move || producer.put(1, 0, 60, b"third"));
+    thread::sleep(Duration::from_millis(200));
+    let job = worker.reserve().unwrap();
+    assert_eq!(job.id, id);
+    worker.delete(id).unwrap();
+
+    let id = waiting.join().unwrap().unwrap();
+    assert_eq!(worker.reserve().unwrap().data, b"third");
+    worker.delete(id).unwrap();
+}
+
+#[test]
+fn only_the_reserving_client_finishes_a_job() {
+    let server = EmbeddedServer::start().unwrap();
+    let mut worker = Client::connect(server.addr()).unwrap();
+    let mut other = Client::connect(server.addr()).unwrap();
+
+    let id = worker.put(1, 0, 60, b"job").unwrap();
+    worker.reserve().unwrap();
+
+    let attempts: Vec<Result<(), Error>> = vec![
+        other.release(id, 1, 0),
+        other.bury(id, 1),
+        other.touch(id),
+        other.delete(id),
+    ];
+    for attempt in attempts {
+        match attempt {
+            Err(Error::NotFound) => {},
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    worker.touch(id).unwrap();
+    worker.release(id, 1, 0).unwrap();
+
+    // Unreserved jobs may be deleted by anybody
+    other.delete(id).unwrap();
+}
+
+#[test]
+fn quitting_releases_reserved_jobs() {
+    let server = EmbeddedServer::start().unwrap();
+    let mut producer = Client::connect(server.addr()).unwrap();
+    let mut worker = Client::connect(server.addr()).unwrap();
+
+    let id = producer.put(1, 0, 60, b"job").unwrap();
+    assert_eq!(worker.reserve().unwrap().id, id);
+    worker.quit().unwrap();
+
+    // Blocks until the server noticed the quit
+    assert_eq!(producer.reserve().unwrap().id, id);
+    let stats = producer.stats().unwrap();
+    assert_eq!(stats.current_connections, 1);
+    assert_eq!(
+        stats.listeners.get(&server.addr().to_string()),
+        Some(&ListenerStats {current_connections: 1, total_connections: 2})
+    );
+}
+
+#[test]
+fn authenticating() {
+    let users = format!("worker:{}\n", auth::hash_password(b"secret"));
+    let server = Server::new(JobQueue::new()).require_auth(Authenticator::parse(&users).unwrap());
+    let server = EmbeddedServer::with_server(server).unwrap();
+    let mut client = Client::connect(server.addr()).unwrap();
+
+    match client.put(1, 0, 60, b"job") {
+        Err(Error::NotAuthenticated) => {},
+        other => panic!("unexpected {:?}", other),
+    }
+    match client.auth("worker", "guess") {
+        Err(Error::AuthFailed) => {},
+        other => panic!("unexpected {:?}", other),
+    }
+
+    client.auth("worker", "secret").unwrap();
+    client.put(1, 0, 60, b"job").unwrap();
+}
+
+#[test]
+fn enforcing_tube_acls() {
+    let users = format!("billing:{}\nops:{}\n", auth::hash_password(b"billing"), auth::hash_password(b"ops"));
+    let acl = Acl::parse("billing billing-* produce,consume\nops * admin\n").unwrap();
+    let server = Server::new(JobQueue::new())
+        .require_auth(Authenticator::parse(&users).unwrap())
+        .enforce_acl(acl);
+    let server = EmbeddedServer::with_server(server).unwrap();
+
+    let mut billing = Client::connect(server.addr()).unwrap();
+    billing.auth("billing", "billing").unwrap();
+    let mut ops = Client::connect(server.addr()).unwrap();
+    ops.auth("ops", "ops").unwrap();
+
+    // Still using and watching the default tube
+    match billing.put(1, 0, 60, b"invoice") {
+        Err(Error::PermissionDenied) => {},
+        other => panic!("unexpected {:?}", other),
+    }
+    match billing.reserve() {
+        Err(Error::PermissionDenied) => {},
+        other => panic!("unexpected {:?}", other),
+    }
+
+    billing.use_tube("billing-invoices").unwrap();
+    let id = billing.put(1, 0, 60, b"invoice").unwrap();
+    billing.watch("billing-invoices").unwrap();
+    assert_eq!(billing.reserve().unwrap().id, id);
+    billing.bury(id, 1).unwrap();
+
+    match billing.kick(1) {
+        Err(Error::PermissionDenied) => {},
+        other => panic!("unexpected {:?}", other),
+    }
+    ops.delete(id).unwrap();
+    match billing.use_tube("emails") {
+        Err(Error::PermissionDenied) => {},
+        other => panic!("unexpected {:?}", other),
+    }
+
+    // Jobs of other tubes can't be looked at, nor the server drained
+    ops.use_tube("emails").unwrap();
+    let email = ops.put(1, 0, 60, b"secret").unwrap();
+    let attempts: Vec<Result<(), Error>> = vec![
+        billing.peek(email).map(|_| ()),
+        billing.stats_job(email).map(|_| ()),
+        billing.stats_tube("emails").map(|_| ()),
+        billing.drain(),
+        billing.undrain(),
+    ];
+    for attempt in attempts {
+        match attempt {
+            Err(Error::PermissionDenied) => {},
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+    match billing.peek_ready() {
+        Err(Error::NotFound) => {},
+        other => panic!("unexpected {:?}", other),
+    }
+
+    assert_eq!(ops.peek(email).unwrap().data, b"secret");
+    ops.drain().unwrap();
+}
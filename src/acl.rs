@@ -0,0 +1,181 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// What a client may do with the jobs of a tube
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operation {
+    // use and put
+    Produce,
+    // watch, reserve, delete, release, bury or touch a reserved job, and
+    // peek and look at job and tube stats
+    Consume,
+    // kick and pause-tube, on every tube also drain, allows everything else
+    // too
+    Admin,
+}
+
+impl Operation {
+    fn parse(name: &str) -> Option<Operation> {
+        match name {
+            "produce" => Some(Operation::Produce),
+            "consume" => Some(Operation::Consume),
+            "admin" => Some(Operation::Admin),
+            _ => None,
+        }
+    }
+}
+
+// Checking an operation on this name checks it on every tube: only patterns
+// made up of nothing but `*` match it, and real tube names can't contain `*`
+pub const ALL_TUBES: &str = "*";
+
+// Rules granting authenticated users operations on tubes, read from a file
+// with a `<user> <tube pattern> <operation>[,<operation>]...` line each:
+//
+//     # billing workers get their own tubes, ops can fix everything
+//     billing billing-* produce,consume
+//     ops * admin
+//
+// `*` in users and tube patterns matches anything. Whatever no rule grants is
+// denied. Blank lines and lines starting with `#` are skipped.
+pub struct Acl {
+    rules: Vec<Rule>,
+}
+
+struct Rule {
+    user: String,
+    tubes: String,
+    operations: Vec<Operation>,
+}
+
+impl Acl {
+    pub fn from_file(path: &Path) -> io::Result<Acl> {
+        let rules = fs::read_to_string(path)?;
+
+        Acl::parse(&rules).map_err(|err| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), err))
+        })
+    }
+
+    pub fn parse(rules: &str) -> Result<Acl, String> {
+        let mut parsed = vec![];
+
+        for (number, line) in rules.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let (user, tubes, operations) = match fields[..] {
+                [user, tubes, operations] => (user, tubes, operations),
+                _ => return Err(format!("line {}: expected user, tube pattern and operations", number + 1)),
+            };
+
+            let operations = operations.split(',')
+                .map(|name| Operation::parse(name).ok_or(format!("line {}: unknown operation {}", number + 1, name)))
+                .collect::<Result<_, _>>()?;
+
+            parsed.push(Rule {user: user.to_string(), tubes: tubes.to_string(), operations});
+        }
+
+        Ok(Acl {rules: parsed})
+    }
+
+    // Whether any rule lets the user, if the client authenticated, do the
+    // operation on the tube.
+    pub fn allows(&self, user: Option<&str>, tube: &str, operation: Operation) -> bool {
+        self.rules.iter().any(|rule| {
+            let user_matches = match user {
+                Some(user) => matches(&rule.user, user),
+                None => rule.user == "*",
+            };
+
+            user_matches
+                && matches(&rule.tubes, tube)
+                && rule.operations.iter().any(|&granted| granted == operation || granted == Operation::Admin)
+        })
+    }
+}
+
+// Glob matching where `*` stands for any run of characters
+pub(crate) fn matches(pattern: &str, name: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == name,
+        Some((prefix, rest)) => {
+            let name = match name.strip_prefix(prefix) {
+                Some(name) => name,
+                None => return false,
+            };
+
+            name.char_indices()
+                .map(|(i, _)| i)
+                .chain(Some(name.len()))
+                .any(|i| matches(rest, &name[i..]))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn acl() -> Acl {
+        Acl::parse("
+            # teams
+            billing billing-* produce,consume
+            reports billing-events consume
+            ops * admin
+            * public produce
+        ").unwrap()
+    }
+
+    #[test]
+    fn grants_operations_by_user_and_tube() {
+        let acl = acl();
+
+        assert!(acl.allows(Some("billing"), "billing-invoices", Operation::Produce));
+        assert!(acl.allows(Some("billing"), "billing-invoices", Operation::Consume));
+        assert!(!acl.allows(Some("billing"), "billing-invoices", Operation::Admin));
+        assert!(!acl.allows(Some("billing"), "emails", Operation::Produce));
+
+        assert!(acl.allows(Some("reports"), "billing-events", Operation::Consume));
+        assert!(!acl.allows(Some("reports"), "billing-events", Operation::Produce));
+    }
+
+    #[test]
+    fn admin_allows_everything() {
+        let acl = acl();
+
+        assert!(acl.allows(Some("ops"), "emails", Operation::Admin));
+        assert!(acl.allows(Some("ops"), "emails", Operation::Consume));
+        assert!(acl.allows(Some("ops"), ALL_TUBES, Operation::Admin));
+        assert!(!acl.allows(Some("billing"), ALL_TUBES, Operation::Consume));
+    }
+
+    #[test]
+    fn wildcard_users_include_anonymous_clients() {
+        let acl = acl();
+
+        assert!(acl.allows(None, "public", Operation::Produce));
+        assert!(acl.allows(Some("reports"), "public", Operation::Produce));
+        assert!(!acl.allows(None, "billing-events", Operation::Consume));
+    }
+
+    #[test]
+    fn matches_globs() {
+        assert!(matches("*", ""));
+        assert!(matches("billing-*", "billing-"));
+        assert!(matches("*-events", "billing-events"));
+        assert!(matches("a*b*c", "a-b-b-c"));
+        assert!(!matches("a*b*c", "a-c-b"));
+        assert!(!matches("billing", "billing-events"));
+    }
+
+    #[test]
+    fn rejects_malformed_rules() {
+        assert!(Acl::parse("billing billing-*").is_err());
+        assert!(Acl::parse("billing billing-* produce,delete").is_err());
+    }
+}
@@ -0,0 +1,160 @@
+use acl::Operation;
+use document::Document;
+use http::{Request, Response};
+use job_queue::JobQueue;
+use json;
+use server::Server;
+
+// JSON over HTTP for dashboards and scripts, doing what the protocol's stats,
+// peek, kick, pause-tube and delete commands do:
+//
+//     GET  /stats                      server stats
+//     GET  /tubes                      tube names
+//     GET  /tubes/<tube>               tube stats
+//     GET  /tubes/<tube>/<state>       stats of the next ready, delayed or
+//                                      buried job
+//     POST /tubes/<tube>/kick?bound=N  kicks up to N jobs
+//     POST /tubes/<tube>/pause?delay=S pauses the tube for S seconds
+//     GET  /jobs/<id>                  job stats
+//     GET  /jobs/<id>/body             the job's body, as is
+//     POST /jobs/<id>/kick             kicks the job
+//     POST /jobs/<id>/delete           deletes the job, unless a client has
+//                                      reserved it
+//
+// Tube names in paths are percent-encoded. If the server requires clients to
+// authenticate they have to use basic authentication, and the ACL applies.
+pub fn handle(server: &Server, request: &Request) -> Response {
+    let user = match server.http_identity(request) {
+        Ok(user) => user,
+        Err(response) => return response,
+    };
+
+    let segments = match request.segments() {
+        Some(segments) => segments,
+        None => return Response::status(400),
+    };
+    let segments: Vec<&str> = segments.iter().map(|segment| segment.as_str()).collect();
+
+    let job_queue = server.job_queue();
+    let mut job_queue = job_queue.lock().unwrap();
+
+    // Refuses what the ACL doesn't allow
+    let permission = match (request.method.as_str(), &segments[..]) {
+        ("POST", ["tubes", tube, "kick"]) | ("POST", ["tubes", tube, "pause"]) => {
+            Some((tube.to_string(), Operation::Admin))
+        },
+        ("POST", ["jobs", id, action @ ("kick" | "delete")]) => {
+            let operation = if *action == "kick" { Operation::Admin } else { Operation::Consume };
+            id.parse().ok()
+                .and_then(|id| job_queue.job_tube(&id))
+                .map(|tube| (tube.to_string(), operation))
+        },
+        // Looking at jobs is up to those who may consume them
+        ("GET", ["tubes", tube]) | ("GET", ["tubes", tube, _]) => Some((tube.to_string(), Operation::Consume)),
+        ("GET", ["jobs", id]) | ("GET", ["jobs", id, "body"]) => {
+            id.parse().ok()
+                .and_then(|id| job_queue.job_tube(&id))
+                .map(|tube| (tube.to_string(), Operation::Consume))
+        },
+        _ => None,
+    };
+    if let Some((tube, operation)) = permission {
+        if !server.allows(user.as_deref(), &tube, operation) {
+            return Response::status(403);
+        }
+    }
+
+    match (request.method.as_str(), &segments[..]) {
+        ("GET", ["stats"]) => Response::json(200, job_queue.stats().write(json::Object::new()).into_string()),
+        ("GET", ["tubes"]) => Response::json(200, json::list(job_queue.tube_names())),
+        ("GET", ["tubes", tube]) => match job_queue.stats_tube(tube) {
+            Some(stats) => Response::json(200, stats.write(json::Object::new()).into_string()),
+            None => Response::status(404),
+        },
+        ("GET", ["tubes", tube, state]) => {
+            let job = match *state {
+                "ready" => job_queue.peek_ready(tube),
+                "delayed" => job_queue.peek_delayed(tube),
+                "buried" => job_queue.peek_buried(tube),
+                _ => return Response::status(404),
+            };
+
+            match job {
+                Some((id, _)) => job_stats(&job_queue, id),
+                None => Response::status(404),
+            }
+        },
+        ("POST", ["tubes", tube, "kick"]) => {
+            let bound = match request.query("bound").map(|bound| bound.parse::<u32>()) {
+                Some(Ok(bound)) => bound,
+                _ => return Response::status(400),
+            };
+            if job_queue.stats_tube(tube).is_none() {
+                return Response::status(404);
+            }
+
+            let kicked = job_queue.kick(tube, bound);
+            if kicked > 0 {
+                server.wake_up();
+            }
+
+            Response::json(200, json::Object::new().number("kicked", kicked).into_string())
+        },
+        ("POST", ["tubes", tube, "pause"]) => {
+            let delay = match request.query("delay").map(|delay| delay.parse::<u32>()) {
+                Some(Ok(delay)) => delay,
+                _ => return Response::status(400),
+            };
+
+            if job_queue.pause_tube(tube, delay) {
+                server.wake_up();
+                Response::status(204)
+            } else {
+                Response::status(404)
+            }
+        },
+        ("GET", ["jobs", id]) => match id.parse() {
+            Ok(id) => job_stats(&job_queue, id),
+            Err(_) => Response::status(404),
+        },
+        ("GET", ["jobs", id, "body"]) => {
+            let job = id.parse().ok().and_then(|id| job_queue.peek(&id));
+            // Spilled bodies are read without holding up the queue
+            drop(job_queue);
+
+            match job.map(|(id, body)| (id, body.read())) {
+                Some((_, Ok(data))) => Response::new(200, "application/octet-stream", data.to_vec()),
+                Some((id, Err(err))) => {
+                    error!("Failed reading the body of job {}: {}", id, err);
+                    Response::status(404)
+                },
+                None => Response::status(404),
+            }
+        },
+        ("POST", ["jobs", id, "kick"]) => match id.parse() {
+            Ok(id) if job_queue.kick_job(&id) => {
+                server.wake_up();
+                Response::status(204)
+            },
+            _ => Response::status(404),
+        },
+        ("POST", ["jobs", id, "delete"]) => match id.parse() {
+            // Only the client that reserved a job may delete it, same as with
+            // the protocol
+            Ok(id) if job_queue.reservation(&id).is_some() => Response::status(409),
+            Ok(id) if job_queue.delete(&id).is_some() => {
+                server.wake_up();
+                Response::status(204)
+            },
+            _ => Response::status(404),
+        },
+        _ => Response::status(404),
+    }
+}
+
+fn job_stats(job_queue: &JobQueue, id: u64) -> Response {
+    match job_queue.stats_job(&id) {
+        Some(stats) => Response::json(200, stats.write(json::Object::new()).into_string()),
+        None => Response::status(404),
+    }
+}
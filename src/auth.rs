@@ -0,0 +1,257 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::num::NonZeroU32;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+
+const SCHEME: &str = "pbkdf2-sha256";
+
+// Iterations new hashes are made with, as recommended for PBKDF2-HMAC-SHA256
+const ITERATIONS: u32 = 600_000;
+
+const SALT_LENGTH: usize = 16;
+const HASH_LENGTH: usize = 32;
+
+// Consecutive failed attempts after which a source is locked out
+const MAX_FAILURES: u32 = 5;
+const LOCKOUT: Duration = Duration::from_secs(30);
+
+// Checks the credentials clients send with `auth`. Users are read from a file
+// with a `user:hash` line each, hashes being made by `hash_password`. Blank
+// lines and lines starting with `#` are skipped.
+pub struct Authenticator {
+    users: HashMap<String, PasswordHash>,
+    // Checked for unknown users, so that telling them apart takes as long
+    // as guessing a password
+    dummy: PasswordHash,
+    // Failed attempts by where they came from rather than by user, so that
+    // nobody can lock a user out from somewhere else
+    failures: Mutex<HashMap<String, Failures>>,
+}
+
+struct PasswordHash {
+    iterations: NonZeroU32,
+    salt: Vec<u8>,
+    hash: Vec<u8>,
+}
+
+#[derive(Default)]
+struct Failures {
+    count: u32,
+    locked_until: Option<Instant>,
+}
+
+impl Authenticator {
+    pub fn from_file(path: &Path) -> io::Result<Authenticator> {
+        let users = fs::read_to_string(path)?;
+
+        Authenticator::parse(&users).map_err(|err| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), err))
+        })
+    }
+
+    pub fn parse(users: &str) -> Result<Authenticator, String> {
+        let mut parsed = HashMap::new();
+
+        for (number, line) in users.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (user, hash) = line.split_once(':')
+                .ok_or(format!("line {}: expected user:hash", number + 1))?;
+            let hash = PasswordHash::parse(hash)
+                .ok_or(format!("line {}: invalid password hash", number + 1))?;
+
+            parsed.insert(user.to_string(), hash);
+        }
+
+        let dummy = PasswordHash {
+            iterations: parsed.values()
+                .map(|hash| hash.iterations)
+                .max()
+                .unwrap_or(NonZeroU32::new(ITERATIONS).unwrap()),
+            salt: vec![0; SALT_LENGTH],
+            hash: vec![0; HASH_LENGTH],
+        };
+
+        Ok(Authenticator {users: parsed, dummy, failures: Mutex::new(HashMap::new())})
+    }
+
+    // Whether the password is the user's. `source` tells who is trying, such
+    // as the client's address: sources failing too many times in a row are
+    // turned away for a while, even with the right password.
+    pub fn authenticate(&self, source: &str, user: &str, password: &[u8]) -> bool {
+        self.authenticate_at(source, user, password, Instant::now())
+    }
+
+    fn authenticate_at(&self, source: &str, user: &str, password: &[u8], now: Instant) -> bool {
+        let locked = self.failures.lock().unwrap()
+            .get(source)
+            .and_then(|failures| failures.locked_until)
+            .is_some_and(|locked_until| now < locked_until);
+        if locked {
+            return false;
+        }
+
+        // Deliberately slow, so not done while holding the lock
+        let valid = match self.users.get(user) {
+            Some(hash) => hash.verify(password),
+            None => {
+                self.dummy.verify(password);
+                false
+            },
+        };
+
+        let mut failures = self.failures.lock().unwrap();
+        if valid {
+            failures.remove(source);
+        } else {
+            let failures = failures.entry(source.to_string()).or_default();
+            failures.count += 1;
+
+            if failures.count >= MAX_FAILURES {
+                warn!("Locking out {} after {} failed attempts", source, failures.count);
+                failures.count = 0;
+                failures.locked_until = Some(now + LOCKOUT);
+            }
+        }
+
+        valid
+    }
+}
+
+impl PasswordHash {
+    // Reads `pbkdf2-sha256$<iterations>$<hex salt>$<hex hash>`
+    fn parse(hash: &str) -> Option<PasswordHash> {
+        let parts: Vec<&str> = hash.split('$').collect();
+
+        match parts[..] {
+            [SCHEME, iterations, salt, hash] => Some(PasswordHash {
+                iterations: iterations.parse().ok()?,
+                salt: from_hex(salt)?,
+                hash: from_hex(hash).filter(|hash| hash.len() == HASH_LENGTH)?,
+            }),
+            _ => None,
+        }
+    }
+
+    fn verify(&self, password: &[u8]) -> bool {
+        pbkdf2::verify(pbkdf2::PBKDF2_HMAC_SHA256, self.iterations, &self.salt, password, &self.hash).is_ok()
+    }
+}
+
+// Hashes a password with a random salt, for the users file.
+pub fn hash_password(password: &[u8]) -> String {
+    let mut salt = [0; SALT_LENGTH];
+    SystemRandom::new().fill(&mut salt).expect("no randomness for a salt");
+
+    hash_with(password, NonZeroU32::new(ITERATIONS).unwrap(), &salt)
+}
+
+fn hash_with(password: &[u8], iterations: NonZeroU32, salt: &[u8]) -> String {
+    let mut hash = [0; HASH_LENGTH];
+    pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, iterations, salt, password, &mut hash);
+
+    format!("{}${}${}${}", SCHEME, iterations, to_hex(salt), to_hex(&hash))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    let mut hex = String::new();
+
+    for byte in bytes {
+        let _ = write!(hex, "{:02x}", byte);
+    }
+
+    hex
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn authenticator() -> Authenticator {
+        // Few iterations keep the tests fast
+        let hash = hash_with(b"secret", NonZeroU32::new(10).unwrap(), b"salt");
+
+        Authenticator::parse(&format!("# workers\n\nworker:{}\n", hash)).unwrap()
+    }
+
+    #[test]
+    fn checks_passwords() {
+        let authenticator = authenticator();
+
+        assert!(authenticator.authenticate("10.0.0.1", "worker", b"secret"));
+        assert!(!authenticator.authenticate("10.0.0.1", "worker", b"Secret"));
+        assert!(!authenticator.authenticate("10.0.0.1", "admin", b"secret"));
+    }
+
+    #[test]
+    fn hashes_passwords_with_random_salts() {
+        let hash = hash_password(b"secret");
+
+        assert!(hash.starts_with("pbkdf2-sha256$600000$"));
+        assert_ne!(hash, hash_password(b"secret"));
+        assert!(PasswordHash::parse(&hash).unwrap().verify(b"secret"));
+    }
+
+    #[test]
+    fn locks_out_sources_failing_repeatedly() {
+        let authenticator = authenticator();
+        let now = Instant::now();
+
+        // Unknown users count as failures too
+        for _ in 0..MAX_FAILURES - 1 {
+            assert!(!authenticator.authenticate_at("10.0.0.1", "worker", b"guess", now));
+        }
+        assert!(!authenticator.authenticate_at("10.0.0.1", "admin", b"guess", now));
+
+        assert!(!authenticator.authenticate_at("10.0.0.1", "worker", b"secret", now + LOCKOUT / 2));
+        assert!(authenticator.authenticate_at("10.0.0.1", "worker", b"secret", now + LOCKOUT));
+    }
+
+    #[test]
+    fn lockouts_leave_other_sources_alone() {
+        let authenticator = authenticator();
+        let now = Instant::now();
+
+        for _ in 0..MAX_FAILURES {
+            assert!(!authenticator.authenticate_at("10.0.0.1", "worker", b"guess", now));
+        }
+
+        assert!(authenticator.authenticate_at("10.0.0.2", "worker", b"secret", now));
+    }
+
+    #[test]
+    fn checks_unknown_users_against_a_dummy_hash() {
+        let authenticator = authenticator();
+
+        assert_eq!(authenticator.dummy.iterations.get(), 10);
+        assert_eq!(Authenticator::parse("").unwrap().dummy.iterations.get(), ITERATIONS);
+    }
+
+    #[test]
+    fn rejects_malformed_users_files() {
+        assert!(Authenticator::parse("worker").is_err());
+        assert!(Authenticator::parse("worker:plaintext").is_err());
+        assert!(Authenticator::parse("worker:pbkdf2-sha256$10$00$abc").is_err());
+    }
+}
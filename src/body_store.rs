@@ -0,0 +1,324 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+#[cfg(unix)]
+use std::os::unix::fs::FileExt;
+#[cfg(windows)]
+use std::os::windows::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+// Segments are started afresh once they reach this size
+const SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+
+const SEGMENT_PREFIX: &str = "bodies-";
+const SEGMENT_SUFFIX: &str = ".seg";
+
+// Keeps job bodies in append-only segment files, so that only their metadata
+// takes up memory. A segment is deleted once none of the bodies in it are
+// needed anymore. Nothing survives a restart, the files are only there to get
+// bodies out of memory.
+//
+// Room for a body is set aside first and written to through a `Handle`, so
+// that callers can do the I/O without holding whatever guards the store.
+pub struct BodyStore {
+    dir: PathBuf,
+    segments: BTreeMap<u32, Segment>,
+    // Segment bodies are appended to
+    current: u32,
+    // Bytes of bodies that are stored and still needed
+    used: u64,
+}
+
+struct Segment {
+    // Shared with handles, which read and write at an offset rather than
+    // seeking
+    file: Arc<File>,
+    len: u64,
+    // Bodies in the segment that are still needed
+    live: usize,
+}
+
+// Where a stored body is
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Location {
+    pub segment: u32,
+    offset: u64,
+    pub len: usize,
+}
+
+// The body at a location, to be read or written without the store
+pub struct Handle {
+    file: Arc<File>,
+    location: Location,
+}
+
+impl Handle {
+    pub fn location(&self) -> Location {
+        self.location
+    }
+
+    pub fn write(&self, body: &[u8]) -> io::Result<()> {
+        assert_eq!(body.len(), self.location.len);
+        write_all_at(&self.file, body, self.location.offset)
+    }
+
+    pub fn read(&self) -> io::Result<Vec<u8>> {
+        let mut body = vec![0; self.location.len];
+        read_exact_at(&self.file, &mut body, self.location.offset)?;
+
+        Ok(body)
+    }
+}
+
+impl BodyStore {
+    // Stores bodies in the directory, creating it if needed. Segments left
+    // behind by an earlier run are removed.
+    pub fn open(dir: &Path) -> io::Result<BodyStore> {
+        fs::create_dir_all(dir)?;
+
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if segment_number(&path).is_some() {
+                info!("Removing stale segment {}", path.display());
+                fs::remove_file(&path)?;
+            }
+        }
+
+        let mut store = BodyStore {
+            dir: dir.to_path_buf(),
+            segments: BTreeMap::new(),
+            current: 0,
+            used: 0,
+        };
+        store.start_segment()?;
+
+        Ok(store)
+    }
+
+    // Bytes of bodies stored
+    pub fn used(&self) -> u64 {
+        self.used
+    }
+
+    // Sets aside room for a body of `len` bytes, which counts as stored from
+    // now on. Has to be freed if writing the body through the handle fails.
+    pub fn allocate(&mut self, len: usize) -> io::Result<Handle> {
+        if self.segments[&self.current].len >= SEGMENT_SIZE {
+            self.start_segment()?;
+        }
+
+        let segment = self.segments.get_mut(&self.current).unwrap();
+        let location = Location { segment: self.current, offset: segment.len, len };
+
+        segment.len += len as u64;
+        segment.live += 1;
+        self.used += len as u64;
+
+        Ok(Handle {file: segment.file.clone(), location})
+    }
+
+    // Handle to read a stored body with. It stays readable even once the
+    // body is freed.
+    pub fn handle(&self, location: &Location) -> io::Result<Handle> {
+        let segment = self.segments.get(&location.segment)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "segment is gone"))?;
+
+        Ok(Handle {file: segment.file.clone(), location: *location})
+    }
+
+    pub fn write(&mut self, body: &[u8]) -> io::Result<Location> {
+        let handle = self.allocate(body.len())?;
+
+        if let Err(err) = handle.write(body) {
+            self.free(&handle.location);
+            return Err(err);
+        }
+
+        Ok(handle.location)
+    }
+
+    pub fn read(&self, location: &Location) -> io::Result<Vec<u8>> {
+        self.handle(location)?.read()
+    }
+
+    // Lets go of a body that isn't needed anymore.
+    pub fn free(&mut self, location: &Location) {
+        let emptied = match self.segments.get_mut(&location.segment) {
+            Some(segment) => {
+                segment.live -= 1;
+                segment.live == 0 && location.segment != self.current
+            },
+            None => return,
+        };
+        self.used -= location.len as u64;
+
+        if emptied {
+            self.segments.remove(&location.segment);
+
+            let path = self.segment_path(location.segment);
+            if let Err(err) = fs::remove_file(&path) {
+                warn!("Failed removing segment {}: {}", path.display(), err);
+            }
+        }
+    }
+
+    fn start_segment(&mut self) -> io::Result<()> {
+        let number = self.current + 1;
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(self.segment_path(number))?;
+
+        self.segments.insert(number, Segment { file: Arc::new(file), len: 0, live: 0 });
+        self.current = number;
+
+        // The segment bodies were appended to until now may have been done
+        // with already
+        let previous = number - 1;
+        if self.segments.get(&previous).is_some_and(|segment| segment.live == 0) {
+            self.segments.remove(&previous);
+            let _ = fs::remove_file(self.segment_path(previous));
+        }
+
+        Ok(())
+    }
+
+    fn segment_path(&self, number: u32) -> PathBuf {
+        self.dir.join(format!("{}{:08}{}", SEGMENT_PREFIX, number, SEGMENT_SUFFIX))
+    }
+}
+
+#[cfg(unix)]
+fn write_all_at(file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+    file.write_all_at(buf, offset)
+}
+
+#[cfg(unix)]
+fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    file.read_exact_at(buf, offset)
+}
+
+#[cfg(windows)]
+fn write_all_at(file: &File, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+    while !buf.is_empty() {
+        match file.seek_write(buf, offset)? {
+            0 => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+            written => {
+                buf = &buf[written..];
+                offset += written as u64;
+            },
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset)? {
+            0 => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            read => {
+                buf = &mut buf[read..];
+                offset += read as u64;
+            },
+        }
+    }
+
+    Ok(())
+}
+
+fn segment_number(path: &Path) -> Option<u32> {
+    path.file_name()?
+        .to_str()?
+        .strip_prefix(SEGMENT_PREFIX)?
+        .strip_suffix(SEGMENT_SUFFIX)?
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::process;
+
+    fn dir(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("beanstalkdrs-test-{}-{}", process::id(), name))
+    }
+
+    #[test]
+    fn reads_back_what_was_written() {
+        let dir = dir("bodies");
+        let mut sut = BodyStore::open(&dir).unwrap();
+
+        let first = sut.write(b"hello").unwrap();
+        let second = sut.write(b"").unwrap();
+        let third = sut.write(b"world\r\n").unwrap();
+
+        assert_eq!(sut.read(&third).unwrap(), b"world\r\n");
+        assert_eq!(sut.read(&second).unwrap(), b"");
+        assert_eq!(sut.read(&first).unwrap(), b"hello");
+        assert_eq!(sut.used(), 12);
+
+        sut.free(&first);
+        assert_eq!(sut.used(), 7);
+        assert_eq!(sut.read(&third).unwrap(), b"world\r\n");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn handles_work_without_the_store() {
+        let dir = dir("handles");
+        let mut sut = BodyStore::open(&dir).unwrap();
+
+        let first = sut.allocate(5).unwrap();
+        let second = sut.allocate(3).unwrap();
+        second.write(b"bar").unwrap();
+        first.write(b"hello").unwrap();
+        assert_eq!(sut.used(), 8);
+
+        assert_eq!(sut.handle(&first.location()).unwrap().read().unwrap(), b"hello");
+        assert_eq!(sut.read(&second.location()).unwrap(), b"bar");
+
+        // The file stays open for handles even once its segment is removed
+        let handle = sut.handle(&first.location()).unwrap();
+        sut.start_segment().unwrap();
+        sut.free(&first.location());
+        sut.free(&second.location());
+        assert!(!dir.join("bodies-00000001.seg").exists());
+        assert_eq!(handle.read().unwrap(), b"hello");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn removes_segments_once_they_are_done_with() {
+        let dir = dir("segments");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("bodies-00000007.seg"), b"stale").unwrap();
+        fs::write(dir.join("notes.txt"), b"kept").unwrap();
+
+        let mut sut = BodyStore::open(&dir).unwrap();
+        assert!(!dir.join("bodies-00000007.seg").exists());
+        assert!(dir.join("notes.txt").exists());
+
+        let body = sut.write(b"job").unwrap();
+        sut.start_segment().unwrap();
+        let later = sut.write(b"later").unwrap();
+        assert!(dir.join("bodies-00000001.seg").exists());
+
+        sut.free(&body);
+        assert!(!dir.join("bodies-00000001.seg").exists());
+
+        // The segment being written to is kept even if it is empty
+        sut.free(&later);
+        assert!(dir.join("bodies-00000002.seg").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,9 @@
+use std::fmt::Display;
+
+// Flat key/value documents like the stats responses, written out as YAML for
+// the beanstalkd protocol or as JSON for HTTP.
+pub trait Document: Sized {
+    fn number<V: Display>(self, key: &str, value: V) -> Self;
+
+    fn string(self, key: &str, value: &str) -> Self;
+}
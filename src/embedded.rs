@@ -0,0 +1,73 @@
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use job_queue::JobQueue;
+use server::Server;
+
+// A server running inside the current process on an ephemeral local port, for
+// tests that need a real beanstalkd endpoint. Dropping it shuts the server
+// down.
+//
+//     let server = EmbeddedServer::start().unwrap();
+//     let client = TcpStream::connect(server.addr()).unwrap();
+pub struct EmbeddedServer {
+    server: Server,
+    addr: SocketAddr,
+    listener: Option<JoinHandle<io::Result<()>>>,
+}
+
+impl EmbeddedServer {
+    pub fn start() -> io::Result<EmbeddedServer> {
+        EmbeddedServer::with_job_queue(JobQueue::new())
+    }
+
+    // Serves the given queue, e.g. one created with a `ManualClock`.
+    pub fn with_job_queue(job_queue: JobQueue) -> io::Result<EmbeddedServer> {
+        EmbeddedServer::with_server(Server::new(job_queue))
+    }
+
+    // Serves an already configured server, e.g. one requiring authentication.
+    pub fn with_server(server: Server) -> io::Result<EmbeddedServer> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+
+        let listener = {
+            let server = server.clone();
+            thread::spawn(move || server.listen(listener))
+        };
+
+        Ok(EmbeddedServer {
+            server,
+            addr,
+            listener: Some(listener),
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn job_queue(&self) -> Arc<Mutex<JobQueue>> {
+        self.server.job_queue()
+    }
+
+    pub fn server(&self) -> &Server {
+        &self.server
+    }
+}
+
+impl Drop for EmbeddedServer {
+    fn drop(&mut self) {
+        self.server.shutdown();
+
+        if let Some(listener) = self.listener.take() {
+            match listener.join() {
+                Ok(Err(err)) => warn!("Embedded server stopped listening: {:?}", err),
+                Err(_) => warn!("Embedded server listener panicked"),
+                Ok(Ok(())) => {},
+            }
+        }
+    }
+}
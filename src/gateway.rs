@@ -0,0 +1,222 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use ring::rand::{SecureRandom, SystemRandom};
+
+use acl::Operation;
+use document::Document;
+use http::{Request, Response};
+use json;
+use parser::is_tube_name;
+use server::{PutError, ReserveError, Server};
+
+// Defaults of put parameters the request leaves out
+const DEFAULT_PRI: u32 = 1024;
+const DEFAULT_TTR: u32 = 60;
+
+// Lets producers and workers that can't keep a connection open use the queue
+// over HTTP:
+//
+//     POST /tubes/<tube>/jobs?pri=P&delay=D&ttr=T  puts the request body into
+//                                                  the tube, 429 if it's full
+//     POST /reserve?tube=T[&tube=T...]&timeout=S   reserves a job from any of
+//                                                  the tubes, waiting up to S
+//                                                  seconds for one
+//     POST /jobs/<id>/delete                       deletes a reserved job
+//     POST /jobs/<id>/release?pri=P&delay=D        releases it
+//     POST /jobs/<id>/bury?pri=P                   buries it
+//     POST /jobs/<id>/touch                        asks for another TTR
+//
+// A reservation isn't bound to the connection it was made on but to the lease
+// token sent along with the job in the `X-Lease` header. Requests for the job
+// have to send the token back in the same header, and it is good until the
+// reservation ends, e.g. because its TTR ran out.
+pub struct Gateway {
+    // Client the gateway holds every reservation as in the job queue
+    client_id: u64,
+    // Job and reserve count each lease token stands for
+    leases: Mutex<HashMap<String, (u64, u32)>>,
+    random: SystemRandom,
+}
+
+impl Gateway {
+    pub fn new(client_id: u64) -> Gateway {
+        Gateway {
+            client_id,
+            leases: Mutex::new(HashMap::new()),
+            random: SystemRandom::new(),
+        }
+    }
+
+    pub fn handle(&self, server: &Server, request: &Request) -> Response {
+        let user = match server.http_identity(request) {
+            Ok(user) => user,
+            Err(response) => return response,
+        };
+
+        let segments = match request.segments() {
+            Some(segments) => segments,
+            None => return Response::status(400),
+        };
+        let segments: Vec<&str> = segments.iter().map(|segment| segment.as_str()).collect();
+
+        if request.method != "POST" {
+            return Response::status(if segments.is_empty() { 404 } else { 405 });
+        }
+
+        match segments[..] {
+            ["tubes", tube, "jobs"] => self.put(server, user.as_deref(), tube, request),
+            ["reserve"] => self.reserve(server, user.as_deref(), request),
+            ["jobs", id, action] => match id.parse() {
+                Ok(id) => self.finish(server, user.as_deref(), id, action, request),
+                Err(_) => Response::status(404),
+            },
+            _ => Response::status(404),
+        }
+    }
+
+    fn put(&self, server: &Server, user: Option<&str>, tube: &str, request: &Request) -> Response {
+        if !is_tube_name(tube) {
+            return Response::status(400);
+        }
+        if !server.allows(user, tube, Operation::Produce) {
+            return Response::status(403);
+        }
+
+        let (pri, delay, ttr) = match (
+            param(request, "pri", DEFAULT_PRI),
+            param(request, "delay", 0),
+            param(request, "ttr", DEFAULT_TTR),
+        ) {
+            (Some(pri), Some(delay), Some(ttr)) => (pri, delay, ttr),
+            _ => return Response::status(400),
+        };
+
+        match server.put(tube, pri, delay, ttr, &request.body) {
+            Ok(id) => Response::json(201, json::Object::new().number("id", id).into_string()),
+            Err(PutError::JobTooBig) => Response::status(413),
+            Err(PutError::Draining) => Response::status(503),
+            Err(PutError::OutOfMemory) => Response::status(507),
+            Err(PutError::TubeFull) => Response::status(429),
+            Err(PutError::Internal) => Response::status(500),
+        }
+    }
+
+    // Long-polls for a job the same way `reserve-with-timeout` does, without
+    // a timeout it only takes a job that is ready right away.
+    fn reserve(&self, server: &Server, user: Option<&str>, request: &Request) -> Response {
+        let watching: Vec<String> = match request.query_all("tube") {
+            ref tubes if tubes.is_empty() => vec!["default".to_string()],
+            tubes => tubes.into_iter().map(|tube| tube.to_string()).collect(),
+        };
+        if !watching.iter().all(|tube| is_tube_name(tube)) {
+            return Response::status(400);
+        }
+        if watching.iter().any(|tube| !server.allows(user, tube, Operation::Consume)) {
+            return Response::status(403);
+        }
+
+        let timeout = match param(request, "timeout", 0) {
+            Some(timeout) => timeout,
+            None => return Response::status(400),
+        };
+
+        let (id, data) = match server.reserve(self.client_id, &watching, Some(timeout), || Ok(())) {
+            Ok(job) => job,
+            Err(ReserveError::ShuttingDown) => return Response::status(503),
+            Err(_) => return Response::status(204),
+        };
+
+        let job_queue = server.job_queue();
+        let job_queue = job_queue.lock().unwrap();
+        // The reservation may have run out already, the job is ready again
+        // then
+        let reserves = match job_queue.reservation(&id) {
+            Some((client, reserves)) if client == self.client_id => reserves,
+            _ => return Response::status(204),
+        };
+        let token = self.lease_token();
+        let mut leases = self.leases.lock().unwrap();
+        // Forgets leases whose reservations ended without the worker saying
+        // so, so that they don't pile up
+        leases.retain(|_, &mut (id, reserves)| job_queue.reservation(&id) == Some((self.client_id, reserves)));
+        leases.insert(token.clone(), (id, reserves));
+
+        Response::new(200, "application/octet-stream", data.to_vec())
+            .with_header("X-Job-Id", id.to_string())
+            .with_header("X-Lease", token)
+    }
+
+    // Deletes, releases, buries or touches a job reserved through the
+    // gateway, given its lease.
+    fn finish(&self, server: &Server, user: Option<&str>, id: u64, action: &str, request: &Request) -> Response {
+        let token = match request.header("x-lease") {
+            Some(token) => token,
+            None => return Response::status(400),
+        };
+
+        let job_queue = server.job_queue();
+        let mut job_queue = job_queue.lock().unwrap();
+
+        let mut leases = self.leases.lock().unwrap();
+        let leased = match leases.get(token) {
+            Some(&(leased, reserves)) => {
+                leased == id && job_queue.reservation(&id) == Some((self.client_id, reserves))
+            },
+            None => false,
+        };
+        if !leased {
+            return Response::status(404);
+        }
+
+        match job_queue.job_tube(&id) {
+            Some(tube) if !server.allows(user, tube, Operation::Consume) => return Response::status(403),
+            _ => {},
+        }
+
+        let pri = job_queue.stats_job(&id).unwrap().pri;
+        match action {
+            "delete" => {
+                job_queue.delete(&id);
+                server.wake_up();
+            },
+            "release" => match (param(request, "pri", pri), param(request, "delay", 0)) {
+                (Some(pri), Some(delay)) => {
+                    job_queue.release(&id, pri, delay);
+                    server.wake_up();
+                },
+                _ => return Response::status(400),
+            },
+            "bury" => match param(request, "pri", pri) {
+                Some(pri) => {
+                    job_queue.bury(&id, pri);
+                },
+                None => return Response::status(400),
+            },
+            "touch" => {
+                job_queue.touch(&id);
+                return Response::status(204);
+            },
+            _ => return Response::status(404),
+        }
+
+        leases.remove(token);
+        Response::status(204)
+    }
+
+    fn lease_token(&self) -> String {
+        let mut token = [0; 16];
+        self.random.fill(&mut token).expect("no randomness for a lease token");
+
+        token.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+}
+
+// Query parameter parsed as a number, `None` if it isn't one.
+fn param<T: FromStr>(request: &Request, name: &str, default: T) -> Option<T> {
+    match request.query(name) {
+        Some(value) => value.parse().ok(),
+        None => Some(default),
+    }
+}
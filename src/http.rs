@@ -0,0 +1,445 @@
+use std::io::{self, Read, Write};
+use std::net::IpAddr;
+use std::str;
+
+// Size of the chunks the input buffer grows by while waiting for a request
+const READ_SIZE: usize = 4096;
+
+// Limits on what a client may send, anything bigger is refused
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+const MAX_BODY_SIZE: usize = 16 * 1024 * 1024;
+
+// Just enough HTTP/1.1 for the HTTP endpoints to be served from the same
+// listeners and connection threads as the beanstalkd protocol.
+pub struct Request {
+    pub method: String,
+    // Without the query string
+    pub path: String,
+    // Decoded query parameters
+    query: Vec<(String, String)>,
+    headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    // Address the request came from, if known
+    pub peer: Option<IpAddr>,
+}
+
+impl Request {
+    // Value of the header, names being case insensitive.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    // Value of the query parameter, if given once or more.
+    pub fn query(&self, name: &str) -> Option<&str> {
+        self.query.iter()
+            .find(|(param, _)| param == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    // Every value of a query parameter that may be given more than once.
+    pub fn query_all(&self, name: &str) -> Vec<&str> {
+        self.query.iter()
+            .filter(|(param, _)| param == name)
+            .map(|(_, value)| value.as_str())
+            .collect()
+    }
+
+    // Decoded segments of the path, e.g. `["tubes", "a/b"]` for
+    // `/tubes/a%2Fb`. `None` if a segment isn't valid percent-encoded UTF-8.
+    pub fn segments(&self) -> Option<Vec<String>> {
+        self.path.trim_start_matches('/')
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .map(|segment| decode(segment, false))
+            .collect()
+    }
+
+    // User and password sent with HTTP basic authentication
+    pub fn basic_auth(&self) -> Option<(String, String)> {
+        let (scheme, credentials) = self.header("authorization")?.split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return None;
+        }
+
+        let credentials = String::from_utf8(decode_base64(credentials.trim())?).ok()?;
+        let (user, password) = credentials.split_once(':')?;
+
+        Some((user.to_string(), password.to_string()))
+    }
+
+    fn keep_alive(&self, version: &str) -> bool {
+        match self.header("connection") {
+            Some(connection) if connection.eq_ignore_ascii_case("close") => false,
+            Some(connection) if connection.eq_ignore_ascii_case("keep-alive") => true,
+            _ => version == "HTTP/1.1",
+        }
+    }
+}
+
+pub struct Response {
+    status: u16,
+    content_type: &'static str,
+    headers: Vec<(&'static str, String)>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status: u16, content_type: &'static str, body: Vec<u8>) -> Response {
+        Response {status, content_type, headers: vec![], body}
+    }
+
+    pub fn json(status: u16, json: String) -> Response {
+        Response::new(status, "application/json", json.into_bytes())
+    }
+
+    pub fn text(status: u16, body: &str) -> Response {
+        Response::new(status, "text/plain; charset=utf-8", body.as_bytes().to_vec())
+    }
+
+    // Error response whose body is just the reason phrase
+    pub fn status(status: u16) -> Response {
+        if status == 204 {
+            return Response::new(status, "text/plain; charset=utf-8", vec![]);
+        }
+
+        Response::text(status, &format!("{}\n", reason(status)))
+    }
+
+    pub fn with_header(mut self, name: &'static str, value: String) -> Response {
+        self.headers.push((name, value));
+        self
+    }
+
+    pub fn write_to<W: Write + ?Sized>(&self, writer: &mut W, keep_alive: bool) -> io::Result<()> {
+        let mut head = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: {}\r\n",
+            self.status,
+            reason(self.status),
+            self.content_type,
+            self.body.len(),
+            if keep_alive { "keep-alive" } else { "close" }
+        );
+        for &(name, ref value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str("\r\n");
+
+        let mut response = head.into_bytes();
+        response.extend_from_slice(&self.body);
+        writer.write_all(&response)?;
+        writer.flush()
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        507 => "Insufficient Storage",
+        _ => "Unknown",
+    }
+}
+
+// Answers requests from the stream, whose client connected from `peer`, with
+// `handle` until the client closes the connection or asks for it to be
+// closed.
+pub fn serve<S, H>(stream: &mut S, peer: Option<IpAddr>, mut handle: H) -> io::Result<()>
+    where S: Read + Write + ?Sized, H: FnMut(&Request) -> Response
+{
+    let mut buffer = vec![];
+
+    loop {
+        let (mut request, version) = match read_request(stream, &mut buffer)? {
+            Ok(Some(request)) => request,
+            Ok(None) => return Ok(()),
+            Err(response) => return response.write_to(stream, false),
+        };
+
+        request.peer = peer;
+        let keep_alive = request.keep_alive(&version);
+        handle(&request).write_to(stream, keep_alive)?;
+
+        if !keep_alive {
+            return Ok(());
+        }
+    }
+}
+
+// Reads the next request, leaving whatever follows it in the buffer. Gives
+// `None` if the client closed the connection in between requests, or the
+// error response for a request that can't be served.
+pub fn read_request<S>(stream: &mut S, buffer: &mut Vec<u8>) -> io::Result<Result<Option<(Request, String)>, Response>>
+    where S: Read + ?Sized
+{
+    let head_len = loop {
+        if let Some(end) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+            break end + 4;
+        }
+
+        if buffer.len() > MAX_HEAD_SIZE {
+            return Ok(Err(Response::status(431)));
+        }
+
+        if !fill(stream, buffer)? {
+            return Ok(Ok(None));
+        }
+    };
+
+    let (method, path, query, version, headers) = match parse_head(&buffer[..head_len]) {
+        Some(head) => head,
+        None => return Ok(Err(Response::status(400))),
+    };
+
+    let mut request = Request {method, path, query, headers, body: vec![], peer: None};
+
+    if request.header("transfer-encoding").is_some() {
+        return Ok(Err(Response::status(411)));
+    }
+
+    let body_len = match request.header("content-length").map(|len| len.trim().parse::<usize>()) {
+        None => 0,
+        Some(Ok(len)) if len <= MAX_BODY_SIZE => len,
+        Some(Ok(_)) => return Ok(Err(Response::status(413))),
+        Some(Err(_)) => return Ok(Err(Response::status(400))),
+    };
+
+    while buffer.len() < head_len + body_len {
+        if !fill(stream, buffer)? {
+            return Ok(Ok(None));
+        }
+    }
+
+    request.body = buffer[head_len..head_len + body_len].to_vec();
+    buffer.drain(..head_len + body_len);
+
+    Ok(Ok(Some((request, version))))
+}
+
+// Reads more of the stream into the buffer, false once the client is done.
+pub fn fill<S: Read + ?Sized>(stream: &mut S, buffer: &mut Vec<u8>) -> io::Result<bool> {
+    let written = buffer.len();
+    buffer.resize(written + READ_SIZE, 0);
+
+    let len = stream.read(&mut buffer[written..])?;
+    buffer.truncate(written + len);
+
+    Ok(len > 0)
+}
+
+type Head = (String, String, Vec<(String, String)>, String, Vec<(String, String)>);
+
+fn parse_head(head: &[u8]) -> Option<Head> {
+    let head = str::from_utf8(head).ok()?;
+    let mut lines = head.split("\r\n");
+
+    let mut request_line = lines.next()?.split(' ');
+    let method = request_line.next()?.to_string();
+    let target = request_line.next()?;
+    let version = request_line.next()?.to_string();
+    if request_line.next().is_some() || !version.starts_with("HTTP/1.") {
+        return None;
+    }
+
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let query = query.split('&')
+        .filter(|param| !param.is_empty())
+        .map(|param| {
+            let (name, value) = param.split_once('=').unwrap_or((param, ""));
+            Some((decode(name, true)?, decode(value, true)?))
+        })
+        .collect::<Option<_>>()?;
+
+    let mut headers = vec![];
+    for line in lines.filter(|line| !line.is_empty()) {
+        let (name, value) = line.split_once(':')?;
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+
+    Some((method, path.to_string(), query, version, headers))
+}
+
+const BASE64_ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub fn encode_base64(bytes: &[u8]) -> String {
+    let mut encoded = String::new();
+
+    for chunk in bytes.chunks(3) {
+        let bits = chunk.iter().fold(0u32, |bits, &byte| bits << 8 | byte as u32) << (8 * (3 - chunk.len()));
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(BASE64_ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+
+    encoded
+}
+
+fn decode_base64(encoded: &str) -> Option<Vec<u8>> {
+    let encoded = encoded.trim_end_matches('=').as_bytes();
+    let mut decoded = vec![];
+    let mut bits = 0u32;
+    let mut bit_count = 0;
+
+    for &c in encoded {
+        bits = bits << 6 | BASE64_ALPHABET.iter().position(|&a| a == c)? as u32;
+        bit_count += 6;
+
+        if bit_count >= 8 {
+            bit_count -= 8;
+            decoded.push((bits >> bit_count) as u8);
+        }
+    }
+
+    Some(decoded)
+}
+
+// Undoes percent-encoding, and in query strings the `+` standing for a space.
+fn decode(encoded: &str, query: bool) -> Option<String> {
+    let bytes = encoded.as_bytes();
+    let mut decoded = vec![];
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+                decoded.push(u8::from_str_radix(hex, 16).ok()?);
+                i += 3;
+                continue;
+            },
+            b'+' if query => decoded.push(b' '),
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    // Input from a client and whatever was written back to it
+    struct Exchange {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Exchange {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Exchange {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn exchange(input: &[u8]) -> (Vec<String>, String) {
+        let mut stream = Exchange {input: Cursor::new(input.to_vec()), output: vec![]};
+        let mut seen = vec![];
+
+        serve(&mut stream, None, |request| {
+            seen.push(format!("{} {} {}", request.method, request.path, String::from_utf8_lossy(&request.body)));
+            Response::text(200, "hi")
+        }).unwrap();
+
+        (seen, String::from_utf8(stream.output).unwrap())
+    }
+
+    #[test]
+    fn serves_pipelined_requests() {
+        let (seen, output) = exchange(
+            b"GET /metrics?x=1 HTTP/1.1\r\nHost: a\r\n\r\nPOST /jobs HTTP/1.1\r\ncontent-length: 5\r\n\r\nhello"
+        );
+
+        assert_eq!(seen, vec!["GET /metrics ", "POST /jobs hello"]);
+        assert_eq!(output.matches("HTTP/1.1 200 OK\r\n").count(), 2);
+        assert!(output.contains("Content-Length: 2\r\nConnection: keep-alive\r\n\r\nhi"));
+    }
+
+    #[test]
+    fn closes_when_asked_to() {
+        let (seen, output) = exchange(b"GET / HTTP/1.0\r\n\r\nGET /ignored HTTP/1.1\r\n\r\n");
+
+        assert_eq!(seen, vec!["GET / "]);
+        assert!(output.contains("Connection: close\r\n"));
+    }
+
+    #[test]
+    fn decodes_paths_and_queries() {
+        let mut stream = Exchange {
+            input: Cursor::new(b"POST /tubes/a%2Fb/kick?bound=10&note=hi+there%21&note=again HTTP/1.0\r\n\r\n".to_vec()),
+            output: vec![],
+        };
+
+        serve(&mut stream, None, |request| {
+            assert_eq!(request.segments(), Some(vec!["tubes".to_string(), "a/b".to_string(), "kick".to_string()]));
+            assert_eq!(request.query("bound"), Some("10"));
+            assert_eq!(request.query("note"), Some("hi there!"));
+            assert_eq!(request.query_all("note"), vec!["hi there!", "again"]);
+            assert_eq!(request.query("missing"), None);
+            Response::status(204)
+        }).unwrap();
+
+        assert!(String::from_utf8(stream.output).unwrap().starts_with("HTTP/1.1 204 No Content\r\n"));
+    }
+
+    #[test]
+    fn reads_basic_auth_credentials() {
+        let mut stream = Exchange {
+            input: Cursor::new(b"GET / HTTP/1.0\r\nAuthorization: Basic b3BzOnMzY3I6dA==\r\n\r\n".to_vec()),
+            output: vec![],
+        };
+
+        serve(&mut stream, None, |request| {
+            assert_eq!(request.basic_auth(), Some(("ops".to_string(), "s3cr:t".to_string())));
+            Response::status(204)
+        }).unwrap();
+    }
+
+    #[test]
+    fn encodes_base64() {
+        assert_eq!(encode_base64(b"ops:s3cr:t"), "b3BzOnMzY3I6dA==");
+        assert_eq!(encode_base64(b"ab"), "YWI=");
+        assert_eq!(encode_base64(b"abc"), "YWJj");
+        assert_eq!(decode_base64(&encode_base64(&[0, 255, 128, 7])), Some(vec![0, 255, 128, 7]));
+    }
+
+    #[test]
+    fn refuses_malformed_requests() {
+        let (seen, output) = exchange(b"GET /\r\n\r\n");
+
+        assert!(seen.is_empty());
+        assert!(output.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    }
+}
@@ -1,253 +1,1170 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::io;
+use std::mem;
+use std::process;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use acl;
+use body_store::{BodyStore, Handle, Location};
+use timer::{Clock, Deadline, SystemClock, Timer};
+use document::Document;
+use yaml::Dict;
+
+// beanstalkd silently raises a TTR of 0 to one second
+const MIN_TTR: u32 = 1;
+
+// Ready jobs more important than this count as urgent
+const URGENT_PRI: u32 = 1024;
+
+pub const DEFAULT_TUBE: &str = "default";
+
+// Biggest job body taken unless the queue is told otherwise, same as
+// beanstalkd's
+pub const DEFAULT_MAX_JOB_SIZE: usize = 65535;
+
+// Memory a job takes up, roughly. Counts what the job holds on to, not what
+// indexing it costs.
+fn job_memory(tube: &str, body_size: usize) -> usize {
+    mem::size_of::<Job>() + tube.len() + body_size
+}
+
+enum Body {
+    // Shared so handing a job out never copies its body
+    Memory(Arc<[u8]>),
+    // Spilled to the body store
+    Disk(Location),
+}
+
+impl Body {
+    fn len(&self) -> usize {
+        match *self {
+            Body::Memory(ref data) => data.len(),
+            Body::Disk(ref location) => location.len,
+        }
+    }
+
+    // Bytes of the body kept in memory
+    fn memory(&self) -> usize {
+        match *self {
+            Body::Memory(ref data) => data.len(),
+            Body::Disk(_) => 0,
+        }
+    }
+}
+
+// Body of a job handed out by the queue. Reading a spilled one takes I/O,
+// which is best done once the queue is let go of.
+pub enum JobBody {
+    Memory(Arc<[u8]>),
+    Disk(Handle),
+}
+
+impl JobBody {
+    pub fn read(&self) -> io::Result<Arc<[u8]>> {
+        match *self {
+            JobBody::Memory(ref data) => Ok(data.clone()),
+            JobBody::Disk(ref handle) => Ok(handle.read()?.into()),
+        }
+    }
+}
+
+// Body of a job about to be put, see `JobQueue::prepare_body`
+pub(crate) struct NewBody {
+    body: Body,
+    // Spilled body yet to be written to the store
+    unwritten: Option<(Handle, Arc<[u8]>)>,
+}
+
+impl NewBody {
+    // Bytes of the body kept in memory
+    pub(crate) fn memory(&self) -> usize {
+        self.body.memory()
+    }
+
+    // Writes a body to be spilled to the store, a no-op for those kept in
+    // memory. Meant to be done without holding the queue.
+    pub(crate) fn write(&mut self) -> io::Result<()> {
+        match self.unwritten.take() {
+            Some((handle, data)) => handle.write(&data),
+            None => Ok(()),
+        }
+    }
+}
+
+// When bodies are stored on disk rather than in memory
+struct Spill {
+    store: BodyStore,
+    // Bodies at least this big are spilled
+    min_size: Option<usize>,
+    // Bodies are spilled while jobs take up more memory than this
+    watermark: Option<usize>,
+}
+
+// How much a tube may hold, puts beyond it are refused or have to wait for
+// room
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TubeLimit {
+    pub max_jobs: Option<usize>,
+    // Bytes of job bodies
+    pub max_bytes: Option<usize>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum JobState {
+    Ready,
+    Reserved,
+    Delayed,
+    Buried,
+}
 
 pub struct Job {
-    id: u8,
-    data: Vec<u8>,
+    tube: String,
+    pri: u32,
+    // Delay of the last put or release
+    delay: u32,
+    ttr: u32,
+    created_at: Instant,
+    data: Body,
+    state: JobState,
+    // When a delayed job becomes ready or a reserved job runs out of time
+    deadline: Option<Instant>,
+    // Client holding the reservation
+    reserved_by: Option<u64>,
+    // Orders the job among buried ones while it is buried
+    buried_at: u64,
+    reserves: u32,
+    timeouts: u32,
+    releases: u32,
+    buries: u32,
+    kicks: u32,
 }
 
-impl Job {
-    fn new(id: u8, data: Vec<u8>) -> Job {
-        Job { id: id, data: data }
+// Jobs themselves live in `JobQueue::jobs`, tubes only index them. Ordered sets
+// are used as priority queues because, unlike a binary heap, they can drop an
+// arbitrary job (delete, touch) in O(log n) as well.
+struct Tube {
+    ready: BTreeSet<(u32, u64)>,
+    // Ready jobs with a priority below `URGENT_PRI`
+    urgent: usize,
+    delayed: BTreeSet<(Instant, u64)>,
+    // In the order jobs were buried, which is the order they get kicked in
+    buried: BTreeSet<(u64, u64)>,
+    reserved: usize,
+    paused_until: Option<Instant>,
+    // Length of the current pause in seconds
+    pause: u32,
+    total_jobs: u64,
+    // Bytes of the bodies of its jobs
+    bytes: usize,
+    // Connections using, watching and blocked on reserving from the tube
+    using: usize,
+    watching: usize,
+    waiting: usize,
+    cmd_delete: u64,
+    cmd_pause_tube: u64,
+}
+
+impl Tube {
+    fn new() -> Tube {
+        Tube {
+            ready: BTreeSet::new(),
+            urgent: 0,
+            delayed: BTreeSet::new(),
+            buried: BTreeSet::new(),
+            reserved: 0,
+            paused_until: None,
+            pause: 0,
+            total_jobs: 0,
+            bytes: 0,
+            using: 0,
+            watching: 0,
+            waiting: 0,
+            cmd_delete: 0,
+            cmd_pause_tube: 0,
+        }
+    }
+
+    fn current_jobs(&self) -> usize {
+        self.ready.len() + self.delayed.len() + self.buried.len() + self.reserved
     }
 }
 
+// Connections that came in through one listener
+#[derive(Default)]
+struct ListenerStats {
+    current_connections: usize,
+    total_connections: u64,
+}
+
 pub struct JobQueue {
-    ready_jobs: HashMap<u8, Job>,
-    reserved_jobs: HashMap<u8, Job>,
-    auto_increment_index: u8,
+    clock: Box<dyn Clock>,
+    timer: Timer,
+    jobs: HashMap<u64, Job>,
+    tubes: HashMap<String, Tube>,
+    reserved_count: usize,
+    // Jobs each client has reserved, handed back when it disconnects
+    reservations: HashMap<u64, HashSet<u64>>,
+    auto_increment_index: u64,
+    // Number of times jobs have been buried
+    bury_sequence: u64,
+    started_at: Instant,
+    job_timeouts: u64,
+    // Connected clients and the listener each of them came in through
+    clients: HashMap<u64, String>,
+    listeners: BTreeMap<String, ListenerStats>,
+    total_connections: u64,
+    current_waiting: usize,
+    // While draining, producers are turned away but workers keep going
+    draining: bool,
+    // Bytes taken up by jobs, bodies included
+    memory_used: usize,
+    max_memory: Option<usize>,
+    // Puts of bigger bodies are answered with JOB_TOO_BIG
+    max_job_size: usize,
+    spill: Option<Spill>,
+    // Limits by tube name pattern, the first one matching a tube applies
+    tube_limits: Vec<(String, TubeLimit)>,
 }
 
 impl JobQueue {
     pub fn new() -> JobQueue {
+        JobQueue::with_clock(Box::new(SystemClock))
+    }
+
+    pub fn with_clock(clock: Box<dyn Clock>) -> JobQueue {
+        let mut tubes = HashMap::new();
+        tubes.insert(DEFAULT_TUBE.to_string(), Tube::new());
+
         JobQueue {
-            ready_jobs: HashMap::new(),
-            reserved_jobs: HashMap::new(),
+            started_at: clock.now(),
+            clock,
+            timer: Timer::new(),
+            jobs: HashMap::new(),
+            tubes,
+            reserved_count: 0,
+            reservations: HashMap::new(),
             auto_increment_index: 0,
+            bury_sequence: 0,
+            job_timeouts: 0,
+            clients: HashMap::new(),
+            listeners: BTreeMap::new(),
+            total_connections: 0,
+            current_waiting: 0,
+            draining: false,
+            memory_used: 0,
+            max_memory: None,
+            max_job_size: DEFAULT_MAX_JOB_SIZE,
+            spill: None,
+            tube_limits: vec![],
         }
     }
 
-    #[allow(unused_variables)]
-    pub fn put(&mut self, pri: u8, delay: u8, ttr: u8, data: Vec<u8>) -> u8 {
-        self.auto_increment_index += 1;
+    pub fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining
+    }
+
+    // Puts are refused while draining so the queue can be emptied before
+    // maintenance.
+    pub fn set_draining(&mut self, draining: bool) {
+        if draining != self.draining {
+            info!("{} drain mode", if draining { "Entering" } else { "Leaving" });
+        }
 
-        debug!("Putting job ID {} with data {:?}", self.auto_increment_index, data);
+        self.draining = draining;
+    }
 
-        self.ready_jobs.insert(self.auto_increment_index, Job::new(self.auto_increment_index, data));
+    // Limits how much memory jobs may take up, puts beyond it have to be
+    // refused.
+    pub fn set_max_memory(&mut self, max_memory: Option<usize>) {
+        self.max_memory = max_memory;
+    }
+
+    pub fn max_job_size(&self) -> usize {
+        self.max_job_size
+    }
 
-        self.auto_increment_index
+    // Refuses jobs with bodies of more than `bytes` instead of
+    // `DEFAULT_MAX_JOB_SIZE`.
+    pub fn set_max_job_size(&mut self, bytes: usize) {
+        self.max_job_size = bytes;
     }
 
-    pub fn reserve(&mut self) -> (u8, Vec<u8>) {
-        let key = self.ready_jobs.iter()
-            .next()
-            .map(|(key, _)| key.clone());
+    // Whether putting a job into the tube whose body keeps `in_memory` bytes
+    // in memory stays within the memory limit.
+    pub fn has_memory_for(&self, tube: &str, in_memory: usize) -> bool {
+        match self.max_memory {
+            Some(max_memory) => self.memory_used + job_memory(tube, in_memory) <= max_memory,
+            None => true,
+        }
+    }
+
+    // Limits the tubes whose names match the pattern, where `*` stands for
+    // any run of characters. A tube matching several patterns gets the limit
+    // given first.
+    pub fn limit_tubes(&mut self, pattern: &str, limit: TubeLimit) {
+        self.tube_limits.push((pattern.to_string(), limit));
+    }
+
+    fn tube_limit(&self, tube: &str) -> Option<TubeLimit> {
+        self.tube_limits.iter()
+            .find(|(pattern, _)| acl::matches(pattern, tube))
+            .map(|&(_, limit)| limit)
+    }
 
-        match key {
-            Some(id) => {
-                let job = self.ready_jobs.remove(&id).unwrap();
+    // Whether putting a job with a body of the given size into the tube stays
+    // within the tube's limit.
+    pub fn has_room_in(&self, tube: &str, body_size: usize) -> bool {
+        let limit = match self.tube_limit(tube) {
+            Some(limit) => limit,
+            None => return true,
+        };
+        let (jobs, bytes) = self.tubes.get(tube).map_or((0, 0), |tube| (tube.current_jobs(), tube.bytes));
+
+        limit.max_jobs.is_none_or(|max_jobs| jobs < max_jobs)
+            && limit.max_bytes.is_none_or(|max_bytes| bytes + body_size <= max_bytes)
+    }
+
+    // Keeps the bodies of jobs put from now on in the store rather than in
+    // memory: those of at least `min_size` bytes, and all of them while jobs
+    // take up more than `watermark` bytes of memory. Every body is spilled if
+    // neither is given. Bodies are only ever spilled when put, those already
+    // in memory stay there however long their jobs wait.
+    pub fn spill_bodies(&mut self, store: BodyStore, min_size: Option<usize>, watermark: Option<usize>) {
+        self.spill = Some(Spill {store, min_size, watermark});
+    }
+
+    fn spills(&self, body_size: usize) -> bool {
+        match self.spill {
+            Some(Spill {min_size: None, watermark: None, ..}) => true,
+            Some(ref spill) => {
+                spill.min_size.is_some_and(|min_size| body_size >= min_size)
+                    || spill.watermark.is_some_and(|watermark| self.memory_used + body_size > watermark)
+            },
+            None => false,
+        }
+    }
+
+    // Gets a body ready to be put. One to be spilled is given room in the
+    // store and has to be written with `NewBody::write` before it's put.
+    pub(crate) fn prepare_body(&mut self, data: Arc<[u8]>) -> io::Result<NewBody> {
+        if !self.spills(data.len()) {
+            return Ok(NewBody {body: Body::Memory(data), unwritten: None});
+        }
+
+        let handle = self.spill.as_mut().unwrap().store.allocate(data.len())?;
+        Ok(NewBody {body: Body::Disk(handle.location()), unwritten: Some((handle, data))})
+    }
 
-                let ret = (id, job.data.clone());
+    // Lets go of a body that won't be put after all.
+    pub(crate) fn discard_body(&mut self, body: NewBody) {
+        if let Body::Disk(ref location) = body.body {
+            self.spill.as_mut().unwrap().store.free(location);
+        }
+    }
 
-                self.reserved_jobs.insert(id, Job::new(job.id, job.data.clone()));
+    fn job_body(&self, body: &Body) -> io::Result<JobBody> {
+        match *body {
+            Body::Memory(ref data) => Ok(JobBody::Memory(data.clone())),
+            Body::Disk(ref location) => Ok(JobBody::Disk(self.spill.as_ref().unwrap().store.handle(location)?)),
+        }
+    }
 
-                ret
+    // The job along with its body, for peeks. Bodies that can't be found are
+    // as good as gone.
+    fn peek_job(&self, id: u64) -> Option<(u64, JobBody)> {
+        match self.job_body(&self.jobs.get(&id)?.data) {
+            Ok(body) => Some((id, body)),
+            Err(err) => {
+                error!("Failed finding the body of job {}: {}", id, err);
+                None
             },
-            None => panic!("No more jobs!"),
         }
     }
 
-    pub fn delete(&mut self, id: &u8) -> Option<Job> {
+    // Registers a client that connected through the named listener. Returns
+    // the id the client reserves jobs with.
+    pub fn client_connected(&mut self, listener: &str) -> u64 {
+        self.total_connections += 1;
+        let client = self.total_connections;
+
+        let stats = self.listeners.entry(listener.to_string()).or_default();
+        stats.current_connections += 1;
+        stats.total_connections += 1;
+        self.clients.insert(client, listener.to_string());
+
+        client
+    }
+
+    // Puts the jobs the client still had reserved back into their ready
+    // queues. Returns true if there were any.
+    pub fn client_disconnected(&mut self, client: u64) -> bool {
+        if let Some(listener) = self.clients.remove(&client) {
+            self.listeners.get_mut(&listener).unwrap().current_connections -= 1;
+        }
+
+        let reserved = self.reservations.remove(&client).unwrap_or_default();
+        for &id in &reserved {
+            debug!("Releasing job {} of disconnected client", id);
+            self.unschedule(id);
+            self.schedule(id, 0);
+        }
+
+        !reserved.is_empty()
+    }
+
+    // Makes sure the tube exists, e.g. because a client started using it.
+    pub fn create_tube(&mut self, tube: &str) {
+        self.tube_mut(tube);
+    }
+
+    // Connections report the tubes they use, watch and wait on, so that
+    // `stats-tube` can tell how many of them there are.
+    pub fn start_using(&mut self, tube: &str) {
+        self.tube_mut(tube).using += 1;
+    }
+
+    pub fn stop_using(&mut self, tube: &str) {
+        if let Some(tube) = self.tubes.get_mut(tube) {
+            tube.using -= 1;
+        }
+    }
+
+    pub fn start_watching(&mut self, tube: &str) {
+        self.tube_mut(tube).watching += 1;
+    }
+
+    pub fn stop_watching(&mut self, tube: &str) {
+        if let Some(tube) = self.tubes.get_mut(tube) {
+            tube.watching -= 1;
+        }
+    }
+
+    pub fn start_waiting(&mut self, watching: &[String]) {
+        self.current_waiting += 1;
+
+        for tube in watching {
+            self.tube_mut(tube).waiting += 1;
+        }
+    }
+
+    pub fn stop_waiting(&mut self, watching: &[String]) {
+        self.current_waiting -= 1;
+
+        for tube in watching {
+            if let Some(tube) = self.tubes.get_mut(tube) {
+                tube.waiting -= 1;
+            }
+        }
+    }
+
+    // Puts a job, writing its body to the store right away if it's spilled.
+    pub fn put<D: Into<Arc<[u8]>>>(&mut self, tube: &str, pri: u32, delay: u32, ttr: u32, data: D) -> io::Result<u64> {
+        let mut body = self.prepare_body(data.into())?;
+
+        if let Err(err) = body.write() {
+            self.discard_body(body);
+            return Err(err);
+        }
+
+        Ok(self.put_body(tube, pri, delay, ttr, body))
+    }
+
+    // Puts a job with a body that has been prepared and written.
+    pub(crate) fn put_body(&mut self, tube: &str, pri: u32, delay: u32, ttr: u32, body: NewBody) -> u64 {
+        assert!(body.unwritten.is_none(), "body of a put job was never written");
+
+        self.auto_increment_index += 1;
+        let id = self.auto_increment_index;
+
+        debug!("Putting job ID {} into tube {}", id, tube);
+
+        let now = self.clock.now();
+
+        let data = body.body;
+        self.memory_used += job_memory(tube, data.memory());
+
+        {
+            let tube = self.tube_mut(tube);
+            tube.total_jobs += 1;
+            tube.bytes += data.len();
+        }
+        self.jobs.insert(id, Job {
+            tube: tube.to_string(),
+            pri,
+            delay,
+            ttr: ttr.max(MIN_TTR),
+            created_at: now,
+            data,
+            state: JobState::Ready,
+            deadline: None,
+            reserved_by: None,
+            buried_at: 0,
+            reserves: 0,
+            timeouts: 0,
+            releases: 0,
+            buries: 0,
+            kicks: 0,
+        });
+        self.schedule(id, delay);
+
+        id
+    }
+
+    // Reserves the most urgent ready job out of the given tubes for the
+    // client. Its body is read by the caller, who buries the job with
+    // `bury_unreadable` if that fails.
+    pub fn reserve(&mut self, client: u64, watching: &[String]) -> Option<(u64, JobBody)> {
+        let (id, data) = loop {
+            let (_, id) = watching.iter()
+                .filter_map(|name| self.tubes.get(name))
+                .filter(|tube| tube.paused_until.is_none())
+                .filter_map(|tube| tube.ready.iter().next().cloned())
+                .min()?;
+
+            // A job whose body is lost would otherwise be handed out again
+            // and again
+            match self.job_body(&self.jobs[&id].data) {
+                Ok(data) => break (id, data),
+                Err(err) => {
+                    error!("Burying job {}, finding its body failed: {}", id, err);
+                    self.unschedule(id);
+                    self.put_aside(id);
+                },
+            }
+        };
+
+        let deadline = {
+            let job = &self.jobs[&id];
+            self.clock.now() + Duration::from_secs(job.ttr as u64)
+        };
+
+        self.unschedule(id);
+
+        let job = self.jobs.get_mut(&id).unwrap();
+        job.state = JobState::Reserved;
+        job.deadline = Some(deadline);
+        job.reserved_by = Some(client);
+        job.reserves += 1;
+        self.reserved_count += 1;
+        self.reservations.entry(client).or_default().insert(id);
+        self.tubes.get_mut(&job.tube).unwrap().reserved += 1;
+        self.timer.schedule(deadline, Deadline::TtrExpired(id));
+
+        Some((id, data))
+    }
+
+    pub fn delete(&mut self, id: &u64) -> Option<Job> {
         debug!("Deleting job {}", id);
 
-        match self.ready_jobs.remove(id) {
-            Some(job) => Some(job),
-            None => self.reserved_jobs.remove(id),
+        if !self.jobs.contains_key(id) {
+            return None;
         }
+
+        self.unschedule(*id);
+
+        let job = self.jobs.remove(id)?;
+        {
+            let tube = self.tubes.get_mut(&job.tube).unwrap();
+            tube.cmd_delete += 1;
+            tube.bytes -= job.data.len();
+        }
+        self.memory_used -= job_memory(&job.tube, job.data.memory());
+        if let Body::Disk(ref location) = job.data {
+            self.spill.as_mut().unwrap().store.free(location);
+        }
+
+        Some(job)
     }
 
-    pub fn release(&mut self, id: &u8) -> Option<Job> {
+    pub fn release(&mut self, id: &u64, pri: u32, delay: u32) -> bool {
         debug!("Releasing job {}", id);
 
-        let key = self.reserved_jobs.iter()
-            .find(|&(job_id, &_)| job_id == id)
-            .map(|(key, _)| key.clone());
+        match self.jobs.get_mut(id) {
+            Some(job) if job.state == JobState::Reserved => {
+                job.pri = pri;
+                job.delay = delay;
+                job.releases += 1;
+            },
+            _ => return false,
+        }
 
-        match key {
-            Some(id) => {
-                let job = self.reserved_jobs.remove(&id).unwrap();
-                self.ready_jobs.insert(id, Job::new(job.id, job.data.clone()));
-                Some(job)
+        self.unschedule(*id);
+        self.schedule(*id, delay);
+
+        true
+    }
+
+    // Puts a reserved job aside until it gets kicked.
+    pub fn bury(&mut self, id: &u64, pri: u32) -> bool {
+        debug!("Burying job {}", id);
+
+        match self.jobs.get_mut(id) {
+            Some(job) if job.state == JobState::Reserved => {
+                job.pri = pri;
+                job.buries += 1;
             },
-            None => None,
-        }
-    }
-
-    pub fn peek_ready(&self) -> Option<(u8, Vec<u8>)> {
-        match self.ready_jobs.iter().next() {
-            Some((id, job)) => Some((id.clone(), job.data.clone())),
-            None => None,
-        }
-    }
-
-    pub fn stats_job(&self, id: &u8) -> Option<StatsJobResponse> {
-        match self.ready_jobs.get(id) {
-            Some(_) => {
-                Some(StatsJobResponse {
-                    id: *id,
-                    tube: "default".to_string(),
-                    state: "ready".to_string(),
-                    pri: 0,
-                    age: 0,
-                    delay: 0,
-                    ttr: 0,
-                    time: 0,
-                    file: 0,
-                    reserves: 0,
-                    timeouts: 0,
-                    releases: 0,
-                    buries: 0,
-                    kicks: 0,
-                })
+            _ => return false,
+        }
+
+        self.unschedule(*id);
+        self.put_aside(*id);
+
+        true
+    }
+
+    // Buries a reserved job whose body turned out to be unreadable, which
+    // would otherwise be handed out again and again.
+    pub(crate) fn bury_unreadable(&mut self, id: &u64) {
+        if self.jobs.get(id).is_some_and(|job| job.state == JobState::Reserved) {
+            self.unschedule(*id);
+            self.put_aside(*id);
+        }
+    }
+
+    // Buries a job that has been unscheduled.
+    fn put_aside(&mut self, id: u64) {
+        self.bury_sequence += 1;
+
+        let job = self.jobs.get_mut(&id).unwrap();
+        job.state = JobState::Buried;
+        job.deadline = None;
+        job.buried_at = self.bury_sequence;
+        self.tubes.get_mut(&job.tube).unwrap().buried.insert((job.buried_at, id));
+    }
+
+    // Moves up to `bound` jobs of the tube to the ready queue. Buried jobs are
+    // kicked first, delayed ones only if there are no buried jobs. Returns the
+    // number of jobs kicked.
+    pub fn kick(&mut self, tube: &str, bound: u32) -> u32 {
+        let ids: Vec<u64> = match self.tubes.get(tube) {
+            Some(tube) if !tube.buried.is_empty() => {
+                tube.buried.iter().take(bound as usize).map(|&(_, id)| id).collect()
             },
-            None => {
-                match self.reserved_jobs.get(id) {
-                    Some(_) => {
-                        Some(StatsJobResponse {
-                            id: *id,
-                            tube: "default".to_string(),
-                            state: "reserved".to_string(),
-                            pri: 0,
-                            age: 0,
-                            delay: 0,
-                            ttr: 0,
-                            time: 0,
-                            file: 0,
-                            reserves: 0,
-                            timeouts: 0,
-                            releases: 0,
-                            buries: 0,
-                            kicks: 0,
-                        })
-                    },
-                    None => None,
-                }
+            Some(tube) => {
+                tube.delayed.iter().take(bound as usize).map(|&(_, id)| id).collect()
             },
+            None => return 0,
+        };
+
+        for id in &ids {
+            self.kick_job(id);
+        }
+
+        ids.len() as u32
+    }
+
+    // Makes a buried or delayed job ready right away.
+    pub fn kick_job(&mut self, id: &u64) -> bool {
+        match self.jobs.get_mut(id) {
+            Some(job) if job.state == JobState::Buried || job.state == JobState::Delayed => {
+                job.kicks += 1;
+            },
+            _ => return false,
+        }
+
+        debug!("Kicking job {}", id);
+
+        self.unschedule(*id);
+        self.schedule(*id, 0);
+
+        true
+    }
+
+    // Gives the client another TTR worth of time to work on a reserved job.
+    pub fn touch(&mut self, id: &u64) -> bool {
+        let now = self.clock.now();
+
+        match self.jobs.get_mut(id) {
+            Some(job) if job.state == JobState::Reserved => {
+                let deadline = now + Duration::from_secs(job.ttr as u64);
+                job.deadline = Some(deadline);
+                self.timer.schedule(deadline, Deadline::TtrExpired(*id));
+                true
+            },
+            _ => false,
+        }
+    }
+
+    // Client holding the reservation of the job and how many times the job
+    // has been reserved, which tells reservations of the same client apart.
+    pub fn reservation(&self, id: &u64) -> Option<(u64, u32)> {
+        let job = self.jobs.get(id)?;
+        job.reserved_by.map(|client| (client, job.reserves))
+    }
+
+    pub fn peek(&self, id: &u64) -> Option<(u64, JobBody)> {
+        self.peek_job(*id)
+    }
+
+    // Name of the tube the job is in
+    pub fn job_tube(&self, id: &u64) -> Option<&str> {
+        self.jobs.get(id).map(|job| job.tube.as_str())
+    }
+
+    pub fn peek_ready(&self, tube: &str) -> Option<(u64, JobBody)> {
+        self.tubes.get(tube)
+            .and_then(|tube| tube.ready.iter().next())
+            .and_then(|&(_, id)| self.peek_job(id))
+    }
+
+    pub fn peek_delayed(&self, tube: &str) -> Option<(u64, JobBody)> {
+        self.tubes.get(tube)
+            .and_then(|tube| tube.delayed.iter().next())
+            .and_then(|&(_, id)| self.peek_job(id))
+    }
+
+    pub fn peek_buried(&self, tube: &str) -> Option<(u64, JobBody)> {
+        self.tubes.get(tube)
+            .and_then(|tube| tube.buried.iter().next())
+            .and_then(|&(_, id)| self.peek_job(id))
+    }
+
+    // Stops handing out jobs from the tube for `delay` seconds.
+    pub fn pause_tube(&mut self, tube: &str, delay: u32) -> bool {
+        let until = self.clock.now() + Duration::from_secs(delay as u64);
+
+        match self.tubes.get_mut(tube) {
+            Some(paused) => {
+                paused.paused_until = Some(until);
+                paused.pause = delay;
+                paused.cmd_pause_tube += 1;
+            },
+            None => return false,
+        }
+
+        self.timer.schedule(until, Deadline::TubeUnpaused(tube.to_string()));
+
+        true
+    }
+
+    // Makes the timer wake up blocked commands once `timeout` seconds have
+    // passed. Returns that point in time, when the caller gives up.
+    pub fn schedule_wakeup(&mut self, timeout: u32) -> Instant {
+        let deadline = self.clock.now() + Duration::from_secs(timeout as u64);
+        self.timer.schedule(deadline, Deadline::Wakeup);
+        deadline
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.timer.next_deadline()
+    }
+
+    // Applies every deadline that has passed. Returns true if anything fired,
+    // in which case blocked reserves should check the queue again.
+    pub fn tick(&mut self) -> bool {
+        let now = self.clock.now();
+        let expired = self.timer.expired(now);
+
+        for deadline in &expired {
+            match *deadline {
+                Deadline::DelayEnded(id) => {
+                    if self.is_due(id, JobState::Delayed, now) {
+                        self.unschedule(id);
+                        self.schedule(id, 0);
+                    }
+                },
+                Deadline::TtrExpired(id) => {
+                    if self.is_due(id, JobState::Reserved, now) {
+                        debug!("Job {} ran out of time to run", id);
+                        self.jobs.get_mut(&id).unwrap().timeouts += 1;
+                        self.job_timeouts += 1;
+                        self.unschedule(id);
+                        self.schedule(id, 0);
+                    }
+                },
+                Deadline::Wakeup => {},
+                Deadline::TubeUnpaused(ref name) => {
+                    if let Some(tube) = self.tubes.get_mut(name) {
+                        if tube.paused_until.is_some_and(|until| until <= now) {
+                            tube.paused_until = None;
+                            tube.pause = 0;
+                        }
+                    }
+                },
+            }
+        }
+
+        !expired.is_empty()
+    }
+
+    // Names of all tubes, in alphabetical order.
+    pub fn tube_names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.tubes.keys().map(|name| name.as_str()).collect();
+        names.sort();
+        names
+    }
+
+    pub fn stats(&self) -> StatsResponse {
+        let mut stats = StatsResponse {
+            current_jobs_urgent: 0,
+            current_jobs_ready: 0,
+            current_jobs_reserved: self.reserved_count,
+            current_jobs_delayed: 0,
+            current_jobs_buried: 0,
+            job_timeouts: self.job_timeouts,
+            total_jobs: self.auto_increment_index,
+            max_job_size: self.max_job_size,
+            current_tubes: self.tubes.len(),
+            current_connections: self.clients.len(),
+            current_waiting: self.current_waiting,
+            total_connections: self.total_connections,
+            pid: process::id(),
+            uptime: self.clock.now().saturating_duration_since(self.started_at).as_secs(),
+            draining: self.draining,
+            memory_used: self.memory_used,
+            max_memory: self.max_memory,
+            spilled_bytes: self.spill.as_ref().map(|spill| spill.store.used()),
+            listeners: self.listeners.iter()
+                .map(|(name, stats)| (name.clone(), stats.current_connections, stats.total_connections))
+                .collect(),
+        };
+
+        for tube in self.tubes.values() {
+            stats.current_jobs_urgent += tube.urgent;
+            stats.current_jobs_ready += tube.ready.len();
+            stats.current_jobs_delayed += tube.delayed.len();
+            stats.current_jobs_buried += tube.buried.len();
         }
+
+        stats
     }
 
-    pub fn stats_tube(&self) -> Option<StatsTubeResponse> {
+    pub fn stats_job(&self, id: &u64) -> Option<StatsJobResponse> {
+        let job = self.jobs.get(id)?;
+        let now = self.clock.now();
+
+        let state = match job.state {
+            JobState::Ready => "ready",
+            JobState::Reserved => "reserved",
+            JobState::Delayed => "delayed",
+            JobState::Buried => "buried",
+        };
+
+        Some(StatsJobResponse {
+            id: *id,
+            tube: job.tube.clone(),
+            state: state.to_string(),
+            pri: job.pri,
+            age: now.saturating_duration_since(job.created_at).as_secs(),
+            delay: job.delay,
+            ttr: job.ttr,
+            time_left: job.deadline.map_or(0, |at| at.saturating_duration_since(now).as_secs()),
+            // Segment holding the body
+            file: match job.data {
+                Body::Disk(ref location) => location.segment,
+                Body::Memory(_) => 0,
+            },
+            reserves: job.reserves,
+            timeouts: job.timeouts,
+            releases: job.releases,
+            buries: job.buries,
+            kicks: job.kicks,
+        })
+    }
+
+    pub fn stats_tube(&self, name: &str) -> Option<StatsTubeResponse> {
+        let tube = self.tubes.get(name)?;
+        let now = self.clock.now();
+        let limit = self.tube_limit(name).unwrap_or_default();
+
         Some(StatsTubeResponse {
-            current_jobs_ready: self.ready_jobs.len(),
-            current_jobs_reserved: self.reserved_jobs.len(),
-            total_jobs: self.ready_jobs.len() + self.reserved_jobs.len(),
+            name: name.to_string(),
+            current_jobs_urgent: tube.urgent,
+            current_jobs_ready: tube.ready.len(),
+            current_jobs_reserved: tube.reserved,
+            current_jobs_delayed: tube.delayed.len(),
+            current_jobs_buried: tube.buried.len(),
+            total_jobs: tube.total_jobs,
+            current_using: tube.using,
+            current_waiting: tube.waiting,
+            current_watching: tube.watching,
+            pause: tube.pause,
+            cmd_delete: tube.cmd_delete,
+            cmd_pause_tube: tube.cmd_pause_tube,
+            pause_time_left: tube.paused_until
+                .map_or(0, |until| until.saturating_duration_since(now).as_secs()),
+            current_bytes: tube.bytes,
+            max_jobs: limit.max_jobs,
+            max_bytes: limit.max_bytes,
         })
     }
+
+    // Looks up the tube, creating it if needed.
+    fn tube_mut(&mut self, tube: &str) -> &mut Tube {
+        if !self.tubes.contains_key(tube) {
+            self.tubes.insert(tube.to_string(), Tube::new());
+        }
+
+        self.tubes.get_mut(tube).unwrap()
+    }
+
+    fn is_due(&self, id: u64, state: JobState, now: Instant) -> bool {
+        self.jobs.get(&id)
+            .is_some_and(|job| job.state == state && job.deadline.is_some_and(|at| at <= now))
+    }
+
+    // Puts the job into its tube's ready queue, or holds it back for `delay`
+    // seconds.
+    fn schedule(&mut self, id: u64, delay: u32) {
+        let now = self.clock.now();
+        let job = self.jobs.get_mut(&id).unwrap();
+        let tube = self.tubes.get_mut(&job.tube).unwrap();
+
+        if delay == 0 {
+            job.state = JobState::Ready;
+            job.deadline = None;
+            tube.ready.insert((job.pri, id));
+            if job.pri < URGENT_PRI {
+                tube.urgent += 1;
+            }
+            return;
+        }
+
+        let deadline = now + Duration::from_secs(delay as u64);
+        job.state = JobState::Delayed;
+        job.deadline = Some(deadline);
+        tube.delayed.insert((deadline, id));
+        self.timer.schedule(deadline, Deadline::DelayEnded(id));
+    }
+
+    // Takes the job out of whatever index its current state keeps it in.
+    fn unschedule(&mut self, id: u64) {
+        let job = self.jobs.get_mut(&id).unwrap();
+        let tube = self.tubes.get_mut(&job.tube).unwrap();
+
+        match job.state {
+            JobState::Ready => {
+                tube.ready.remove(&(job.pri, id));
+                if job.pri < URGENT_PRI {
+                    tube.urgent -= 1;
+                }
+            },
+            JobState::Reserved => {
+                self.reserved_count -= 1;
+                tube.reserved -= 1;
+
+                let client = job.reserved_by.take().unwrap();
+                if let Some(reserved) = self.reservations.get_mut(&client) {
+                    reserved.remove(&id);
+                }
+            },
+            JobState::Delayed => {
+                tube.delayed.remove(&(job.deadline.unwrap(), id));
+            },
+            JobState::Buried => {
+                tube.buried.remove(&(job.buried_at, id));
+            },
+        }
+    }
+}
+
+impl Default for JobQueue {
+    fn default() -> JobQueue {
+        JobQueue::new()
+    }
+}
+
+pub struct StatsResponse {
+    pub current_jobs_urgent: usize,
+    pub current_jobs_ready: usize,
+    pub current_jobs_reserved: usize,
+    pub current_jobs_delayed: usize,
+    pub current_jobs_buried: usize,
+    pub job_timeouts: u64,
+    pub total_jobs: u64,
+    pub max_job_size: usize,
+    pub current_tubes: usize,
+    pub current_connections: usize,
+    pub current_waiting: usize,
+    pub total_connections: u64,
+    pub pid: u32,
+    pub uptime: u64,
+    pub draining: bool,
+    pub memory_used: usize,
+    pub max_memory: Option<usize>,
+    pub spilled_bytes: Option<u64>,
+    // Current and total connections by listener
+    pub listeners: Vec<(String, usize, u64)>,
+}
+
+impl StatsResponse {
+    pub(crate) fn write<D: Document>(&self, document: D) -> D {
+        let mut document = document
+            .number("current-jobs-urgent", self.current_jobs_urgent)
+            .number("current-jobs-ready", self.current_jobs_ready)
+            .number("current-jobs-reserved", self.current_jobs_reserved)
+            .number("current-jobs-delayed", self.current_jobs_delayed)
+            .number("current-jobs-buried", self.current_jobs_buried)
+            .number("job-timeouts", self.job_timeouts)
+            .number("total-jobs", self.total_jobs)
+            .number("max-job-size", self.max_job_size)
+            .number("current-tubes", self.current_tubes)
+            .number("current-connections", self.current_connections)
+            .number("current-waiting", self.current_waiting)
+            .number("total-connections", self.total_connections)
+            .number("pid", self.pid)
+            .string("version", env!("CARGO_PKG_VERSION"))
+            .number("uptime", self.uptime)
+            .number("draining", self.draining)
+            .number("memory-used", self.memory_used);
+
+        if let Some(max_memory) = self.max_memory {
+            document = document.number("max-memory", max_memory);
+        }
+        if let Some(spilled_bytes) = self.spilled_bytes {
+            document = document.number("spilled-bytes", spilled_bytes);
+        }
+
+        for &(ref listener, current, total) in &self.listeners {
+            document = document
+                .number(&format!("listener-{}-current-connections", listener), current)
+                .number(&format!("listener-{}-total-connections", listener), total);
+        }
+
+        document
+    }
+}
+
+impl fmt::Display for StatsResponse {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.write(Dict::new()).into_string())
+    }
 }
 
 pub struct StatsJobResponse {
-    id: u8,
-    tube: String,
-    state: String,
-    pri: u8,
-    age: u8,
-    delay: u8,
-    ttr: u8,
-    time: u8,
-    file: u8,
-    reserves: u8,
-    timeouts: u8,
-    releases: u8,
-    buries: u8,
-    kicks: u8,
+    pub id: u64,
+    pub tube: String,
+    pub state: String,
+    pub pri: u32,
+    pub age: u64,
+    pub delay: u32,
+    pub ttr: u32,
+    pub time_left: u64,
+    pub file: u32,
+    pub reserves: u32,
+    pub timeouts: u32,
+    pub releases: u32,
+    pub buries: u32,
+    pub kicks: u32,
 }
 
 impl StatsJobResponse {
-    pub fn to_string(&self) -> String {
-        let yaml = format!(
-            "---\n\
-id: {}\n\
-tube: {}\n\
-state: {}\n\
-pri: {}\n\
-age: {}\n\
-delay: {}\n\
-ttr: {}\n\
-time: {}\n\
-file: {}\n\
-reserves: {}\n\
-timeouts: {}\n\
-releases: {}\n\
-buries: {}\n\
-kicks: {}\n",
-            self.id,
-            self.tube,
-            self.state,
-            self.pri,
-            self.age,
-            self.delay,
-            self.ttr,
-            self.time,
-            self.file,
-            self.reserves,
-            self.timeouts,
-            self.releases,
-            self.buries,
-            self.kicks,
-        );
-
-        format!("OK {}\r\n{}\r\n", yaml.len(), yaml)
+    pub(crate) fn write<D: Document>(&self, document: D) -> D {
+        document
+            .number("id", self.id)
+            .string("tube", &self.tube)
+            .string("state", &self.state)
+            .number("pri", self.pri)
+            .number("age", self.age)
+            .number("delay", self.delay)
+            .number("ttr", self.ttr)
+            .number("time-left", self.time_left)
+            .number("file", self.file)
+            .number("reserves", self.reserves)
+            .number("timeouts", self.timeouts)
+            .number("releases", self.releases)
+            .number("buries", self.buries)
+            .number("kicks", self.kicks)
+    }
+}
+
+impl fmt::Display for StatsJobResponse {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.write(Dict::new()).into_string())
     }
 }
 
 pub struct StatsTubeResponse {
-    current_jobs_ready: usize,
-    current_jobs_reserved: usize,
-    total_jobs: usize,
+    pub name: String,
+    pub current_jobs_urgent: usize,
+    pub current_jobs_ready: usize,
+    pub current_jobs_reserved: usize,
+    pub current_jobs_delayed: usize,
+    pub current_jobs_buried: usize,
+    pub total_jobs: u64,
+    pub current_using: usize,
+    pub current_waiting: usize,
+    pub current_watching: usize,
+    pub pause: u32,
+    pub cmd_delete: u64,
+    pub cmd_pause_tube: u64,
+    pub pause_time_left: u64,
+    pub current_bytes: usize,
+    pub max_jobs: Option<usize>,
+    pub max_bytes: Option<usize>,
 }
 
 impl StatsTubeResponse {
-    pub fn to_string(&self) -> String {
-        let stats = format!(
-            "---
-name: default
-current-jobs-urgent: 0
-current-jobs-ready: {}
-current-jobs-reserved: {}
-current-jobs-delayed: 0
-current-jobs-buried: 0
-total-jobs: {}
-current-using: 0
-current-waiting: 0
-current-watching: 0
-pause: 0
-cmd-delete: 0
-cmd-pause-tube: 0
-pause-time-left: 0
-",
-        self.current_jobs_ready,
-        self.current_jobs_reserved,
-        self.total_jobs
-        );
-        format!(
-            "OK {}\r\n{}\r\n",
-            stats.len(),
-            stats
-        )
+    pub(crate) fn write<D: Document>(&self, document: D) -> D {
+        let mut document = document
+            .string("name", &self.name)
+            .number("current-jobs-urgent", self.current_jobs_urgent)
+            .number("current-jobs-ready", self.current_jobs_ready)
+            .number("current-jobs-reserved", self.current_jobs_reserved)
+            .number("current-jobs-delayed", self.current_jobs_delayed)
+            .number("current-jobs-buried", self.current_jobs_buried)
+            .number("total-jobs", self.total_jobs)
+            .number("current-using", self.current_using)
+            .number("current-waiting", self.current_waiting)
+            .number("current-watching", self.current_watching)
+            .number("pause", self.pause)
+            .number("cmd-delete", self.cmd_delete)
+            .number("cmd-pause-tube", self.cmd_pause_tube)
+            .number("pause-time-left", self.pause_time_left);
+
+        // Only limited tubes report what counts towards their limit
+        if let Some(max_jobs) = self.max_jobs {
+            document = document.number("max-jobs", max_jobs);
+        }
+        if let Some(max_bytes) = self.max_bytes {
+            document = document
+                .number("current-bytes", self.current_bytes)
+                .number("max-bytes", max_bytes);
+        }
+
+        document
+    }
+}
+
+impl fmt::Display for StatsTubeResponse {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.write(Dict::new()).into_string())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::env;
+    use std::fs;
+    use timer::ManualClock;
+
+    fn default_tube() -> Vec<String> {
+        vec![DEFAULT_TUBE.to_string()]
+    }
+
+    fn found(id: u64, data: &[u8]) -> Option<(u64, Arc<[u8]>)> {
+        Some((id, Arc::from(data)))
+    }
+
+    fn read(job: Option<(u64, JobBody)>) -> Option<(u64, Arc<[u8]>)> {
+        job.map(|(id, body)| (id, body.read().unwrap()))
+    }
 
     #[test]
     fn stats_job_checks_ready_and_reserved_jobs() {
         let mut sut = JobQueue::new();
 
-        let id1 = sut.put(1, 1, 1, "job1".to_string().into_bytes());
-        let id2 = sut.put(1, 1, 1, "job2".to_string().into_bytes());
+        let id1 = sut.put("default", 1, 0, 1, "job1".to_string().into_bytes()).unwrap();
+        let id2 = sut.put("default", 1, 0, 1, "job2".to_string().into_bytes()).unwrap();
 
-        let (reserved_job_id, _) = sut.reserve();
+        let (reserved_job_id, _) = sut.reserve(1, &default_tube()).unwrap();
 
         assert!(sut.stats_job(&id1).is_some());
         assert!(sut.stats_job(&id2).is_some());
@@ -258,10 +1175,10 @@ mod tests {
     fn delete_checks_ready_and_reserved_jobs() {
         let mut sut = JobQueue::new();
 
-        let id1 = sut.put(1, 1, 1, "job1".to_string().into_bytes());
-        let id2 = sut.put(1, 1, 1, "job2".to_string().into_bytes());
+        let id1 = sut.put("default", 1, 0, 1, "job1".to_string().into_bytes()).unwrap();
+        let id2 = sut.put("default", 1, 0, 1, "job2".to_string().into_bytes()).unwrap();
 
-        let (reserved_job_id, _) = sut.reserve();
+        let (reserved_job_id, _) = sut.reserve(1, &default_tube()).unwrap();
 
         if id1 != reserved_job_id {
             assert!(sut.delete(&id1).is_some());
@@ -273,4 +1190,388 @@ mod tests {
 
         assert!(sut.delete(&reserved_job_id).is_some());
     }
+
+    #[test]
+    fn reserve_returns_most_urgent_job_first() {
+        let mut sut = JobQueue::new();
+
+        sut.put("default", 10, 0, 1, b"later".to_vec()).unwrap();
+        let urgent = sut.put("default", 1, 0, 1, b"urgent".to_vec()).unwrap();
+
+        assert_eq!(read(sut.reserve(1, &default_tube())), found(urgent, b"urgent"));
+    }
+
+    #[test]
+    fn delayed_job_becomes_ready_when_delay_ends() {
+        let clock = ManualClock::new();
+        let mut sut = JobQueue::with_clock(Box::new(clock.clone()));
+
+        let id = sut.put("default", 1, 10, 1, b"job".to_vec()).unwrap();
+
+        assert!(sut.reserve(1, &default_tube()).is_none());
+        assert_eq!(read(sut.peek_delayed("default")), found(id, b"job"));
+
+        clock.advance(Duration::from_secs(9));
+        assert!(!sut.tick());
+        assert!(sut.reserve(1, &default_tube()).is_none());
+
+        clock.advance(Duration::from_secs(1));
+        assert!(sut.tick());
+        assert_eq!(read(sut.reserve(1, &default_tube())), found(id, b"job"));
+    }
+
+    #[test]
+    fn reserved_job_is_released_when_ttr_expires() {
+        let clock = ManualClock::new();
+        let mut sut = JobQueue::with_clock(Box::new(clock.clone()));
+
+        let id = sut.put("default", 1, 0, 5, b"job".to_vec()).unwrap();
+        sut.reserve(1, &default_tube()).unwrap();
+
+        clock.advance(Duration::from_secs(4));
+        assert!(sut.touch(&id));
+
+        clock.advance(Duration::from_secs(4));
+        sut.tick();
+        assert!(sut.peek_ready("default").is_none());
+
+        clock.advance(Duration::from_secs(1));
+        sut.tick();
+        assert_eq!(read(sut.peek_ready("default")), found(id, b"job"));
+    }
+
+    #[test]
+    fn release_with_delay_holds_job_back() {
+        let clock = ManualClock::new();
+        let mut sut = JobQueue::with_clock(Box::new(clock.clone()));
+
+        let id = sut.put("default", 1, 0, 60, b"job".to_vec()).unwrap();
+        sut.reserve(1, &default_tube()).unwrap();
+
+        assert!(sut.release(&id, 1, 3));
+        assert!(sut.peek_ready("default").is_none());
+
+        clock.advance(Duration::from_secs(3));
+        sut.tick();
+        assert_eq!(read(sut.peek_ready("default")), found(id, b"job"));
+    }
+
+    #[test]
+    fn paused_tube_hands_out_no_jobs_until_pause_ends() {
+        let clock = ManualClock::new();
+        let mut sut = JobQueue::with_clock(Box::new(clock.clone()));
+
+        sut.put("default", 1, 0, 60, b"job".to_vec()).unwrap();
+
+        assert!(!sut.pause_tube("unknown", 10));
+        assert!(sut.pause_tube("default", 10));
+        assert!(sut.reserve(1, &default_tube()).is_none());
+
+        clock.advance(Duration::from_secs(10));
+        sut.tick();
+        assert!(sut.reserve(1, &default_tube()).is_some());
+    }
+
+    #[test]
+    fn reserve_picks_most_urgent_job_across_watched_tubes() {
+        let mut sut = JobQueue::new();
+
+        sut.put("default", 5, 0, 60, b"default".to_vec()).unwrap();
+        sut.put("other", 1, 0, 60, b"other".to_vec()).unwrap();
+        let urgent = sut.put("emails", 2, 0, 60, b"emails".to_vec()).unwrap();
+
+        let watching = vec!["default".to_string(), "emails".to_string()];
+
+        assert_eq!(read(sut.reserve(1, &watching)), found(urgent, b"emails"));
+        assert_eq!(sut.peek_ready("other").map(|(_, body)| body.read().unwrap()), Some(Arc::from(&b"other"[..])));
+    }
+
+    #[test]
+    fn stats_job_reports_live_values() {
+        let clock = ManualClock::new();
+        let mut sut = JobQueue::with_clock(Box::new(clock.clone()));
+
+        let id = sut.put("emails", 2000, 0, 30, b"job".to_vec()).unwrap();
+        let watching = vec!["emails".to_string()];
+
+        sut.reserve(1, &watching).unwrap();
+        clock.advance(Duration::from_secs(30));
+        sut.tick();
+
+        sut.reserve(1, &watching).unwrap();
+        assert!(sut.release(&id, 3000, 20));
+        clock.advance(Duration::from_secs(5));
+
+        let stats = sut.stats_job(&id).unwrap().to_string();
+        assert!(stats.contains("\ntube: emails\nstate: delayed\npri: 3000\nage: 35\ndelay: 20\nttr: 30\ntime-left: 15\n"));
+        assert!(stats.contains("\nreserves: 2\ntimeouts: 1\nreleases: 1\nburies: 0\nkicks: 0\n"));
+    }
+
+    #[test]
+    fn stats_tube_counts_jobs_by_state() {
+        let clock = ManualClock::new();
+        let mut sut = JobQueue::with_clock(Box::new(clock.clone()));
+        let watching = vec!["emails".to_string()];
+
+        let buried = sut.put("emails", 1, 0, 60, b"buried".to_vec()).unwrap();
+        sut.reserve(1, &watching).unwrap();
+        sut.bury(&buried, 1);
+        sut.put("emails", 1, 0, 60, b"urgent".to_vec()).unwrap();
+        sut.put("emails", 2000, 0, 60, b"ready".to_vec()).unwrap();
+        sut.put("emails", 1, 30, 60, b"delayed".to_vec()).unwrap();
+        let deleted = sut.put("emails", 1, 30, 60, b"deleted".to_vec()).unwrap();
+        sut.delete(&deleted);
+        sut.reserve(1, &watching).unwrap();
+        sut.put("default", 1, 0, 60, b"elsewhere".to_vec()).unwrap();
+
+        sut.start_using("emails");
+        sut.start_watching("emails");
+        sut.start_watching("emails");
+        sut.stop_watching("emails");
+        sut.start_waiting(&watching);
+        sut.pause_tube("emails", 10);
+        clock.advance(Duration::from_secs(4));
+
+        assert_eq!(sut.stats_tube("emails").unwrap().to_string(), "---
+name: emails
+current-jobs-urgent: 0
+current-jobs-ready: 1
+current-jobs-reserved: 1
+current-jobs-delayed: 1
+current-jobs-buried: 1
+total-jobs: 5
+current-using: 1
+current-waiting: 1
+current-watching: 1
+pause: 10
+cmd-delete: 1
+cmd-pause-tube: 1
+pause-time-left: 6
+");
+        assert!(sut.stats_tube("unknown").is_none());
+    }
+
+    #[test]
+    fn stats_sums_up_all_tubes() {
+        let clock = ManualClock::new();
+        let mut sut = JobQueue::with_clock(Box::new(clock.clone()));
+
+        sut.put("default", 1, 0, 60, b"urgent".to_vec()).unwrap();
+        sut.put("emails", 2000, 0, 60, b"ready".to_vec()).unwrap();
+        sut.put("emails", 1, 10, 60, b"delayed".to_vec()).unwrap();
+        sut.client_connected("127.0.0.1:11300");
+        let client = sut.client_connected("[::1]:11300");
+        sut.client_disconnected(client);
+        sut.set_draining(true);
+        clock.advance(Duration::from_secs(3));
+
+        let stats = sut.stats().to_string();
+        assert!(stats.starts_with("---\ncurrent-jobs-urgent: 1\ncurrent-jobs-ready: 2\n\
+current-jobs-reserved: 0\ncurrent-jobs-delayed: 1\ncurrent-jobs-buried: 0\njob-timeouts: 0\n\
+total-jobs: 3\nmax-job-size: 65535\ncurrent-tubes: 2\ncurrent-connections: 1\ncurrent-waiting: 0\ntotal-connections: 2\n"));
+        assert!(stats.ends_with(&format!("\nuptime: 3\ndraining: true\nmemory-used: {}\n\
+\"listener-127.0.0.1:11300-current-connections\": 1\n\"listener-127.0.0.1:11300-total-connections\": 1\n\
+\"listener-[::1]:11300-current-connections\": 0\n\"listener-[::1]:11300-total-connections\": 1\n", 3 * mem::size_of::<Job>() + 37)));
+    }
+
+    #[test]
+    fn tracks_memory_taken_up_by_jobs() {
+        let mut sut = JobQueue::new();
+        let job = mem::size_of::<Job>();
+        sut.set_max_memory(Some(2 * job + 20));
+
+        let first = sut.put("default", 1, 0, 60, b"hello".to_vec()).unwrap();
+        assert_eq!(sut.stats().memory_used, job + 12);
+
+        assert!(sut.has_memory_for("emails", 2));
+        assert!(!sut.has_memory_for("emails", 3));
+        sut.put("emails", 1, 0, 60, b"hi".to_vec()).unwrap();
+        assert!(!sut.has_memory_for("default", 0));
+
+        sut.delete(&first);
+        assert_eq!(sut.stats().memory_used, job + 8);
+        assert!(sut.has_memory_for("default", 5));
+        assert!(sut.stats().to_string().contains(&format!("\nmax-memory: {}\n", 2 * job + 20)));
+    }
+
+    #[test]
+    fn limits_tubes_matching_a_pattern() {
+        let mut sut = JobQueue::new();
+        sut.limit_tubes("emails", TubeLimit {max_jobs: Some(2), max_bytes: None});
+        sut.limit_tubes("e*", TubeLimit {max_jobs: None, max_bytes: Some(10)});
+
+        assert!(sut.has_room_in("events", 10));
+        assert!(!sut.has_room_in("events", 11));
+        sut.put("events", 1, 0, 60, b"hello".to_vec()).unwrap();
+        assert!(!sut.has_room_in("events", 6));
+        assert!(sut.has_room_in("default", 1000));
+
+        // Reserved and buried jobs take up room too
+        let first = sut.put("emails", 1, 0, 60, b"hello, world".to_vec()).unwrap();
+        sut.reserve(1, &["emails".to_string()]).unwrap();
+        sut.put("emails", 1, 0, 60, b"".to_vec()).unwrap();
+        assert!(!sut.has_room_in("emails", 0));
+
+        let stats = sut.stats_tube("emails").unwrap().to_string();
+        assert!(stats.ends_with("\npause-time-left: 0\nmax-jobs: 2\n"), "{}", stats);
+        let stats = sut.stats_tube("events").unwrap().to_string();
+        assert!(stats.ends_with("\npause-time-left: 0\ncurrent-bytes: 5\nmax-bytes: 10\n"), "{}", stats);
+
+        sut.delete(&first);
+        assert!(sut.has_room_in("emails", 0));
+    }
+
+    #[test]
+    fn spills_big_bodies_to_disk() {
+        let dir = env::temp_dir().join(format!("beanstalkdrs-test-{}-spill", process::id()));
+        let mut sut = JobQueue::new();
+        let job = mem::size_of::<Job>();
+        sut.spill_bodies(BodyStore::open(&dir).unwrap(), Some(6), None);
+
+        let small = sut.put("default", 1, 0, 60, b"small".to_vec()).unwrap();
+        let big = sut.put("default", 2, 0, 60, b"bigger".to_vec()).unwrap();
+        assert_eq!(sut.stats().memory_used, 2 * job + 2 * 7 + 5);
+        assert_eq!(sut.stats().spilled_bytes, Some(6));
+        assert_eq!(sut.stats_job(&small).unwrap().file, 0);
+        assert_eq!(sut.stats_job(&big).unwrap().file, 1);
+
+        assert_eq!(read(sut.peek(&big)), found(big, b"bigger"));
+        assert_eq!(read(sut.reserve(1, &default_tube())), found(small, b"small"));
+        assert_eq!(read(sut.reserve(1, &default_tube())), found(big, b"bigger"));
+
+        sut.delete(&big);
+        assert_eq!(sut.stats().spilled_bytes, Some(0));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn writes_spilled_bodies_apart_from_putting_them() {
+        let dir = env::temp_dir().join(format!("beanstalkdrs-test-{}-prepare", process::id()));
+        let mut sut = JobQueue::new();
+        sut.spill_bodies(BodyStore::open(&dir).unwrap(), Some(6), None);
+
+        let mut body = sut.prepare_body(Arc::from(&b"bigger"[..])).unwrap();
+        assert_eq!(body.memory(), 0);
+        body.write().unwrap();
+        let id = sut.put_body("default", 1, 0, 60, body);
+
+        let discarded = sut.prepare_body(Arc::from(&b"refused"[..])).unwrap();
+        assert_eq!(sut.stats().spilled_bytes, Some(13));
+        sut.discard_body(discarded);
+        assert_eq!(sut.stats().spilled_bytes, Some(6));
+
+        // Handed out bodies can still be read once the job is gone
+        let (_, body) = sut.reserve(1, &default_tube()).unwrap();
+        sut.delete(&id);
+        assert_eq!(&body.read().unwrap()[..], b"bigger");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn spills_bodies_beyond_the_watermark() {
+        let dir = env::temp_dir().join(format!("beanstalkdrs-test-{}-watermark", process::id()));
+        let mut sut = JobQueue::new();
+        let job = mem::size_of::<Job>();
+        sut.spill_bodies(BodyStore::open(&dir).unwrap(), None, Some(job + 12));
+
+        let first = sut.put("default", 1, 0, 60, b"first".to_vec()).unwrap();
+        let second = sut.put("default", 1, 0, 60, b"second".to_vec()).unwrap();
+        assert_eq!(sut.stats_job(&first).unwrap().file, 0);
+        assert_eq!(sut.stats_job(&second).unwrap().file, 1);
+        assert_eq!(read(sut.peek_ready("default")), found(first, b"first"));
+        assert_eq!(read(sut.peek(&second)), found(second, b"second"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn disconnecting_releases_reserved_jobs() {
+        let mut sut = JobQueue::new();
+
+        let first = sut.client_connected("test");
+        let second = sut.client_connected("test");
+        let id = sut.put("default", 1, 0, 60, b"job".to_vec()).unwrap();
+        let deleted = sut.put("default", 1, 0, 60, b"deleted".to_vec()).unwrap();
+
+        assert_eq!(read(sut.reserve(first, &default_tube())), found(id, b"job"));
+        assert_eq!(read(sut.reserve(first, &default_tube())), found(deleted, b"deleted"));
+        sut.delete(&deleted);
+
+        assert!(!sut.client_disconnected(second));
+        assert!(sut.reserve(second, &default_tube()).is_none());
+
+        assert!(sut.client_disconnected(first));
+        assert_eq!(read(sut.peek_ready("default")), found(id, b"job"));
+    }
+
+    #[test]
+    fn buried_jobs_wait_for_a_kick() {
+        let mut sut = JobQueue::new();
+
+        let buried = sut.put("default", 1, 0, 60, b"buried".to_vec()).unwrap();
+        let delayed = sut.put("default", 1, 60, 60, b"delayed".to_vec()).unwrap();
+
+        sut.reserve(1, &default_tube()).unwrap();
+        assert!(sut.bury(&buried, 5));
+        assert!(!sut.bury(&buried, 5));
+        assert!(sut.reserve(1, &default_tube()).is_none());
+        assert_eq!(read(sut.peek_buried("default")), found(buried, b"buried"));
+
+        // Buried jobs go first, delayed ones are only kicked once none are left
+        assert_eq!(sut.kick("default", 10), 1);
+        assert_eq!(read(sut.peek_delayed("default")), found(delayed, b"delayed"));
+        assert_eq!(sut.kick("default", 10), 1);
+        assert_eq!(sut.kick("default", 10), 0);
+
+        assert!(sut.stats_job(&buried).unwrap().to_string().contains("\nburies: 1\nkicks: 1\n"));
+    }
+
+    #[test]
+    fn counts_urgent_jobs_while_they_are_ready() {
+        let mut sut = JobQueue::new();
+
+        let id = sut.put("default", 1, 0, 60, b"urgent".to_vec()).unwrap();
+        sut.put("default", URGENT_PRI, 0, 60, b"not urgent".to_vec()).unwrap();
+        assert_eq!(sut.stats_tube("default").unwrap().current_jobs_urgent, 1);
+
+        sut.reserve(1, &default_tube()).unwrap();
+        assert_eq!(sut.stats().current_jobs_urgent, 0);
+        sut.release(&id, URGENT_PRI - 1, 0);
+        assert_eq!(sut.stats().current_jobs_urgent, 1);
+        sut.delete(&id);
+        assert_eq!(sut.stats_tube("default").unwrap().current_jobs_urgent, 0);
+    }
+
+    #[test]
+    fn buried_jobs_are_kicked_in_the_order_they_were_buried() {
+        let mut sut = JobQueue::new();
+
+        let last = sut.put("default", 3, 0, 60, b"last".to_vec()).unwrap();
+        let deleted = sut.put("default", 2, 0, 60, b"deleted".to_vec()).unwrap();
+        let first = sut.put("default", 1, 0, 60, b"first".to_vec()).unwrap();
+        for _ in 0..3 {
+            let (id, _) = sut.reserve(1, &default_tube()).unwrap();
+            sut.bury(&id, 1);
+        }
+
+        assert!(sut.delete(&deleted).is_some());
+        assert_eq!(read(sut.peek_buried("default")), found(first, b"first"));
+        assert_eq!(sut.kick("default", 1), 1);
+        assert_eq!(read(sut.peek_buried("default")), found(last, b"last"));
+        assert_eq!(sut.stats_tube("default").unwrap().current_jobs_buried, 1);
+    }
+
+    #[test]
+    fn reserve_and_peek_share_the_job_body() {
+        let mut sut = JobQueue::new();
+
+        sut.put("default", 1, 0, 60, b"job".to_vec()).unwrap();
+
+        let (_, peeked) = sut.peek_ready("default").unwrap();
+        let (_, reserved) = sut.reserve(1, &default_tube()).unwrap();
+
+        assert!(Arc::ptr_eq(&peeked.read().unwrap(), &reserved.read().unwrap()));
+    }
 }
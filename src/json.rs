@@ -0,0 +1,222 @@
+use std::char;
+use std::fmt::{Display, Write};
+use std::iter::Peekable;
+use std::str::Chars;
+
+use document::Document;
+
+// Builds the JSON objects the HTTP endpoints answer with. Numbers are written
+// as they display, so they have to be integers or booleans.
+pub struct Object {
+    json: String,
+}
+
+impl Object {
+    pub fn new() -> Object {
+        Object { json: "{".to_string() }
+    }
+
+    pub fn into_string(mut self) -> String {
+        self.json.push('}');
+        self.json
+    }
+
+    fn key(&mut self, key: &str) {
+        if self.json.len() > 1 {
+            self.json.push(',');
+        }
+
+        self.json.push_str(&string(key));
+        self.json.push(':');
+    }
+}
+
+impl Document for Object {
+    fn number<V: Display>(mut self, key: &str, value: V) -> Object {
+        self.key(key);
+        let _ = write!(self.json, "{}", value);
+        self
+    }
+
+    fn string(mut self, key: &str, value: &str) -> Object {
+        self.key(key);
+        self.json.push_str(&string(value));
+        self
+    }
+}
+
+// Builds a JSON array of strings.
+pub fn list<I, S>(items: I) -> String
+    where I: IntoIterator<Item = S>, S: AsRef<str>
+{
+    let items: Vec<String> = items.into_iter().map(|item| string(item.as_ref())).collect();
+
+    format!("[{}]", items.join(","))
+}
+
+// Reads an object whose values are all strings, e.g. a message from a client.
+// `None` if it is anything else or not valid JSON.
+pub fn parse_strings(json: &str) -> Option<Vec<(String, String)>> {
+    let mut chars = json.chars().peekable();
+    let mut members = vec![];
+
+    expect(&mut chars, '{')?;
+    if skip_whitespace(&mut chars) == Some('}') {
+        chars.next();
+    } else {
+        loop {
+            expect(&mut chars, '"')?;
+            let key = parse_string(&mut chars)?;
+            expect(&mut chars, ':')?;
+            expect(&mut chars, '"')?;
+            members.push((key, parse_string(&mut chars)?));
+
+            let separator = skip_whitespace(&mut chars);
+            chars.next();
+
+            match separator {
+                Some(',') => continue,
+                Some('}') => break,
+                _ => return None,
+            }
+        }
+    }
+
+    if skip_whitespace(&mut chars).is_some() {
+        return None;
+    }
+
+    Some(members)
+}
+
+fn skip_whitespace(chars: &mut Peekable<Chars>) -> Option<char> {
+    while chars.peek().is_some_and(|c| c.is_ascii_whitespace()) {
+        chars.next();
+    }
+
+    chars.peek().cloned()
+}
+
+fn expect(chars: &mut Peekable<Chars>, expected: char) -> Option<()> {
+    skip_whitespace(chars);
+
+    if chars.next()? == expected {
+        Some(())
+    } else {
+        None
+    }
+}
+
+// Reads the rest of a string whose opening quote has been read.
+fn parse_string(chars: &mut Peekable<Chars>) -> Option<String> {
+    let mut string = String::new();
+
+    loop {
+        match chars.next()? {
+            '"' => return Some(string),
+            '\\' => {
+                let c = match chars.next()? {
+                    'n' => '\n',
+                    'r' => '\r',
+                    't' => '\t',
+                    'b' => '\u{8}',
+                    'f' => '\u{c}',
+                    'u' => {
+                        let unit = parse_unit(chars)?;
+
+                        // Characters outside the BMP are escaped as surrogate
+                        // pairs
+                        if (0xd800..0xdc00).contains(&unit) {
+                            if chars.next()? != '\\' || chars.next()? != 'u' {
+                                return None;
+                            }
+                            let low = parse_unit(chars)?;
+                            if !(0xdc00..0xe000).contains(&low) {
+                                return None;
+                            }
+                            char::from_u32(0x10000 + ((unit - 0xd800) << 10) + (low - 0xdc00))?
+                        } else {
+                            char::from_u32(unit)?
+                        }
+                    },
+                    c @ ('"' | '\\' | '/') => c,
+                    _ => return None,
+                };
+                string.push(c);
+            },
+            c if c.is_control() => return None,
+            c => string.push(c),
+        }
+    }
+}
+
+fn parse_unit(chars: &mut Peekable<Chars>) -> Option<u32> {
+    let hex: String = chars.take(4).collect();
+    if hex.len() != 4 {
+        return None;
+    }
+
+    u32::from_str_radix(&hex, 16).ok()
+}
+
+fn string(value: &str) -> String {
+    let mut quoted = "\"".to_string();
+
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            },
+            c => quoted.push(c),
+        }
+    }
+
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_objects() {
+        let json = Object::new()
+            .number("id", 3)
+            .number("draining", false)
+            .string("tube", "say \"hi\"\n")
+            .into_string();
+
+        assert_eq!(json, "{\"id\":3,\"draining\":false,\"tube\":\"say \\\"hi\\\"\\n\"}");
+        assert_eq!(Object::new().into_string(), "{}");
+    }
+
+    #[test]
+    fn builds_lists() {
+        assert_eq!(list(vec!["default", "emails"]), "[\"default\",\"emails\"]");
+        assert_eq!(list(Vec::<String>::new()), "[]");
+    }
+
+    #[test]
+    fn parses_objects_of_strings() {
+        assert_eq!(
+            parse_strings(" { \"command\" : \"put 0 0 60\", \"body\":\"say \\\"hi\\\"\\n\\u00e9\\ud83d\\ude00\" } "),
+            Some(vec![
+                ("command".to_string(), "put 0 0 60".to_string()),
+                ("body".to_string(), "say \"hi\"\n\u{e9}\u{1f600}".to_string()),
+            ])
+        );
+        assert_eq!(parse_strings("{}"), Some(vec![]));
+
+        assert_eq!(parse_strings("{\"id\":3}"), None);
+        assert_eq!(parse_strings("{\"a\":\"b\",}"), None);
+        assert_eq!(parse_strings("{\"a\":\"b\"} x"), None);
+        assert_eq!(parse_strings("{\"a\":\"\\ud83d\"}"), None);
+        assert_eq!(parse_strings("[\"a\"]"), None);
+    }
+}
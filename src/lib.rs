@@ -0,0 +1,53 @@
+//! A beanstalkd compatible work queue.
+//!
+//! The queue itself is [`JobQueue`](job_queue/struct.JobQueue.html), which can
+//! be used on its own. [`Server`](server/struct.Server.html) serves it to
+//! clients over the beanstalkd protocol.
+//!
+//! ```no_run
+//! extern crate beanstalkdrs;
+//!
+//! use std::net::TcpListener;
+//!
+//! use beanstalkdrs::{JobQueue, Server};
+//!
+//! fn main() {
+//!     let listener = TcpListener::bind("127.0.0.1:11300").unwrap();
+//!
+//!     Server::new(JobQueue::new()).listen(listener).unwrap();
+//! }
+//! ```
+
+#[macro_use]
+extern crate nom;
+
+#[macro_use]
+extern crate log;
+
+extern crate ring;
+extern crate rustls;
+
+pub mod acl;
+pub mod auth;
+pub mod body_store;
+pub mod embedded;
+pub mod job_queue;
+pub mod parser;
+pub mod server;
+pub mod stream;
+pub mod timer;
+pub mod tls;
+
+mod admin;
+mod document;
+mod gateway;
+mod http;
+mod json;
+mod metrics;
+mod output_buffer;
+mod websocket;
+mod yaml;
+
+pub use embedded::EmbeddedServer;
+pub use job_queue::JobQueue;
+pub use server::Server;
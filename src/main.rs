@@ -1,216 +1,284 @@
-#[macro_use]
-extern crate nom;
-
+extern crate beanstalkdrs;
+#[cfg(unix)]
+extern crate libc;
 #[macro_use]
 extern crate log;
+extern crate signal_hook;
 
-mod parser;
-
-use parser::*;
+mod options;
+mod pretty_env_logger;
 
-mod job_queue;
+use std::env;
+#[cfg(unix)]
+use std::fs::{self, Permissions};
+use std::io::{self, BufRead};
+use std::net::TcpListener;
+#[cfg(unix)]
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::process;
+use std::sync::Arc;
+use std::thread;
 
-use job_queue::*;
+use beanstalkdrs::acl::Acl;
+use beanstalkdrs::auth::{self, Authenticator};
+use beanstalkdrs::body_store::BodyStore;
+use beanstalkdrs::tls::{self, ServerConfig};
+use beanstalkdrs::{JobQueue, Server};
+use options::Address;
+use signal_hook::consts::{SIGINT, SIGTERM, SIGUSR1};
+use signal_hook::iterator::Signals;
+
+enum Listener {
+    Tcp(TcpListener),
+    Tls(TcpListener, Arc<ServerConfig>),
+    Metrics(TcpListener),
+    Admin(TcpListener),
+    Gateway(TcpListener),
+    WebSocket(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
 
-mod pretty_env_logger;
+fn main() {
+    pretty_env_logger::init().unwrap();
 
-use std::io::{Read, Write};
-use std::net::{TcpListener, TcpStream};
-use std::str;
-use std::sync::{Arc, Mutex};
-use std::thread;
-use std::iter;
+    let options = match options::parse(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{}\n{}", message, options::USAGE);
+            process::exit(2);
+        },
+    };
+
+    if options.hash_password {
+        let mut password = String::new();
+        if let Err(err) = io::stdin().lock().read_line(&mut password) {
+            eprintln!("Failed reading password: {}", err);
+            process::exit(1);
+        }
 
-use nom::IResult;
+        println!("{}", auth::hash_password(password.trim_end_matches(['\r', '\n']).as_bytes()));
+        return;
+    }
 
-struct Server {
-    stream: TcpStream,
-    job_queue: Arc<Mutex<JobQueue>>,
-}
+    let authenticator = options.auth_file.as_ref().map(|path| {
+        match Authenticator::from_file(path) {
+            Ok(authenticator) => authenticator,
+            Err(err) => {
+                error!("Failed loading users: {}", err);
+                process::exit(1);
+            },
+        }
+    });
+
+    let acl = options.acl_file.as_ref().map(|path| {
+        match Acl::from_file(path) {
+            Ok(acl) => acl,
+            Err(err) => {
+                error!("Failed loading ACL: {}", err);
+                process::exit(1);
+            },
+        }
+    });
+
+    let tls_config = options.tls.as_ref().map(|files| {
+        match tls::server_config(&files.cert, &files.key, files.client_ca.as_deref()) {
+            Ok(config) => config,
+            Err(err) => {
+                error!("Failed loading TLS configuration: {}", err);
+                process::exit(1);
+            },
+        }
+    });
+
+    let mut listeners: Vec<Listener> = options.addresses.iter()
+        .map(|address| match bind(address, options.socket_mode, tls_config.as_ref()) {
+            Ok(listener) => listener,
+            Err(err) => {
+                error!("Failed listening on {}: {}", address, err);
+                process::exit(1);
+            },
+        })
+        .collect();
+
+    let http = vec![
+        (options.metrics.as_ref(), Listener::Metrics as fn(TcpListener) -> Listener),
+        (options.admin.as_ref(), Listener::Admin),
+        (options.gateway.as_ref(), Listener::Gateway),
+        (options.websocket.as_ref(), Listener::WebSocket),
+    ];
+    for (addr, listener) in http {
+        if let Some(addr) = addr {
+            match TcpListener::bind(addr.as_str()) {
+                Ok(bound) => listeners.push(listener(bound)),
+                Err(err) => {
+                    error!("Failed listening on {}: {}", addr, err);
+                    process::exit(1);
+                },
+            }
+        }
+    }
 
-impl Server {
-    fn new(stream: TcpStream, job_queue: Arc<Mutex<JobQueue>>) -> Server {
-        Server {
-            stream: stream,
-            job_queue: job_queue,
+    let mut job_queue = JobQueue::new();
+    job_queue.set_max_memory(options.max_memory);
+    if let Some(bytes) = options.max_job_size {
+        job_queue.set_max_job_size(bytes);
+    }
+    for &(ref pattern, limit) in &options.tube_limits {
+        job_queue.limit_tubes(pattern, limit);
+    }
+    if let Some(ref spill) = options.spill {
+        match BodyStore::open(&spill.dir) {
+            Ok(store) => job_queue.spill_bodies(store, spill.min_size, spill.watermark),
+            Err(err) => {
+                error!("Failed opening {}: {}", spill.dir.display(), err);
+                process::exit(1);
+            },
         }
     }
 
-    fn run(&mut self) {
-        let mut buffer = vec![];
-        let mut written = 0;
+    let mut server = Server::new(job_queue);
+    if let Some(authenticator) = authenticator {
+        server = server.require_auth(authenticator);
+    }
+    if let Some(acl) = acl {
+        server = server.enforce_acl(acl);
+    }
+    if let Some(timeout) = options.tube_full_wait {
+        server = server.wait_for_room(timeout);
+    }
+    if !options.websocket_origins.is_empty() {
+        server = server.allow_websocket_origins(options.websocket_origins.clone());
+    }
 
-        loop {
-            let incomplete = match parse_beanstalk_command(&(&*buffer)[0..written]) {
-                IResult::Incomplete(_) => true,
-                _ => false,
-            };
+    let mut signals = Signals::new([SIGUSR1, SIGTERM, SIGINT]).unwrap();
+    {
+        let server = server.clone();
+        thread::spawn(move || {
+            for signal in signals.forever() {
+                if signal == SIGUSR1 {
+                    // Same as beanstalkd, there is no signal to leave drain mode
+                    server.job_queue().lock().unwrap().set_draining(true);
+                    continue;
+                }
 
-            if incomplete {
-                buffer.extend(iter::repeat(0).take(16));
-
-                let pos = written;
-                let len = match self.stream.read(&mut buffer[pos..]) {
-                    Ok(r) => r,
-                    Err(err) => {
-                        warn!("Failed reading from client: {:?}", err);
-                        break;
-                    },
-                };
-                written += len;
-
-                if len == 0 {
-                    debug!("Client closed connection");
-                    break;
+                if server.is_shutting_down() {
+                    // Impatient enough to send it twice
+                    warn!("Received signal {} again, exiting right away", signal);
+                    process::exit(1);
                 }
+
+                info!("Received signal {}, shutting down", signal);
+                let server = server.clone();
+                thread::spawn(move || server.shutdown());
             }
+        });
+    }
 
-            match parse_beanstalk_command(&(&*buffer)[0..written]) {
-                IResult::Done(_, command) => {
-                    debug!("Received command {:?}", command);
-
-                    let mut job_queue = self.job_queue.lock().unwrap();
-
-                    let not_found_response = b"NOT_FOUND\r\n";
-
-                    #[allow(unused_must_use)]
-                    match command {
-                        Command::Put {data} => {
-                            let mut alloc_data = Vec::new();
-                            alloc_data.extend_from_slice(data);
-
-                            let id = job_queue.put(1, 1, 1, alloc_data);
-
-                            let response = format!("INSERTED {}\r\n", id);
-
-                            self.stream.write(response.as_bytes());
-                        },
-                        Command::Reserve => {
-                            let (job_id, job_data) = job_queue.reserve();
-
-                            let header = format!("RESERVED {} {}\r\n", job_id, job_data.len());
-
-                            self.stream.write(header.as_bytes());
-                            self.stream.write(job_data.as_slice());
-                            self.stream.write(b"\r\n");
-                        },
-                        Command::Delete {id} => {
-                            let id = str::from_utf8(id)
-                                .unwrap()
-                                .parse::<u8>()
-                                .unwrap();
-
-                            match job_queue.delete(&id) {
-                                Some(_) => self.stream.write(b"DELETED\r\n"),
-                                None => self.stream.write(not_found_response),
-                            };
-                        },
-                        Command::Release { id, .. } => {
-                            let id = str::from_utf8(id)
-                                .unwrap()
-                                .parse::<u8>()
-                                .unwrap();
-
-                            match job_queue.release(&id) {
-                                Some(_) => self.stream.write(b"RELEASED\r\n"),
-                                None => self.stream.write(not_found_response),
-                            };
-                        },
-                        Command::Watch { .. } => {
-                            self.stream.write(b"WATCHING 1\r\n");
-                        },
-                        Command::ListTubes {} => {
-                            let tube_list = "default";
-                            self.stream.write(format!(
-                                "OK {}\r\n{}\r\n",
-                                tube_list.len(),
-                                tube_list
-                            ).as_bytes());
-                        },
-                        Command::StatsTube { .. } => {
-                            match job_queue.stats_tube() {
-                                Some(response) => self.stream.write(response.to_string().as_bytes()),
-                                None => self.stream.write(not_found_response),
-                            };
-                        },
-                        Command::Use {tube} => {
-                            self.stream.write(format!("USING {:?}\r\n", tube).as_bytes());
-                        },
-                        Command::PeekReady {} => {
-                            match job_queue.peek_ready() {
-                                Some((id, data)) => {
-                                    self.stream.write(format!(
-                                        "FOUND {} {}\r\n",
-                                        id,
-                                        data.len()
-                                    ).as_bytes());
-                                    self.stream.write(data.as_slice());
-                                    self.stream.write(b"\r\n");
-                                },
-                                None => {
-                                    self.stream.write(not_found_response);
-                                },
-                            };
-                        },
-                        Command::PeekDelayed {} => {
-                            self.stream.write(not_found_response);
-                        },
-                        Command::PeekBuried {} => {
-                            self.stream.write(not_found_response);
-                        },
-                        Command::StatsJob {id} => {
-                            let id = str::from_utf8(id)
-                                .unwrap()
-                                .parse::<u8>()
-                                .unwrap();
-
-                            match job_queue.stats_job(&id) {
-                                Some(response) => {
-                                    self.stream.write(response.to_string().as_bytes());
-                                },
-                                None => {
-                                    self.stream.write(not_found_response);
-                                },
-                            };
-
-                        },
-                    };
-                },
-                IResult::Incomplete(_) => {
-                    debug!("Unable to parse command - incomplete. Trying to read more data.");
-                    continue;
-                },
-                IResult::Error(err) => {
-                    debug!("Protocol error from client: {:?}", err);
-                    break;
-                }
-            };
+    let threads: Vec<_> = listeners.into_iter()
+        .map(|listener| {
+            let server = server.clone();
+            thread::spawn(move || serve(&server, listener))
+        })
+        .collect();
 
-            buffer = vec![];
-            written = 0;
-        }
+    for thread in threads {
+        thread.join().unwrap();
     }
-}
 
-fn main() {
-    pretty_env_logger::init().unwrap();
+    // Listeners only stop once a shutdown has started. Shutting down again
+    // just waits for it to finish serving clients.
+    server.shutdown();
+    info!("Shut down cleanly");
+}
 
-    let listener = TcpListener::bind("127.0.0.1:11300").unwrap();
+#[cfg_attr(not(unix), allow(unused_variables))]
+fn bind(address: &Address, socket_mode: Option<u32>, tls_config: Option<&Arc<ServerConfig>>) -> io::Result<Listener> {
+    match *address {
+        Address::Tcp(ref addr) => Ok(Listener::Tcp(TcpListener::bind(addr.as_str())?)),
+        Address::Tls(ref addr) => {
+            // Options don't allow TLS listeners without a configuration
+            let config = tls_config.expect("TLS listener without a configuration").clone();
+            Ok(Listener::Tls(TcpListener::bind(addr.as_str())?, config))
+        },
+        #[cfg(unix)]
+        Address::Unix(ref path) => {
+            // Left behind by a server that didn't get to clean up, as long as
+            // nobody is listening on it anymore
+            let is_socket = fs::symlink_metadata(path)
+                .map(|metadata| metadata.file_type().is_socket())
+                .unwrap_or(false);
+            let stale = is_socket && UnixStream::connect(path)
+                .is_err_and(|err| err.kind() == io::ErrorKind::ConnectionRefused);
+            if stale {
+                fs::remove_file(path)?;
+            }
 
-    let job_queue = Arc::new(Mutex::new(JobQueue::new()));
+            let listener = match socket_mode {
+                Some(mode) => {
+                    // Nobody may connect before the socket gets its mode. The
+                    // umask is process wide, but no other threads run yet.
+                    let umask = unsafe { libc::umask(0o777) };
+                    let bound = UnixListener::bind(path);
+                    unsafe { libc::umask(umask) };
+
+                    let listener = bound?;
+                    fs::set_permissions(path, Permissions::from_mode(mode))?;
+                    listener
+                },
+                None => UnixListener::bind(path)?,
+            };
 
-    for stream in listener.incoming() {
-        match stream {
-            Err(_) => panic!("error listen"),
-            Ok(stream) => {
-                let job_queue = job_queue.clone();
-                thread::spawn(move || {
-                    debug!("Client connected");
+            Ok(Listener::Unix(listener, path.clone()))
+        },
+        #[cfg(not(unix))]
+        Address::Unix(_) => Err(io::Error::new(io::ErrorKind::Unsupported, "Unix domain sockets need a Unix system")),
+    }
+}
 
-                    let mut server = Server::new(stream, job_queue);
-                    server.run();
-                });
-            },
-        };
+// Serves clients of the listener. The whole server goes down if the listener
+// stops being usable, as it did with a single listener.
+fn serve(server: &Server, listener: Listener) {
+    let result = match listener {
+        Listener::Tcp(listener) => {
+            info!("Listening on {}", listener.local_addr().unwrap());
+            server.listen(listener)
+        },
+        Listener::Tls(listener, config) => {
+            info!("Listening on tls:{}", listener.local_addr().unwrap());
+            server.listen_tls(listener, config)
+        },
+        Listener::Metrics(listener) => {
+            info!("Serving metrics on http://{}/metrics", listener.local_addr().unwrap());
+            server.listen_metrics(listener)
+        },
+        Listener::Admin(listener) => {
+            info!("Serving the admin API on http://{}", listener.local_addr().unwrap());
+            server.listen_admin(listener)
+        },
+        Listener::Gateway(listener) => {
+            info!("Serving the job gateway on http://{}", listener.local_addr().unwrap());
+            server.listen_gateway(listener)
+        },
+        Listener::WebSocket(listener) => {
+            info!("Listening on ws://{}", listener.local_addr().unwrap());
+            server.listen_websocket(listener)
+        },
+        #[cfg(unix)]
+        Listener::Unix(listener, path) => {
+            info!("Listening on unix:{}", path.display());
+            let result = server.listen_unix(listener);
+            let _ = fs::remove_file(&path);
+            result
+        },
+    };
+
+    if let Err(err) = result {
+        error!("Failed accepting clients: {}", err);
+        server.shutdown();
     }
 }
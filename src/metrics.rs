@@ -0,0 +1,246 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Write};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use http::{Request, Response};
+use job_queue::{JobQueue, StatsResponse, StatsTubeResponse};
+
+// Upper bounds of the command latency buckets, in seconds
+const LATENCY_BUCKETS: [f64; 14] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0,
+];
+
+// How long commands take to handle, by command. Blocking reserves count the
+// time spent waiting for a job.
+pub struct Metrics {
+    latencies: Mutex<BTreeMap<&'static str, Histogram>>,
+}
+
+// Name, type, help and value of a metric every tube has
+type TubeMetric = (&'static str, &'static str, &'static str, fn(&StatsTubeResponse) -> u64);
+
+struct Histogram {
+    // Observations up to each bucket's bound, not cumulative
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics { latencies: Mutex::new(BTreeMap::new()) }
+    }
+
+    pub fn observe(&self, command: &'static str, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let mut latencies = self.latencies.lock().unwrap();
+        let histogram = latencies.entry(command).or_insert(Histogram {
+            buckets: [0; LATENCY_BUCKETS.len()],
+            count: 0,
+            sum: 0.0,
+        });
+
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|&bound| seconds <= bound) {
+            histogram.buckets[bucket] += 1;
+        }
+        histogram.count += 1;
+        histogram.sum += seconds;
+    }
+
+    // Answers scrapes of `/metrics` with the server, tube and latency metrics
+    // in the Prometheus text format.
+    pub fn handle(&self, job_queue: &Mutex<JobQueue>, request: &Request) -> Response {
+        if request.path != "/metrics" {
+            return Response::status(404);
+        }
+        if request.method != "GET" {
+            return Response::status(405);
+        }
+
+        let (stats, tubes) = {
+            let job_queue = job_queue.lock().unwrap();
+            let tubes: Vec<StatsTubeResponse> = job_queue.tube_names().into_iter()
+                .filter_map(|name| job_queue.stats_tube(name))
+                .collect();
+
+            (job_queue.stats(), tubes)
+        };
+
+        let text = self.render(&stats, &tubes);
+        Response::new(200, "text/plain; version=0.0.4; charset=utf-8", text.into_bytes())
+    }
+
+    fn render(&self, stats: &StatsResponse, tubes: &[StatsTubeResponse]) -> String {
+        let mut text = Text::new();
+
+        text.family("beanstalkdrs_current_jobs", "gauge", "Jobs by state.");
+        for &(state, value) in &[
+            ("urgent", stats.current_jobs_urgent),
+            ("ready", stats.current_jobs_ready),
+            ("reserved", stats.current_jobs_reserved),
+            ("delayed", stats.current_jobs_delayed),
+            ("buried", stats.current_jobs_buried),
+        ] {
+            text.sample("beanstalkdrs_current_jobs", &[("state", state)], value);
+        }
+
+        text.single("beanstalkdrs_jobs_total", "counter", "Jobs ever put.", stats.total_jobs);
+        text.single("beanstalkdrs_job_timeouts_total", "counter", "Reservations that timed out.", stats.job_timeouts);
+        text.single("beanstalkdrs_current_tubes", "gauge", "Tubes that exist.", stats.current_tubes);
+        text.single("beanstalkdrs_current_connections", "gauge", "Open connections.", stats.current_connections);
+        text.single("beanstalkdrs_current_waiting", "gauge", "Connections waiting in a reserve.", stats.current_waiting);
+        text.single("beanstalkdrs_connections_total", "counter", "Connections ever made.", stats.total_connections);
+        text.single("beanstalkdrs_uptime_seconds", "gauge", "Seconds since the server started.", stats.uptime);
+        text.single("beanstalkdrs_draining", "gauge", "Whether new jobs are refused.", stats.draining as u8);
+        text.single("beanstalkdrs_memory_used_bytes", "gauge", "Memory taken up by jobs.", stats.memory_used);
+        if let Some(max_memory) = stats.max_memory {
+            text.single("beanstalkdrs_max_memory_bytes", "gauge", "Memory jobs may take up.", max_memory);
+        }
+        if let Some(spilled_bytes) = stats.spilled_bytes {
+            text.single("beanstalkdrs_spilled_bytes", "gauge", "Bytes of job bodies stored on disk.", spilled_bytes);
+        }
+
+        text.family("beanstalkdrs_listener_current_connections", "gauge", "Open connections by listener.");
+        for &(ref listener, current, _) in &stats.listeners {
+            text.sample("beanstalkdrs_listener_current_connections", &[("listener", listener)], current);
+        }
+        text.family("beanstalkdrs_listener_connections_total", "counter", "Connections ever made by listener.");
+        for &(ref listener, _, total) in &stats.listeners {
+            text.sample("beanstalkdrs_listener_connections_total", &[("listener", listener)], total);
+        }
+
+        text.family("beanstalkdrs_tube_current_jobs", "gauge", "Jobs by tube and state.");
+        for tube in tubes {
+            for &(state, value) in &[
+                ("urgent", tube.current_jobs_urgent),
+                ("ready", tube.current_jobs_ready),
+                ("reserved", tube.current_jobs_reserved),
+                ("delayed", tube.current_jobs_delayed),
+                ("buried", tube.current_jobs_buried),
+            ] {
+                text.sample("beanstalkdrs_tube_current_jobs", &[("tube", &tube.name), ("state", state)], value);
+            }
+        }
+
+        let tube_metrics: [TubeMetric; 7] = [
+            ("beanstalkdrs_tube_jobs_total", "counter", "Jobs ever put into the tube.", |tube| tube.total_jobs),
+            ("beanstalkdrs_tube_current_using", "gauge", "Connections using the tube.", |tube| tube.current_using as u64),
+            ("beanstalkdrs_tube_current_watching", "gauge", "Connections watching the tube.", |tube| tube.current_watching as u64),
+            ("beanstalkdrs_tube_current_waiting", "gauge", "Connections waiting for a job from the tube.", |tube| tube.current_waiting as u64),
+            ("beanstalkdrs_tube_pause_time_left_seconds", "gauge", "Seconds until the tube is unpaused.", |tube| tube.pause_time_left),
+            ("beanstalkdrs_tube_deletes_total", "counter", "Jobs deleted from the tube.", |tube| tube.cmd_delete),
+            ("beanstalkdrs_tube_pauses_total", "counter", "Times the tube was paused.", |tube| tube.cmd_pause_tube),
+        ];
+        for &(name, kind, help, value) in &tube_metrics {
+            text.family(name, kind, help);
+            for tube in tubes {
+                text.sample(name, &[("tube", &tube.name)], value(tube));
+            }
+        }
+
+        text.family("beanstalkdrs_command_duration_seconds", "histogram", "Time taken to handle commands.");
+        for (command, histogram) in self.latencies.lock().unwrap().iter() {
+            let mut cumulative = 0;
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets.iter()) {
+                cumulative += count;
+                text.sample(
+                    "beanstalkdrs_command_duration_seconds_bucket",
+                    &[("command", command), ("le", &bound.to_string())],
+                    cumulative
+                );
+            }
+            text.sample(
+                "beanstalkdrs_command_duration_seconds_bucket",
+                &[("command", command), ("le", "+Inf")],
+                histogram.count
+            );
+            text.sample("beanstalkdrs_command_duration_seconds_sum", &[("command", command)], histogram.sum);
+            text.sample("beanstalkdrs_command_duration_seconds_count", &[("command", command)], histogram.count);
+        }
+
+        text.into_string()
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Metrics {
+        Metrics::new()
+    }
+}
+
+// Builds a Prometheus text exposition
+struct Text {
+    text: String,
+}
+
+impl Text {
+    fn new() -> Text {
+        Text { text: String::new() }
+    }
+
+    fn family(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.text, "# HELP {} {}", name, help);
+        let _ = writeln!(self.text, "# TYPE {} {}", name, kind);
+    }
+
+    // A metric family with a single unlabelled sample
+    fn single<V: Display>(&mut self, name: &str, kind: &str, help: &str, value: V) {
+        self.family(name, kind, help);
+        self.sample(name, &[], value);
+    }
+
+    fn sample<V: Display>(&mut self, name: &str, labels: &[(&str, &str)], value: V) {
+        self.text.push_str(name);
+
+        if !labels.is_empty() {
+            let labels: Vec<String> = labels.iter()
+                .map(|&(label, value)| format!("{}=\"{}\"", label, escape(value)))
+                .collect();
+            let _ = write!(self.text, "{{{}}}", labels.join(","));
+        }
+
+        let _ = writeln!(self.text, " {}", value);
+    }
+
+    fn into_string(self) -> String {
+        self.text
+    }
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_text_expositions() {
+        let mut text = Text::new();
+        text.single("up", "gauge", "Whether it is up.", 1);
+        text.sample("tube_jobs", &[("tube", "say \"hi\""), ("state", "ready")], 2);
+
+        assert_eq!(
+            text.into_string(),
+            "# HELP up Whether it is up.\n# TYPE up gauge\nup 1\ntube_jobs{tube=\"say \\\"hi\\\"\",state=\"ready\"} 2\n"
+        );
+    }
+
+    #[test]
+    fn observes_latencies_in_cumulative_buckets() {
+        let metrics = Metrics::new();
+        metrics.observe("put", Duration::from_micros(50));
+        metrics.observe("put", Duration::from_millis(3));
+        metrics.observe("put", Duration::from_secs(10));
+
+        let text = metrics.render(&JobQueue::new().stats(), &[]);
+
+        assert!(text.contains("beanstalkdrs_command_duration_seconds_bucket{command=\"put\",le=\"0.0001\"} 1\n"));
+        assert!(text.contains("beanstalkdrs_command_duration_seconds_bucket{command=\"put\",le=\"0.005\"} 2\n"));
+        assert!(text.contains("beanstalkdrs_command_duration_seconds_bucket{command=\"put\",le=\"5\"} 2\n"));
+        assert!(text.contains("beanstalkdrs_command_duration_seconds_bucket{command=\"put\",le=\"+Inf\"} 3\n"));
+        assert!(text.contains("beanstalkdrs_command_duration_seconds_count{command=\"put\"} 3\n"));
+    }
+}
@@ -0,0 +1,473 @@
+use std::fmt;
+use std::net::Ipv6Addr;
+use std::path::PathBuf;
+
+use beanstalkdrs::job_queue::TubeLimit;
+
+const DEFAULT_HOST: &str = "127.0.0.1";
+const DEFAULT_PORT: u16 = 11300;
+
+pub const USAGE: &str = "\
+Usage: beanstalkdrs [-l ADDR]... [-p PORT] [-m MODE] [-z SIZE] [--max-memory SIZE] [spill options]
+                    [tube limits] [--auth-file PATH [--acl-file PATH]]
+                    [--metrics ADDR] [--admin ADDR] [--gateway ADDR]
+                    [--websocket ADDR [--websocket-origin ORIGIN]...] [TLS options]
+       beanstalkdrs --hash-password < password
+
+Options:
+    -l ADDR     listen on ADDR, either HOST[:PORT], an IPv6 address like ::1
+                or [::1]:PORT, or unix:PATH for a Unix domain socket; may be
+                given several times (default 127.0.0.1)
+                tls:HOST[:PORT] listens for clients connecting through TLS
+    -p PORT     port for addresses that don't name one (default 11300)
+    -m MODE     octal file permissions of Unix domain sockets, e.g. 660
+    -z SIZE     answer puts of bodies bigger than SIZE with JOB_TOO_BIG
+                (default 65535)
+    --max-memory SIZE
+                answer puts with OUT_OF_MEMORY once jobs take up SIZE bytes,
+                which may end in K, M or G
+    -h          show this help
+
+Spill options:
+    --spill-dir PATH      keep job bodies in segment files in PATH instead of
+                          memory; bodies of all jobs unless limited by
+    --spill-min-size SIZE only bodies of at least SIZE bytes
+    --spill-watermark SIZE
+                          only while jobs take up more than SIZE bytes of
+                          memory; bodies already in memory stay there
+
+Tube limits:
+    --tube-max-jobs PATTERN=N
+                          answer puts with TUBE_FULL while tubes whose names
+                          match PATTERN hold N jobs; `*` in PATTERN stands for
+                          any characters, the first matching PATTERN applies
+    --tube-max-bytes PATTERN=SIZE
+                          same for SIZE bytes of job bodies
+    --tube-full-wait SECONDS
+                          let puts into a full tube wait up to SECONDS for
+                          room before answering TUBE_FULL
+
+Authentication:
+    --auth-file PATH      require clients to `auth` as one of the users in
+                          PATH, a file of user:hash lines
+    --acl-file PATH       limit what users may do with each tube to the rules
+                          in PATH, lines of: user tube-pattern operations
+    --hash-password       print the hash of the password read from stdin, for
+                          the auth file
+
+HTTP:
+    --metrics ADDR        serve Prometheus metrics at /metrics on HOST:PORT
+    --admin ADDR          serve the JSON admin API on HOST:PORT
+    --gateway ADDR        let producers and workers put and reserve jobs over
+                          HTTP on HOST:PORT
+    --websocket ADDR      serve the protocol over WebSocket on HOST:PORT
+    --websocket-origin ORIGIN
+                          only let browsers open WebSockets from pages of
+                          ORIGIN, e.g. https://example.com; may be given
+                          several times (default any origin)
+
+TLS options:
+    --tls-cert PATH       PEM certificate chain presented to clients
+    --tls-key PATH        PEM private key of the certificate
+    --tls-client-ca PATH  PEM CA certificates client certificates must be
+                          signed by; clients need none if not given
+";
+
+#[derive(Debug, PartialEq)]
+pub enum Address {
+    // `host:port`, resolved when binding
+    Tcp(String),
+    Tls(String),
+    Unix(PathBuf),
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Address::Tcp(ref addr) => write!(f, "{}", addr),
+            Address::Tls(ref addr) => write!(f, "tls:{}", addr),
+            Address::Unix(ref path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+// Files TLS listeners are set up with
+#[derive(Debug, PartialEq)]
+pub struct Tls {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    pub client_ca: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Options {
+    pub addresses: Vec<Address>,
+    pub socket_mode: Option<u32>,
+    pub max_job_size: Option<usize>,
+    pub max_memory: Option<usize>,
+    pub spill: Option<Spill>,
+    // In the order they were given
+    pub tube_limits: Vec<(String, TubeLimit)>,
+    pub tube_full_wait: Option<u32>,
+    pub tls: Option<Tls>,
+    pub auth_file: Option<PathBuf>,
+    pub acl_file: Option<PathBuf>,
+    pub hash_password: bool,
+    // `host:port` of the metrics endpoint
+    pub metrics: Option<String>,
+    pub admin: Option<String>,
+    pub gateway: Option<String>,
+    pub websocket: Option<String>,
+    // Origins allowed to open WebSockets, any if empty
+    pub websocket_origins: Vec<String>,
+}
+
+// Where and when job bodies get stored on disk
+#[derive(Debug, PartialEq)]
+pub struct Spill {
+    pub dir: PathBuf,
+    pub min_size: Option<usize>,
+    pub watermark: Option<usize>,
+}
+
+pub fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Options, String> {
+    let mut listen = vec![];
+    let mut port = DEFAULT_PORT;
+    let mut socket_mode = None;
+    let mut max_job_size = None;
+    let mut max_memory = None;
+    let mut spill_dir = None;
+    let mut spill_min_size = None;
+    let mut spill_watermark = None;
+    let mut tube_limits = vec![];
+    let mut tube_full_wait = None;
+    let mut cert = None;
+    let mut key = None;
+    let mut client_ca = None;
+    let mut auth_file = None;
+    let mut acl_file = None;
+    let mut hash_password = false;
+    let mut metrics = None;
+    let mut admin = None;
+    let mut gateway = None;
+    let mut websocket = None;
+    let mut websocket_origins = vec![];
+
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or(format!("{} needs a value", name));
+
+        match arg.as_str() {
+            "-l" => listen.push(value("-l")?),
+            "-p" => {
+                let value = value("-p")?;
+                port = value.parse().map_err(|_| format!("invalid port {}", value))?;
+            },
+            "-m" => {
+                let value = value("-m")?;
+                let mode = u32::from_str_radix(&value, 8)
+                    .ok()
+                    .filter(|&mode| mode <= 0o777)
+                    .ok_or(format!("invalid file mode {}", value))?;
+                socket_mode = Some(mode);
+            },
+            "-z" => max_job_size = Some(size(&value("-z")?)?),
+            "--max-memory" => max_memory = Some(size(&value("--max-memory")?)?),
+            "--spill-dir" => spill_dir = Some(PathBuf::from(value("--spill-dir")?)),
+            "--spill-min-size" => spill_min_size = Some(size(&value("--spill-min-size")?)?),
+            "--spill-watermark" => spill_watermark = Some(size(&value("--spill-watermark")?)?),
+            "--tube-max-jobs" => {
+                let value = value("--tube-max-jobs")?;
+                let (pattern, jobs) = pattern_value("--tube-max-jobs", &value)?;
+                let jobs = jobs.parse().map_err(|_| format!("invalid number of jobs {}", jobs))?;
+                tube_limit(&mut tube_limits, pattern).max_jobs = Some(jobs);
+            },
+            "--tube-max-bytes" => {
+                let value = value("--tube-max-bytes")?;
+                let (pattern, bytes) = pattern_value("--tube-max-bytes", &value)?;
+                tube_limit(&mut tube_limits, pattern).max_bytes = Some(size(bytes)?);
+            },
+            "--tube-full-wait" => {
+                let value = value("--tube-full-wait")?;
+                tube_full_wait = Some(value.parse().map_err(|_| format!("invalid timeout {}", value))?);
+            },
+            "--tls-cert" => cert = Some(PathBuf::from(value("--tls-cert")?)),
+            "--tls-key" => key = Some(PathBuf::from(value("--tls-key")?)),
+            "--tls-client-ca" => client_ca = Some(PathBuf::from(value("--tls-client-ca")?)),
+            "--auth-file" => auth_file = Some(PathBuf::from(value("--auth-file")?)),
+            "--acl-file" => acl_file = Some(PathBuf::from(value("--acl-file")?)),
+            "--hash-password" => hash_password = true,
+            "--metrics" => metrics = Some(http_address("--metrics", &value("--metrics")?)?),
+            "--admin" => admin = Some(http_address("--admin", &value("--admin")?)?),
+            "--gateway" => gateway = Some(http_address("--gateway", &value("--gateway")?)?),
+            "--websocket" => websocket = Some(http_address("--websocket", &value("--websocket")?)?),
+            "--websocket-origin" => websocket_origins.push(value("--websocket-origin")?),
+            "-h" | "--help" => return Err("".to_string()),
+            other => return Err(format!("unknown option {}", other)),
+        }
+    }
+
+    if listen.is_empty() {
+        listen.push(DEFAULT_HOST.to_string());
+    }
+
+    let addresses: Vec<Address> = listen.into_iter()
+        .map(|addr| address(&addr, port))
+        .collect::<Result<_, _>>()?;
+
+    let tls = match (cert, key) {
+        (Some(cert), Some(key)) => Some(Tls {cert, key, client_ca}),
+        (None, None) if client_ca.is_none() => None,
+        _ => return Err("TLS needs both --tls-cert and --tls-key".to_string()),
+    };
+
+    let listens_on_tls = addresses.iter().any(|address| matches!(*address, Address::Tls(_)));
+    if listens_on_tls && tls.is_none() {
+        return Err("tls: addresses need --tls-cert and --tls-key".to_string());
+    }
+
+    let spill = match spill_dir {
+        Some(dir) => Some(Spill {dir, min_size: spill_min_size, watermark: spill_watermark}),
+        None if spill_min_size.is_none() && spill_watermark.is_none() => None,
+        None => return Err("--spill-min-size and --spill-watermark need --spill-dir".to_string()),
+    };
+
+    // Rules are about users, so clients have to say who they are
+    if acl_file.is_some() && auth_file.is_none() {
+        return Err("--acl-file needs --auth-file".to_string());
+    }
+
+    if !websocket_origins.is_empty() && websocket.is_none() {
+        return Err("--websocket-origin needs --websocket".to_string());
+    }
+
+    Ok(Options {
+        addresses, socket_mode, max_job_size, max_memory, spill, tube_limits, tube_full_wait, tls, auth_file, acl_file,
+        hash_password, metrics, admin, gateway, websocket, websocket_origins,
+    })
+}
+
+// Splits `PATTERN=VALUE`
+fn pattern_value<'a>(name: &str, value: &'a str) -> Result<(&'a str, &'a str), String> {
+    match value.split_once('=') {
+        Some((pattern, value)) if !pattern.is_empty() => Ok((pattern, value)),
+        _ => Err(format!("{} needs PATTERN=VALUE, not {}", name, value)),
+    }
+}
+
+// Limit of the pattern, so that jobs and bytes given separately end up in
+// the same one
+fn tube_limit<'a>(limits: &'a mut Vec<(String, TubeLimit)>, pattern: &str) -> &'a mut TubeLimit {
+    let pos = match limits.iter().position(|(limited, _)| limited == pattern) {
+        Some(pos) => pos,
+        None => {
+            limits.push((pattern.to_string(), TubeLimit::default()));
+            limits.len() - 1
+        },
+    };
+
+    &mut limits[pos].1
+}
+
+// Number of bytes, optionally in binary kilo-, mega- or gigabytes.
+fn size(value: &str) -> Result<usize, String> {
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(pos) => value.split_at(pos),
+        None => (value, ""),
+    };
+
+    let unit = match unit.to_ascii_uppercase().as_str() {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        _ => return Err(format!("invalid size {}", value)),
+    };
+
+    number.parse::<usize>().ok()
+        .and_then(|number| number.checked_mul(unit))
+        .ok_or(format!("invalid size {}", value))
+}
+
+// HTTP endpoints have no port of their own to default to
+fn http_address(name: &str, addr: &str) -> Result<String, String> {
+    match address(addr, 0)? {
+        Address::Tcp(ref addr) if !addr.ends_with(":0") => Ok(addr.clone()),
+        _ => Err(format!("{} needs HOST:PORT, not {}", name, addr)),
+    }
+}
+
+fn address(addr: &str, port: u16) -> Result<Address, String> {
+    if let Some(addr) = addr.strip_prefix("tls:") {
+        return match address(addr, port)? {
+            Address::Tcp(addr) => Ok(Address::Tls(addr)),
+            _ => Err(format!("tls: needs a host, not {}", addr)),
+        };
+    }
+
+    if let Some(path) = addr.strip_prefix("unix:") {
+        if path.is_empty() {
+            return Err("unix: needs a socket path".to_string());
+        }
+
+        return Ok(Address::Unix(PathBuf::from(path)));
+    }
+
+    // A bare IPv6 address has colons too, but no port
+    if addr.parse::<Ipv6Addr>().is_ok() {
+        return Ok(Address::Tcp(format!("[{}]:{}", addr, port)));
+    }
+
+    if addr.starts_with('[') && addr.ends_with(']') {
+        return Ok(Address::Tcp(format!("{}:{}", addr, port)));
+    }
+
+    if addr.contains(':') {
+        Ok(Address::Tcp(addr.to_string()))
+    } else {
+        Ok(Address::Tcp(format!("{}:{}", addr, port)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Result<Options, String> {
+        parse(line.split_whitespace().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn listens_on_localhost_by_default() {
+        assert_eq!(
+            args(""),
+            Ok(Options {
+                addresses: vec![Address::Tcp("127.0.0.1:11300".to_string())],
+                ..Options::default()
+            })
+        );
+    }
+
+    #[test]
+    fn parses_tcp_and_unix_addresses() {
+        assert_eq!(
+            args("-l 10.0.0.1 -l unix:/run/beanstalkdrs.sock -l 0.0.0.0:9000 -p 11301 -m 660"),
+            Ok(Options {
+                addresses: vec![
+                    Address::Tcp("10.0.0.1:11301".to_string()),
+                    Address::Unix(PathBuf::from("/run/beanstalkdrs.sock")),
+                    Address::Tcp("0.0.0.0:9000".to_string()),
+                ],
+                socket_mode: Some(0o660),
+                ..Options::default()
+            })
+        );
+    }
+
+    #[test]
+    fn parses_ipv6_addresses() {
+        let addresses = args("-l ::1 -l [fe80::1] -l [::]:9000 -p 11301").unwrap().addresses;
+
+        assert_eq!(addresses, vec![
+            Address::Tcp("[::1]:11301".to_string()),
+            Address::Tcp("[fe80::1]:11301".to_string()),
+            Address::Tcp("[::]:9000".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn parses_tls_listeners() {
+        let options = args("-l tls:0.0.0.0 -l [::1]:9000 --tls-cert cert.pem --tls-key key.pem --tls-client-ca ca.pem")
+            .unwrap();
+
+        assert_eq!(options.addresses, vec![
+            Address::Tls("0.0.0.0:11300".to_string()),
+            Address::Tcp("[::1]:9000".to_string()),
+        ]);
+        assert_eq!(options.tls, Some(Tls {
+            cert: PathBuf::from("cert.pem"),
+            key: PathBuf::from("key.pem"),
+            client_ca: Some(PathBuf::from("ca.pem")),
+        }));
+    }
+
+    #[test]
+    fn parses_authentication_options() {
+        let options = args("--auth-file /etc/beanstalkdrs/users --acl-file /etc/beanstalkdrs/acl").unwrap();
+        assert_eq!(options.auth_file, Some(PathBuf::from("/etc/beanstalkdrs/users")));
+        assert_eq!(options.acl_file, Some(PathBuf::from("/etc/beanstalkdrs/acl")));
+        assert!(!options.hash_password);
+
+        assert!(args("--hash-password").unwrap().hash_password);
+    }
+
+    #[test]
+    fn parses_http_addresses() {
+        assert_eq!(args("--metrics 0.0.0.0:9100").unwrap().metrics, Some("0.0.0.0:9100".to_string()));
+        assert_eq!(args("--metrics [::1]:9100").unwrap().metrics, Some("[::1]:9100".to_string()));
+        assert_eq!(args("--admin localhost:8080").unwrap().admin, Some("localhost:8080".to_string()));
+        assert_eq!(args("--gateway 0.0.0.0:8081").unwrap().gateway, Some("0.0.0.0:8081".to_string()));
+        assert_eq!(args("--websocket 0.0.0.0:8082").unwrap().websocket, Some("0.0.0.0:8082".to_string()));
+        assert_eq!(
+            args("--websocket 0.0.0.0:8082 --websocket-origin https://a.example --websocket-origin https://b.example")
+                .unwrap()
+                .websocket_origins,
+            vec!["https://a.example".to_string(), "https://b.example".to_string()]
+        );
+        assert!(args("--websocket-origin https://a.example").is_err());
+        assert!(args("--metrics localhost").is_err());
+        assert!(args("--metrics unix:/run/metrics.sock").is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        assert!(args("-p http").is_err());
+        assert!(args("-m 999").is_err());
+        assert!(args("-l unix:").is_err());
+        assert!(args("-l").is_err());
+        assert!(args("--verbose").is_err());
+        assert!(args("-l tls:localhost").is_err());
+        assert!(args("-l tls:unix:/run/beanstalkdrs.sock --tls-cert cert.pem --tls-key key.pem").is_err());
+        assert!(args("--tls-cert cert.pem").is_err());
+        assert!(args("--acl-file /etc/beanstalkdrs/acl").is_err());
+        assert!(args("--max-memory 1T").is_err());
+        assert!(args("--max-memory M").is_err());
+        assert!(args("-z big").is_err());
+        assert!(args("--tube-max-jobs 100").is_err());
+        assert!(args("--tube-max-jobs =100").is_err());
+        assert!(args("--tube-max-bytes emails=lots").is_err());
+        assert!(args("--tube-full-wait -1").is_err());
+    }
+
+    #[test]
+    fn parses_spill_options() {
+        assert_eq!(
+            args("--spill-dir /var/lib/beanstalkdrs --spill-min-size 64K").unwrap().spill,
+            Some(Spill {dir: PathBuf::from("/var/lib/beanstalkdrs"), min_size: Some(64 * 1024), watermark: None})
+        );
+        assert_eq!(
+            args("--spill-dir spill --spill-watermark 1G").unwrap().spill,
+            Some(Spill {dir: PathBuf::from("spill"), min_size: None, watermark: Some(1 << 30)})
+        );
+        assert!(args("--spill-watermark 1G").is_err());
+    }
+
+    #[test]
+    fn parses_tube_limits() {
+        let options = args("--tube-max-jobs emails=1000 --tube-max-bytes *=1M --tube-max-bytes emails=64K --tube-full-wait 5")
+            .unwrap();
+
+        assert_eq!(options.tube_limits, vec![
+            ("emails".to_string(), TubeLimit {max_jobs: Some(1000), max_bytes: Some(64 * 1024)}),
+            ("*".to_string(), TubeLimit {max_jobs: None, max_bytes: Some(1 << 20)}),
+        ]);
+        assert_eq!(options.tube_full_wait, Some(5));
+    }
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(args("--max-memory 1000").unwrap().max_memory, Some(1000));
+        assert_eq!(args("--max-memory 512k").unwrap().max_memory, Some(512 * 1024));
+        assert_eq!(args("--max-memory 2G").unwrap().max_memory, Some(2 << 30));
+        assert_eq!(args("-z 1M").unwrap().max_job_size, Some(1 << 20));
+    }
+}
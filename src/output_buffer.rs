@@ -0,0 +1,130 @@
+use std::io::{self, Write};
+
+// Collects complete responses for a connection so they can be sent with as
+// few writes as possible and never interleave partially written frames.
+pub struct OutputBuffer {
+    data: Vec<u8>,
+}
+
+impl OutputBuffer {
+    pub fn new() -> OutputBuffer {
+        OutputBuffer { data: Vec::new() }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    // Queues a single line response, e.g. `DELETED`.
+    pub fn line(&mut self, line: &str) {
+        self.data.extend_from_slice(line.as_bytes());
+        self.data.extend_from_slice(b"\r\n");
+    }
+
+    // Queues a response line followed by a body, e.g. `RESERVED <id> <bytes>`.
+    pub fn line_with_body(&mut self, line: &str, body: &[u8]) {
+        self.line(line);
+        self.data.extend_from_slice(body);
+        self.data.extend_from_slice(b"\r\n");
+    }
+
+    // Queues an `OK <bytes>` response carrying a YAML document.
+    pub fn yaml(&mut self, yaml: &str) {
+        self.line_with_body(&format!("OK {}", yaml.len()), yaml.as_bytes());
+    }
+
+    // Writes out everything that is queued. Short writes are retried until the
+    // buffer is empty. If the writer would block, the unsent remainder is kept
+    // and the error is returned so the caller can retry later.
+    pub fn flush_to<W: Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        let mut sent = 0;
+
+        let result = loop {
+            if sent == self.data.len() {
+                break writer.flush();
+            }
+
+            match writer.write(&self.data[sent..]) {
+                Ok(0) => break Err(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    "failed to write buffered response",
+                )),
+                Ok(len) => sent += len,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => break Err(err),
+            }
+        };
+
+        self.data.drain(0..sent);
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Accepts at most `chunk` bytes per write and reports EAGAIN on every
+    // other call.
+    struct ChokingWriter {
+        written: Vec<u8>,
+        chunk: usize,
+        would_block: bool,
+    }
+
+    impl Write for ChokingWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.would_block = !self.would_block;
+
+            if self.would_block {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "try again"));
+            }
+
+            let len = buf.len().min(self.chunk);
+            self.written.extend_from_slice(&buf[..len]);
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn frames_responses() {
+        let mut sut = OutputBuffer::new();
+        let mut out = Vec::new();
+
+        sut.line("INSERTED 1");
+        sut.line_with_body("RESERVED 1 5", b"la\r\nb");
+        sut.yaml("---\n- default\n");
+        sut.flush_to(&mut out).unwrap();
+
+        assert_eq!(
+            &out[..],
+            &b"INSERTED 1\r\nRESERVED 1 5\r\nla\r\nb\r\nOK 14\r\n---\n- default\n\r\n"[..]
+        );
+        assert!(sut.is_empty());
+    }
+
+    #[test]
+    fn keeps_unsent_data_when_writer_would_block() {
+        let mut sut = OutputBuffer::new();
+        let mut out = ChokingWriter { written: Vec::new(), chunk: 3, would_block: false };
+
+        sut.line_with_body("RESERVED 12 10", b"0123456789");
+
+        let mut attempts = 0;
+        while !sut.is_empty() {
+            match sut.flush_to(&mut out) {
+                Ok(()) => {},
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => attempts += 1,
+                Err(err) => panic!("unexpected error {:?}", err),
+            }
+        }
+
+        assert!(attempts > 1);
+        assert_eq!(&out.written[..], &b"RESERVED 12 10\r\n0123456789\r\n"[..]);
+    }
+}
@@ -1,84 +1,161 @@
-use nom::{IResult, alphanumeric, digit};
+use nom::{IResult, digit};
 use std::str;
+use std::str::FromStr;
 
-// todo: parser for tube name (max 200 bytes)
-// todo: parser for ID
-// todo: return ID as u8
+const MAX_TUBE_NAME_LENGTH: usize = 200;
 
 // todo: make errors propagate from parsers
-named!(beanstalk_command <&[u8], Command>, alt!(
+named!(beanstalk_command <&[u8], Command<'_>>, alt!(
     put_command |
     reserve_command |
+    reserve_with_timeout_command |
     delete_command |
     release_command |
     watch_command |
+    ignore_command |
     list_tubes_command |
+    list_tubes_watched_command |
+    list_tube_used_command |
     stats_tube_command |
     use_command |
     peek_ready_command |
     peek_delayed_command |
     peek_buried_command |
-    stats_job_command
+    stats_job_command |
+    touch_command |
+    pause_tube_command |
+    bury_command |
+    kick_command |
+    kick_job_command |
+    peek_command |
+    stats_command |
+    drain_command |
+    undrain_command |
+    auth_command |
+    quit_command
 ));
 
-named!(put_command <Command>, do_parse!(
+named!(number <u32>, map_res!(
+    map_res!(digit, str::from_utf8),
+    u32::from_str
+));
+
+named!(id <u64>, map_res!(
+    map_res!(digit, str::from_utf8),
+    u64::from_str
+));
+
+// Tube names are up to 200 bytes of letters, digits and `-+/;.$_()` and may not
+// start with a hyphen
+named!(tube_name <&'a [u8]>, verify!(
+    take_while1!(is_tube_name_char),
+    |name: &[u8]| name.len() <= MAX_TUBE_NAME_LENGTH && name[0] != b'-'
+));
+
+// Whether the name follows the same rules as tube names in commands, for
+// names that come from elsewhere
+pub fn is_tube_name(name: &str) -> bool {
+    match tube_name(name.as_bytes()) {
+        IResult::Done(rest, _) => rest.is_empty(),
+        _ => false,
+    }
+}
+
+fn is_tube_name_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || b"-+/;.$_()".contains(&c)
+}
+
+// User names and passwords are anything printable up to a space
+named!(credential <&'a [u8]>, take_while1!(|c: u8| c.is_ascii_graphic()));
+
+// Everything up to the body, which is `len` bytes
+named!(put_header <(u32, u32, u32, usize)>, do_parse!(
     tag!("put ") >>
-    digit >>
+    pri: number >>
     tag!(" ") >>
-    digit >>
+    delay: number >>
     tag!(" ") >>
-    digit >>
+    ttr: number >>
     tag!(" ") >>
-    len: map!(digit, |len| str::from_utf8(len).unwrap().parse::<usize>().unwrap()) >>
+    len: map_res!(map_res!(digit, str::from_utf8), usize::from_str) >>
     tag!("\r\n") >>
-    data: take!(len) >>
+    (pri, delay, ttr, len)
+));
+
+named!(put_command <Command<'a>>, do_parse!(
+    header: put_header >>
+    data: take!(header.3) >>
     tag!("\r\n") >>
-    (Command::Put {data: data})
+    (Command::Put {pri: header.0, delay: header.1, ttr: header.2, data})
 ));
 
-named!(reserve_command <Command>, do_parse!(
+named!(reserve_command <Command<'a>>, do_parse!(
     tag!("reserve\r\n") >>
     (Command::Reserve {})
 ));
 
-named!(delete_command <Command>, do_parse!(
+named!(reserve_with_timeout_command <Command<'a>>, do_parse!(
+    tag!("reserve-with-timeout ") >>
+    timeout: number >>
+    tag!("\r\n") >>
+    (Command::ReserveWithTimeout {timeout})
+));
+
+named!(delete_command <Command<'a>>, do_parse!(
     tag!("delete ") >>
-    id: digit >>
+    id: id >>
     tag!("\r\n") >>
-    (Command::Delete {id: id})
+    (Command::Delete {id})
 ));
 
-named!(release_command <Command>, do_parse!(
+named!(release_command <Command<'a>>, do_parse!(
     tag!("release ") >>
-    id: digit >>
+    id: id >>
     tag!(" ") >>
-    pri: digit >>
+    pri: number >>
     tag!(" ") >>
-    delay: digit >>
+    delay: number >>
     tag!("\r\n") >>
-    (Command::Release {id: id, pri: pri, delay: delay})
+    (Command::Release {id, pri, delay})
 ));
 
-named!(watch_command <Command>, do_parse!(
+named!(watch_command <Command<'a>>, do_parse!(
     tag!("watch ") >>
-    tube: alphanumeric >>
+    tube: tube_name >>
     tag!("\r\n") >>
-    (Command::Watch {tube: tube})
+    (Command::Watch {tube})
 ));
 
-named!(list_tubes_command <Command>, do_parse!(
+named!(ignore_command <Command<'a>>, do_parse!(
+    tag!("ignore ") >>
+    tube: tube_name >>
+    tag!("\r\n") >>
+    (Command::Ignore {tube})
+));
+
+named!(list_tubes_command <Command<'a>>, do_parse!(
     tag!("list-tubes\r\n") >>
     (Command::ListTubes {})
 ));
 
-named!(stats_tube_command <Command>, do_parse!(
+named!(list_tubes_watched_command <Command<'a>>, do_parse!(
+    tag!("list-tubes-watched\r\n") >>
+    (Command::ListTubesWatched {})
+));
+
+named!(list_tube_used_command <Command<'a>>, do_parse!(
+    tag!("list-tube-used\r\n") >>
+    (Command::ListTubeUsed {})
+));
+
+named!(stats_tube_command <Command<'a>>, do_parse!(
     tag!("stats-tube ") >>
-    tube: alphanumeric >>
+    tube: tube_name >>
     tag!("\r\n") >>
-    (Command::StatsTube {tube: tube})
+    (Command::StatsTube {tube})
 ));
 
-named!(use_command <Command>, do_parse!(
+named!(use_command <Command<'a>>, do_parse!(
     tag!("use") >>
     tube: alt!(
         map!(
@@ -87,55 +164,189 @@ named!(use_command <Command>, do_parse!(
         ) |
         do_parse!(
             tag!(" ") >>
-            tube: alphanumeric >>
+            tube: tube_name >>
             tag!("\r\n") >>
             (tube)
         )
     ) >>
-    (Command::Use {tube: tube})
+    (Command::Use {tube})
 ));
 
-named!(peek_ready_command <Command>, do_parse!(
+named!(peek_ready_command <Command<'a>>, do_parse!(
     tag!("peek-ready\r\n") >>
     (Command::PeekReady {})
 ));
 
-named!(peek_delayed_command <Command>, do_parse!(
+named!(peek_delayed_command <Command<'a>>, do_parse!(
     tag!("peek-delayed\r\n") >>
     (Command::PeekDelayed {})
 ));
 
-named!(peek_buried_command <Command>, do_parse!(
+named!(peek_buried_command <Command<'a>>, do_parse!(
     tag!("peek-buried\r\n") >>
     (Command::PeekBuried {})
 ));
 
-named!(stats_job_command <Command>, do_parse!(
+named!(stats_job_command <Command<'a>>, do_parse!(
     tag!("stats-job ") >>
-    id: digit >>
+    id: id >>
+    tag!("\r\n") >>
+    (Command::StatsJob {id})
+));
+
+named!(touch_command <Command<'a>>, do_parse!(
+    tag!("touch ") >>
+    id: id >>
+    tag!("\r\n") >>
+    (Command::Touch {id})
+));
+
+named!(pause_tube_command <Command<'a>>, do_parse!(
+    tag!("pause-tube ") >>
+    tube: tube_name >>
+    tag!(" ") >>
+    delay: number >>
+    tag!("\r\n") >>
+    (Command::PauseTube {tube, delay})
+));
+
+named!(bury_command <Command<'a>>, do_parse!(
+    tag!("bury ") >>
+    id: id >>
+    tag!(" ") >>
+    pri: number >>
+    tag!("\r\n") >>
+    (Command::Bury {id, pri})
+));
+
+named!(kick_command <Command<'a>>, do_parse!(
+    tag!("kick ") >>
+    bound: number >>
+    tag!("\r\n") >>
+    (Command::Kick {bound})
+));
+
+named!(kick_job_command <Command<'a>>, do_parse!(
+    tag!("kick-job ") >>
+    id: id >>
+    tag!("\r\n") >>
+    (Command::KickJob {id})
+));
+
+named!(peek_command <Command<'a>>, do_parse!(
+    tag!("peek ") >>
+    id: id >>
     tag!("\r\n") >>
-    (Command::StatsJob {id: id})
+    (Command::Peek {id})
 ));
 
-pub fn parse_beanstalk_command(data: &[u8]) -> IResult<&[u8], Command> {
-    debug!("Trying to parse '{}'", str::from_utf8(data).unwrap());
+named!(stats_command <Command<'a>>, do_parse!(
+    tag!("stats\r\n") >>
+    (Command::Stats {})
+));
+
+// Not part of beanstalkd, lets admins toggle drain mode without a signal
+named!(drain_command <Command<'a>>, do_parse!(
+    tag!("drain\r\n") >>
+    (Command::Drain {})
+));
+
+named!(undrain_command <Command<'a>>, do_parse!(
+    tag!("undrain\r\n") >>
+    (Command::Undrain {})
+));
+
+// Not part of beanstalkd, identifies the client when authentication is on
+named!(auth_command <Command<'a>>, do_parse!(
+    tag!("auth ") >>
+    user: credential >>
+    tag!(" ") >>
+    password: credential >>
+    tag!("\r\n") >>
+    (Command::Auth {user, password})
+));
+
+named!(quit_command <Command<'a>>, do_parse!(
+    tag!("quit\r\n") >>
+    (Command::Quit {})
+));
+
+pub fn parse_beanstalk_command(data: &[u8]) -> IResult<&[u8], Command<'_>> {
+    // Only the length, input may hold passwords
+    debug!("Trying to parse {} bytes", data.len());
     beanstalk_command(data)
 }
 
+// Length of the body of the put the data starts with, once its header is
+// complete. Lets bodies too big to take be skipped without buffering them.
+pub fn parse_put_length(data: &[u8]) -> IResult<&[u8], usize> {
+    put_header(data).map(|(_, _, _, len)| len)
+}
+
 #[derive(Debug, PartialEq)]
 pub enum Command<'a> {
-    Put {data: &'a [u8]},
+    Put {pri: u32, delay: u32, ttr: u32, data: &'a [u8]},
     Reserve,
-    Delete {id: &'a [u8]},
-    Release {id: &'a [u8], pri: &'a [u8], delay: &'a [u8]},
+    ReserveWithTimeout {timeout: u32},
+    Delete {id: u64},
+    Release {id: u64, pri: u32, delay: u32},
     Watch {tube: &'a [u8]},
+    Ignore {tube: &'a [u8]},
     ListTubes {},
+    ListTubesWatched {},
+    ListTubeUsed {},
     StatsTube {tube: &'a [u8]},
     Use {tube: &'a [u8]},
     PeekReady {},
     PeekDelayed {},
     PeekBuried {},
-    StatsJob {id: &'a [u8]},
+    StatsJob {id: u64},
+    Touch {id: u64},
+    PauseTube {tube: &'a [u8], delay: u32},
+    Bury {id: u64, pri: u32},
+    Kick {bound: u32},
+    KickJob {id: u64},
+    Peek {id: u64},
+    Stats {},
+    Drain {},
+    Undrain {},
+    Auth {user: &'a [u8], password: &'a [u8]},
+    Quit {},
+}
+
+impl<'a> Command<'a> {
+    // Name of the command as sent on the wire
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Command::Put { .. } => "put",
+            Command::Reserve => "reserve",
+            Command::ReserveWithTimeout { .. } => "reserve-with-timeout",
+            Command::Delete { .. } => "delete",
+            Command::Release { .. } => "release",
+            Command::Watch { .. } => "watch",
+            Command::Ignore { .. } => "ignore",
+            Command::ListTubes {} => "list-tubes",
+            Command::ListTubesWatched {} => "list-tubes-watched",
+            Command::ListTubeUsed {} => "list-tube-used",
+            Command::StatsTube { .. } => "stats-tube",
+            Command::Use { .. } => "use",
+            Command::PeekReady {} => "peek-ready",
+            Command::PeekDelayed {} => "peek-delayed",
+            Command::PeekBuried {} => "peek-buried",
+            Command::StatsJob { .. } => "stats-job",
+            Command::Touch { .. } => "touch",
+            Command::PauseTube { .. } => "pause-tube",
+            Command::Bury { .. } => "bury",
+            Command::Kick { .. } => "kick",
+            Command::KickJob { .. } => "kick-job",
+            Command::Peek { .. } => "peek",
+            Command::Stats {} => "stats",
+            Command::Drain {} => "drain",
+            Command::Undrain {} => "undrain",
+            Command::Auth { .. } => "auth",
+            Command::Quit {} => "quit",
+        }
+    }
 }
 
 #[cfg(test)]
@@ -147,7 +358,7 @@ mod tests {
     fn parsing_put_command() {
         assert_eq!(
             beanstalk_command(b"put 1 10 60 5\r\nlabas\r\n"),
-            IResult::Done(&b""[..], Command::Put {data: &b"labas"[..]})
+            IResult::Done(&b""[..], Command::Put {pri: 1, delay: 10, ttr: 60, data: &b"labas"[..]})
         );
     }
 
@@ -155,7 +366,7 @@ mod tests {
     fn parsing_put_command_when_data_contains_new_line() {
         assert_eq!(
             beanstalk_command(b"put 1 10 60 7\r\nlab\r\nas\r\n"),
-            IResult::Done(&b""[..], Command::Put {data: &b"lab\r\nas"[..]})
+            IResult::Done(&b""[..], Command::Put {pri: 1, delay: 10, ttr: 60, data: &b"lab\r\nas"[..]})
         );
     }
 
@@ -171,15 +382,50 @@ mod tests {
         );
     }
 
+    #[test]
+    fn parsing_put_command_with_out_of_range_numbers() {
+        assert_eq!(
+            beanstalk_command(b"put 4294967296 0 60 1\r\na\r\n"),
+            IResult::Error(ErrorKind::Alt)
+        );
+    }
+
+    #[test]
+    fn parsing_put_command_with_a_length_out_of_range() {
+        assert_eq!(
+            beanstalk_command(b"put 1 0 60 99999999999999999999999\r\na\r\n"),
+            IResult::Error(ErrorKind::Alt)
+        );
+    }
+
+    #[test]
+    fn parsing_put_length_before_the_body_arrives() {
+        assert_eq!(parse_put_length(b"put 1 0 60 1000000\r\nabc"), IResult::Done(&b"abc"[..], 1000000));
+        assert!(parse_put_length(b"put 1 0 60 1000").is_incomplete());
+        assert!(parse_put_length(b"reserve\r\n").is_err());
+    }
+
+    #[test]
+    fn parsing_reserve_with_timeout_command() {
+        assert_eq!(
+            beanstalk_command(b"reserve-with-timeout 5\r\n"),
+            IResult::Done(&b""[..], Command::ReserveWithTimeout {timeout: 5})
+        );
+        assert_eq!(
+            beanstalk_command(b"reserve-with-timeout\r\n"),
+            IResult::Error(ErrorKind::Alt)
+        );
+    }
+
     #[test]
     fn parsing_delete_command_with_numeric_id() {
         assert_eq!(
             beanstalk_command(b"delete 1\r\n"),
-            IResult::Done(&b""[..], Command::Delete {id: &b"1"[..]})
+            IResult::Done(&b""[..], Command::Delete {id: 1})
         );
         assert_eq!(
             beanstalk_command(b"delete 102\r\n"),
-            IResult::Done(&b""[..], Command::Delete {id: &b"102"[..]})
+            IResult::Done(&b""[..], Command::Delete {id: 102})
         );
     }
 
@@ -206,6 +452,75 @@ mod tests {
         );
     }
 
+    #[test]
+    fn parsing_pause_tube_command() {
+        assert_eq!(
+            beanstalk_command(b"pause-tube default 30\r\n"),
+            IResult::Done(&b""[..], Command::PauseTube {tube: &b"default"[..], delay: 30})
+        );
+    }
+
+    #[test]
+    fn parsing_bury_and_kick_commands() {
+        assert_eq!(
+            beanstalk_command(b"bury 3 100\r\n"),
+            IResult::Done(&b""[..], Command::Bury {id: 3, pri: 100})
+        );
+        assert_eq!(beanstalk_command(b"kick 10\r\n"), IResult::Done(&b""[..], Command::Kick {bound: 10}));
+        assert_eq!(beanstalk_command(b"kick-job 4\r\n"), IResult::Done(&b""[..], Command::KickJob {id: 4}));
+        assert_eq!(beanstalk_command(b"peek 4\r\n"), IResult::Done(&b""[..], Command::Peek {id: 4}));
+    }
+
+    #[test]
+    fn parsing_list_commands() {
+        assert_eq!(beanstalk_command(b"list-tubes\r\n"), IResult::Done(&b""[..], Command::ListTubes {}));
+        assert_eq!(
+            beanstalk_command(b"list-tubes-watched\r\n"),
+            IResult::Done(&b""[..], Command::ListTubesWatched {})
+        );
+        assert_eq!(beanstalk_command(b"list-tube-used\r\n"), IResult::Done(&b""[..], Command::ListTubeUsed {}));
+    }
+
+    #[test]
+    fn parsing_quit_command() {
+        assert_eq!(beanstalk_command(b"quit\r\n"), IResult::Done(&b""[..], Command::Quit {}));
+    }
+
+    #[test]
+    fn parsing_auth_command() {
+        assert_eq!(
+            beanstalk_command(b"auth worker s3cr3t!$\r\n"),
+            IResult::Done(&b""[..], Command::Auth {user: &b"worker"[..], password: &b"s3cr3t!$"[..]})
+        );
+        assert_eq!(beanstalk_command(b"auth worker\r\n"), IResult::Error(ErrorKind::Alt));
+    }
+
+    #[test]
+    fn parsing_tube_names() {
+        assert_eq!(
+            beanstalk_command(b"watch my-tube_1.(x)\r\n"),
+            IResult::Done(&b""[..], Command::Watch {tube: &b"my-tube_1.(x)"[..]})
+        );
+        assert_eq!(beanstalk_command(b"watch -tube\r\n"), IResult::Error(ErrorKind::Alt));
+        assert_eq!(beanstalk_command(b"watch tu be\r\n"), IResult::Error(ErrorKind::Alt));
+
+        let mut too_long = b"watch ".to_vec();
+        too_long.extend(vec![b'a'; 201]);
+        too_long.extend_from_slice(b"\r\n");
+        assert_eq!(beanstalk_command(&too_long), IResult::Error(ErrorKind::Alt));
+    }
+
+    #[test]
+    fn checking_tube_names() {
+        assert!(is_tube_name("my-tube_1.(x)"));
+        assert!(is_tube_name(&"a".repeat(200)));
+        assert!(!is_tube_name(&"a".repeat(201)));
+        assert!(!is_tube_name("-tube"));
+        assert!(!is_tube_name("tu be"));
+        assert!(!is_tube_name("tube\r\n"));
+        assert!(!is_tube_name(""));
+    }
+
 //    #[test]
 //    fn parsing_more_data_than_fits_in_buffer() {
 //        let mut sut = Parser::new();
@@ -26,7 +26,7 @@ extern crate env_logger;
 extern crate log;
 
 use std::fmt;
-use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use self::ansi_term::{Color, Style};
 use self::env_logger::LogBuilder;
@@ -46,7 +46,7 @@ impl fmt::Display for Level {
     }
 }
 
-static MAX_MODULE_WIDTH: AtomicUsize = ATOMIC_USIZE_INIT;
+static MAX_MODULE_WIDTH: AtomicUsize = AtomicUsize::new(0);
 
 /// Initializes the global logger with a pretty env logger.
 ///
@@ -57,7 +57,6 @@ static MAX_MODULE_WIDTH: AtomicUsize = ATOMIC_USIZE_INIT;
 /// # Errors
 ///
 /// This function fails to set the global logger if one has already been set.
-
 pub fn init() -> Result<(), log::SetLoggerError> {
     let mut builder = LogBuilder::new();
 
@@ -66,7 +65,7 @@ pub fn init() -> Result<(), log::SetLoggerError> {
         let max_width = MAX_MODULE_WIDTH.load(Ordering::Relaxed);
         if max_width > module_path.len() {
             let diff = max_width - module_path.len();
-            module_path.extend(::std::iter::repeat(' ').take(diff));
+            module_path.extend(::std::iter::repeat_n(' ', diff));
         } else {
             MAX_MODULE_WIDTH.store(module_path.len(), Ordering::Relaxed);
         }
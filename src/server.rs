@@ -0,0 +1,1135 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Read};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::str;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use nom::IResult;
+
+use acl::{Acl, Operation, ALL_TUBES};
+use admin;
+use auth::Authenticator;
+use gateway::Gateway;
+use http;
+use job_queue::{JobBody, JobQueue, DEFAULT_TUBE};
+use metrics::Metrics;
+use output_buffer::OutputBuffer;
+use parser::{parse_beanstalk_command, parse_put_length, Command};
+use stream::Stream;
+use tls::{ServerConfig, TlsStream};
+use websocket::WebSocket;
+use yaml;
+
+// Size of the chunks the input buffer grows by while waiting for a command
+const READ_SIZE: usize = 4096;
+
+// How long a shutdown waits for connections to send their last responses
+// before cutting them off
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+// How long accepting pauses after running out of file descriptors, so clients
+// get to go away in the meantime
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+// Accepts clients and serves them the beanstalkd protocol on top of a shared
+// job queue. Cloning gives another handle to the same queue.
+#[derive(Clone)]
+pub struct Server {
+    job_queue: Arc<Mutex<JobQueue>>,
+    wakeup: Arc<Condvar>,
+    stopper: Arc<Stopper>,
+    // Clients have to `auth` before anything else if set
+    authenticator: Option<Arc<Authenticator>>,
+    // Limits what authenticated clients may do with each tube if set
+    acl: Option<Arc<Acl>>,
+    // Seconds puts into a full tube wait for room, they are refused right
+    // away if not set
+    full_tube_timeout: Option<u32>,
+    // Origins of the pages browsers may open WebSockets from, any if not set
+    websocket_origins: Option<Arc<Vec<String>>>,
+    metrics: Arc<Metrics>,
+}
+
+// Why `Server::put` turned a job away
+pub(crate) enum PutError {
+    JobTooBig,
+    Draining,
+    OutOfMemory,
+    TubeFull,
+    // Storing the body failed
+    Internal,
+}
+
+// Why `Server::reserve` came back without a job
+pub(crate) enum ReserveError {
+    TimedOut,
+    ShuttingDown,
+    // Getting ready to wait failed
+    Io(io::Error),
+}
+
+// What `Server::shutdown` needs to reach every thread a server has started.
+struct Stopper {
+    requested: AtomicBool,
+    listeners: Mutex<Vec<Endpoint>>,
+    clients: Mutex<HashMap<usize, Box<dyn Stream>>>,
+    // Signalled whenever a client is done
+    client_gone: Condvar,
+    next_client_id: AtomicUsize,
+}
+
+impl Stopper {
+    fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+}
+
+// Address a listener accepts clients on
+enum Endpoint {
+    Tcp(SocketAddr),
+    Tls(SocketAddr),
+    WebSocket(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Endpoint::Tcp(ref addr) => write!(f, "{}", addr),
+            Endpoint::Tls(ref addr) => write!(f, "tls:{}", addr),
+            Endpoint::WebSocket(ref addr) => write!(f, "ws:{}", addr),
+            #[cfg(unix)]
+            Endpoint::Unix(ref path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl Endpoint {
+    // Connects to the listener so that it returns from a blocked accept.
+    fn wake_up(&self) {
+        let _ = match *self {
+            Endpoint::Tcp(ref addr) | Endpoint::Tls(ref addr) | Endpoint::WebSocket(ref addr) => {
+                TcpStream::connect(addr).map(|_| ())
+            },
+            #[cfg(unix)]
+            Endpoint::Unix(ref path) => UnixStream::connect(path).map(|_| ()),
+        };
+    }
+}
+
+// Whether accepting failed because of the listener itself, e.g. its socket
+// was closed or isn't listening, rather than because of a single client.
+fn is_listener_broken(err: &io::Error) -> bool {
+    // Same on every Unix
+    const EBADF: i32 = 9;
+
+    err.kind() == io::ErrorKind::InvalidInput || (cfg!(unix) && err.raw_os_error() == Some(EBADF))
+}
+
+// Whether accepting failed because the process or the system ran out of file
+// descriptors, which lasts until some clients disconnect.
+fn is_out_of_descriptors(err: &io::Error) -> bool {
+    // EMFILE and ENFILE, same on every Unix
+    cfg!(unix) && (err.raw_os_error() == Some(24) || err.raw_os_error() == Some(23))
+}
+
+impl Server {
+    // Takes over the queue and starts the thread that fires its deadlines.
+    pub fn new(job_queue: JobQueue) -> Server {
+        let server = Server {
+            job_queue: Arc::new(Mutex::new(job_queue)),
+            wakeup: Arc::new(Condvar::new()),
+            stopper: Arc::new(Stopper {
+                requested: AtomicBool::new(false),
+                listeners: Mutex::new(vec![]),
+                clients: Mutex::new(HashMap::new()),
+                client_gone: Condvar::new(),
+                next_client_id: AtomicUsize::new(0),
+            }),
+            authenticator: None,
+            acl: None,
+            full_tube_timeout: None,
+            websocket_origins: None,
+            metrics: Arc::new(Metrics::new()),
+        };
+
+        {
+            let server = server.clone();
+            thread::spawn(move || server.run_timer());
+        }
+
+        server
+    }
+
+    pub fn job_queue(&self) -> Arc<Mutex<JobQueue>> {
+        self.job_queue.clone()
+    }
+
+    // Makes clients authenticate before they can do anything else. Has to be
+    // done before listening.
+    pub fn require_auth(mut self, authenticator: Authenticator) -> Server {
+        self.authenticator = Some(Arc::new(authenticator));
+        self
+    }
+
+    // Denies clients whatever the rules don't grant them. Has to be done
+    // before listening.
+    pub fn enforce_acl(mut self, acl: Acl) -> Server {
+        self.acl = Some(Arc::new(acl));
+        self
+    }
+
+    // Lets puts into a tube that is at its limit wait up to `timeout` seconds
+    // for jobs to be deleted, rather than answering TUBE_FULL right away. Has
+    // to be done before listening.
+    pub fn wait_for_room(mut self, timeout: u32) -> Server {
+        self.full_tube_timeout = Some(timeout);
+        self
+    }
+
+    // Refuses WebSockets that browsers open from pages of other origins, e.g.
+    // `https://example.com`, with 403. Has to be done before listening.
+    pub fn allow_websocket_origins(mut self, origins: Vec<String>) -> Server {
+        self.websocket_origins = Some(Arc::new(origins));
+        self
+    }
+
+    // Serves every client connecting to the listener, each on its own thread.
+    // Returns once the server is shut down or if the listener stops being
+    // usable. Failing to accept a single client is only logged.
+    pub fn listen(&self, listener: TcpListener) -> io::Result<()> {
+        let endpoint = Endpoint::Tcp(listener.local_addr()?);
+        self.accept(endpoint, || listener.accept().map(|(stream, _)| stream), Server::run)
+    }
+
+    // Same as `listen`, with every client going through a TLS handshake
+    // first. Connections are served no differently once it is done.
+    pub fn listen_tls(&self, listener: TcpListener, config: Arc<ServerConfig>) -> io::Result<()> {
+        let endpoint = Endpoint::Tls(listener.local_addr()?);
+        self.accept(endpoint, || listener.accept().map(|(stream, _)| stream), move |server, stream, name| {
+            match TlsStream::new(config.clone(), stream) {
+                Ok(stream) => server.run(stream, name),
+                Err(err) => warn!("Failed setting up TLS for a client on {}: {}", name, err),
+            }
+        })
+    }
+
+    // Same as `listen`, for clients such as browsers that speak the protocol
+    // over WebSocket as described in the `websocket` module.
+    pub fn listen_websocket(&self, listener: TcpListener) -> io::Result<()> {
+        let endpoint = Endpoint::WebSocket(listener.local_addr()?);
+        let origins = self.websocket_origins.clone();
+        self.accept(endpoint, || listener.accept().map(|(stream, _)| stream), move |server, stream, name| {
+            // Only clients that get through the handshake count as connected
+            server.track(WebSocket::new(stream, origins.clone()), |mut websocket| {
+                match websocket.handshake() {
+                    Ok(true) => Connection::new(Box::new(websocket), server.clone(), name).run(),
+                    Ok(false) => {},
+                    Err(err) => debug!("WebSocket client on {} went away: {:?}", name, err),
+                }
+            });
+        })
+    }
+
+    // Serves Prometheus metrics over HTTP at `/metrics`.
+    pub fn listen_metrics(&self, listener: TcpListener) -> io::Result<()> {
+        let endpoint = Endpoint::Tcp(listener.local_addr()?);
+        self.accept(endpoint, || listener.accept().map(|(stream, _)| stream), |server, stream, _| {
+            server.serve_http(stream, |request| server.metrics.handle(&server.job_queue, request));
+        })
+    }
+
+    // Serves the HTTP/JSON admin API described in the `admin` module.
+    pub fn listen_admin(&self, listener: TcpListener) -> io::Result<()> {
+        let endpoint = Endpoint::Tcp(listener.local_addr()?);
+        self.accept(endpoint, || listener.accept().map(|(stream, _)| stream), |server, stream, _| {
+            server.serve_http(stream, |request| admin::handle(server, request));
+        })
+    }
+
+    // Serves the HTTP producer and worker gateway described in the `gateway`
+    // module. Jobs it has reserved are released once it stops listening.
+    pub fn listen_gateway(&self, listener: TcpListener) -> io::Result<()> {
+        let endpoint = Endpoint::Tcp(listener.local_addr()?);
+        let client_id = self.job_queue.lock().unwrap().client_connected(&endpoint.to_string());
+        let gateway = Arc::new(Gateway::new(client_id));
+
+        let result = self.accept(endpoint, || listener.accept().map(|(stream, _)| stream), move |server, stream, _| {
+            server.serve_http(stream, |request| gateway.handle(server, request));
+        });
+
+        let mut job_queue = self.job_queue.lock().unwrap();
+        if job_queue.client_disconnected(client_id) {
+            self.wakeup.notify_all();
+        }
+
+        result
+    }
+
+    // Same as `listen`, for clients on the same host connecting through a
+    // Unix domain socket.
+    #[cfg(unix)]
+    pub fn listen_unix(&self, listener: UnixListener) -> io::Result<()> {
+        let path = listener.local_addr()?
+            .as_pathname()
+            .map(|path| path.to_path_buf())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "socket has no path"))?;
+
+        self.accept(Endpoint::Unix(path), || listener.accept().map(|(stream, _)| stream), Server::run)
+    }
+
+    // Hands every client the listener accepts to `serve` on a thread of its
+    // own, along with the listener's name.
+    fn accept<S, F, H>(&self, endpoint: Endpoint, mut accept: F, serve: H) -> io::Result<()>
+        where S: Stream + 'static, F: FnMut() -> io::Result<S>, H: Fn(&Server, S, &str) + Clone + Send + 'static
+    {
+        let name = endpoint.to_string();
+        self.stopper.listeners.lock().unwrap().push(endpoint);
+
+        // Checked only after registering, so a concurrent shutdown either sees
+        // this listener or gets seen here
+        if self.stopper.is_requested() {
+            return Ok(());
+        }
+
+        loop {
+            let stream = accept();
+
+            if self.stopper.is_requested() {
+                break;
+            }
+
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(ref err) if !is_listener_broken(err) => {
+                    warn!("Failed accepting a client on {}: {}", name, err);
+                    if is_out_of_descriptors(err) {
+                        thread::sleep(ACCEPT_BACKOFF);
+                    }
+                    continue;
+                },
+                Err(err) => return Err(err),
+            };
+            let server = self.clone();
+            let name = name.clone();
+            let serve = serve.clone();
+
+            thread::spawn(move || serve(&server, stream, &name));
+        }
+
+        Ok(())
+    }
+
+    // Serves a single client until it disconnects. The client is counted
+    // towards the named listener in logs and stats.
+    pub fn run<S: Stream + 'static>(&self, stream: S, listener: &str) {
+        self.track(stream, |stream| Connection::new(Box::new(stream), self.clone(), listener).run());
+    }
+
+    fn serve_http<S, H>(&self, stream: S, handle: H)
+        where S: Stream + 'static, H: FnMut(&http::Request) -> http::Response
+    {
+        self.track(stream, |mut stream| {
+            let peer = stream.peer_ip();
+            if let Err(err) = http::serve(&mut stream, peer, handle) {
+                debug!("HTTP client went away: {:?}", err);
+            }
+        });
+    }
+
+    // Registers the client so a shutdown can reach it while `serve` runs.
+    fn track<S, F>(&self, stream: S, serve: F)
+        where S: Stream + 'static, F: FnOnce(S)
+    {
+        let client_id = self.stopper.next_client_id.fetch_add(1, Ordering::SeqCst);
+
+        match Stream::try_clone(&stream) {
+            Ok(clone) => {
+                self.stopper.clients.lock().unwrap().insert(client_id, clone);
+            },
+            Err(err) => {
+                warn!("Failed registering client: {:?}", err);
+                return;
+            },
+        };
+
+        // A shutdown may have gone through the clients before this one was
+        // registered
+        if !self.stopper.is_requested() {
+            serve(stream);
+        }
+
+        self.stopper.clients.lock().unwrap().remove(&client_id);
+        self.stopper.client_gone.notify_all();
+    }
+
+    // Stops accepting clients and the timer, then waits for connected clients
+    // to get the responses to commands they already sent before disconnecting
+    // them. Blocked reserves give up without a response. Calling it again, or
+    // from several threads, does no harm and waits just the same.
+    pub fn shutdown(&self) {
+        self.stopper.requested.store(true, Ordering::SeqCst);
+
+        // Wakes up `listen` loops blocked on accept
+        for endpoint in self.stopper.listeners.lock().unwrap().iter() {
+            endpoint.wake_up();
+        }
+
+        // Connections see the end of their input once they are done with what
+        // has been read already, and close after flushing
+        let mut clients = self.stopper.clients.lock().unwrap();
+        for client in clients.values() {
+            let _ = client.shutdown(Shutdown::Read);
+        }
+
+        {
+            let _job_queue = self.job_queue.lock().unwrap();
+            self.wakeup.notify_all();
+        }
+
+        let give_up_at = Instant::now() + SHUTDOWN_GRACE_PERIOD;
+        while !clients.is_empty() {
+            let now = Instant::now();
+            if now >= give_up_at {
+                warn!("Disconnecting {} clients that did not finish in time", clients.len());
+                for client in clients.values() {
+                    let _ = client.shutdown(Shutdown::Both);
+                }
+                break;
+            }
+
+            clients = self.stopper.client_gone.wait_timeout(clients, give_up_at - now).unwrap().0;
+        }
+    }
+
+    pub(crate) fn requires_auth(&self) -> bool {
+        self.authenticator.is_some()
+    }
+
+    // Whether the credentials, sent by the client identified by `source`, are
+    // good. Anybody may do anything anyway if authentication isn't required.
+    pub(crate) fn authenticate(&self, source: &str, user: &str, password: &[u8]) -> bool {
+        match self.authenticator {
+            Some(ref authenticator) => authenticator.authenticate(source, user, password),
+            None => true,
+        }
+    }
+
+    // User an HTTP request authenticated as with basic authentication, or the
+    // response refusing it if authentication is required.
+    pub(crate) fn http_identity(&self, request: &http::Request) -> Result<Option<String>, http::Response> {
+        // Clients without a known address share their lockouts
+        let source = request.peer.map_or_else(|| "unknown".to_string(), |ip| ip.to_string());
+
+        match request.basic_auth() {
+            Some((user, password)) if self.authenticate(&source, &user, password.as_bytes()) => Ok(Some(user)),
+            _ if self.requires_auth() => {
+                Err(http::Response::status(401).with_header("WWW-Authenticate", "Basic realm=\"beanstalkdrs\"".to_string()))
+            },
+            _ => Ok(None),
+        }
+    }
+
+    // Whether the ACL, if any, lets the user do the operation on the tube.
+    pub(crate) fn allows(&self, user: Option<&str>, tube: &str, operation: Operation) -> bool {
+        match self.acl {
+            Some(ref acl) => acl.allows(user, tube, operation),
+            None => true,
+        }
+    }
+
+    // Lets reserves and puts waiting on the queue check it again. The caller has to
+    // hold the queue's lock.
+    pub(crate) fn wake_up(&self) {
+        self.wakeup.notify_all();
+    }
+
+    // Puts a job into the tube. A full tube is waited on for as long as the
+    // server lets puts wait for room.
+    pub(crate) fn put(&self, tube: &str, pri: u32, delay: u32, ttr: u32, data: &[u8]) -> Result<u64, PutError> {
+        let mut job_queue = self.job_queue.lock().unwrap();
+        if data.len() > job_queue.max_job_size() {
+            return Err(PutError::JobTooBig);
+        }
+        if job_queue.is_draining() {
+            return Err(PutError::Draining);
+        }
+
+        // A spilled body is written without holding up the queue
+        let mut body = match job_queue.prepare_body(data.into()) {
+            Ok(body) => body,
+            Err(err) => {
+                error!("Failed making room for a body of {} bytes: {}", data.len(), err);
+                return Err(PutError::Internal);
+            },
+        };
+        drop(job_queue);
+        let written = body.write();
+        job_queue = self.job_queue.lock().unwrap();
+
+        if let Err(err) = written {
+            error!("Failed writing a body of {} bytes: {}", data.len(), err);
+            job_queue.discard_body(body);
+            return Err(PutError::Internal);
+        }
+
+        let mut deadline = None;
+        let refused = loop {
+            if job_queue.is_draining() {
+                break PutError::Draining;
+            }
+            if !job_queue.has_memory_for(tube, body.memory()) {
+                break PutError::OutOfMemory;
+            }
+
+            if job_queue.has_room_in(tube, data.len()) {
+                let id = job_queue.put_body(tube, pri, delay, ttr, body);
+                self.wakeup.notify_all();
+
+                return Ok(id);
+            }
+
+            let deadline = match (deadline, self.full_tube_timeout) {
+                (Some(deadline), _) => deadline,
+                (None, Some(timeout)) => {
+                    let scheduled = job_queue.schedule_wakeup(timeout);
+                    self.wakeup.notify_all();
+                    *deadline.insert(scheduled)
+                },
+                (None, None) => break PutError::TubeFull,
+            };
+
+            if job_queue.now() >= deadline || self.stopper.is_requested() {
+                break PutError::TubeFull;
+            }
+            job_queue = self.wakeup.wait(job_queue).unwrap();
+        };
+
+        job_queue.discard_body(body);
+        Err(refused)
+    }
+
+    // Reserves a job out of the watched tubes for the client, waiting until
+    // one is available or `timeout` seconds have passed. `before_waiting` is
+    // called once without holding the queue, before waiting the first time.
+    // Jobs whose bodies can't be read are buried and passed over.
+    pub(crate) fn reserve<F>(&self, client_id: u64, watching: &[String], timeout: Option<u32>, before_waiting: F)
+        -> Result<(u64, Arc<[u8]>), ReserveError>
+        where F: FnOnce() -> io::Result<()>
+    {
+        let mut job_queue = self.job_queue.lock().unwrap();
+
+        let deadline = timeout.map(|timeout| {
+            let deadline = job_queue.schedule_wakeup(timeout);
+            self.wakeup.notify_all();
+            deadline
+        });
+        let mut before_waiting = Some(before_waiting);
+        let mut waiting = false;
+
+        let result = loop {
+            if let Some((id, body)) = job_queue.reserve(client_id, watching) {
+                self.wakeup.notify_all();
+
+                drop(job_queue);
+                let read = body.read();
+                job_queue = self.job_queue.lock().unwrap();
+
+                match read {
+                    Ok(data) => break Ok((id, data)),
+                    Err(err) => {
+                        error!("Burying job {}, reading its body failed: {}", id, err);
+                        job_queue.bury_unreadable(&id);
+                        continue;
+                    },
+                }
+            }
+
+            if deadline.is_some_and(|deadline| job_queue.now() >= deadline) {
+                break Err(ReserveError::TimedOut);
+            }
+            if self.stopper.is_requested() {
+                break Err(ReserveError::ShuttingDown);
+            }
+
+            if let Some(before_waiting) = before_waiting.take() {
+                drop(job_queue);
+                let ready = before_waiting();
+                job_queue = self.job_queue.lock().unwrap();
+
+                if let Err(err) = ready {
+                    break Err(ReserveError::Io(err));
+                }
+                continue;
+            }
+
+            if !waiting {
+                job_queue.start_waiting(watching);
+                waiting = true;
+            }
+            job_queue = self.wakeup.wait(job_queue).unwrap();
+        };
+
+        if waiting {
+            job_queue.stop_waiting(watching);
+        }
+
+        result
+    }
+
+    // Whether `shutdown` has been called.
+    pub fn is_shutting_down(&self) -> bool {
+        self.stopper.is_requested()
+    }
+
+    // Fires deadlines as they pass and wakes up blocked connections. This is
+    // the only thread that sleeps until a point in time, everybody else waits
+    // on `wakeup`.
+    fn run_timer(&self) {
+        let mut job_queue = self.job_queue.lock().unwrap();
+
+        while !self.stopper.is_requested() {
+            if job_queue.tick() {
+                self.wakeup.notify_all();
+            }
+
+            job_queue = match job_queue.next_deadline() {
+                Some(deadline) => {
+                    let timeout = deadline.saturating_duration_since(job_queue.now());
+                    self.wakeup.wait_timeout(job_queue, timeout).unwrap().0
+                },
+                None => self.wakeup.wait(job_queue).unwrap(),
+            };
+        }
+    }
+}
+
+struct Connection {
+    stream: Box<dyn Stream>,
+    server: Server,
+    // Identifies the connection's reservations in the job queue
+    client_id: u64,
+    listener: String,
+    output: OutputBuffer,
+    using: String,
+    watching: Vec<String>,
+    // User the client authenticated as
+    identity: Option<String>,
+    // Set once writing to the client failed, it's disconnected then
+    closed: bool,
+    // Bytes of a body too big to take that are still to be skipped
+    discarding: usize,
+}
+
+impl Connection {
+    fn new(stream: Box<dyn Stream>, server: Server, listener: &str) -> Connection {
+        let client_id = {
+            let mut job_queue = server.job_queue.lock().unwrap();
+            job_queue.start_using(DEFAULT_TUBE);
+            job_queue.start_watching(DEFAULT_TUBE);
+            job_queue.client_connected(listener)
+        };
+
+        debug!("Client {} connected on {}", client_id, listener);
+
+        Connection {
+            stream,
+            server,
+            client_id,
+            listener: listener.to_string(),
+            output: OutputBuffer::new(),
+            using: DEFAULT_TUBE.to_string(),
+            watching: vec![DEFAULT_TUBE.to_string()],
+            identity: None,
+            closed: false,
+            discarding: 0,
+        }
+    }
+
+    fn run(&mut self) {
+        let mut buffer = vec![];
+        let mut written = 0;
+
+        loop {
+            if self.discarding > 0 && written > 0 {
+                let skipped = cmp::min(self.discarding, written);
+                buffer.drain(0..skipped);
+                written -= skipped;
+                self.discarding -= skipped;
+            }
+
+            let consumed = match parse_beanstalk_command(&buffer[0..written]) {
+                IResult::Done(_, Command::Quit {}) => {
+                    debug!("Client {} on {} quit", self.client_id, self.listener);
+                    break;
+                },
+                IResult::Done(remaining, command) => {
+                    match command {
+                        Command::Auth {user, ..} => {
+                            debug!("Received auth as {}", String::from_utf8_lossy(user));
+                        },
+                        ref command => debug!("Received command {:?}", command),
+                    };
+
+                    let started = Instant::now();
+                    let name = command.name();
+                    self.handle_command(command);
+                    self.server.metrics.observe(name, started.elapsed());
+
+                    if self.closed {
+                        break;
+                    }
+
+                    written - remaining.len()
+                },
+                IResult::Incomplete(_) => {
+                    // A body too big to take is skipped as it arrives, like
+                    // beanstalkd does, rather than growing the buffer for it
+                    let too_big = match parse_put_length(&buffer[0..written]) {
+                        IResult::Done(remaining, len) if len > self.server.job_queue.lock().unwrap().max_job_size() => {
+                            Some((written - remaining.len(), len))
+                        },
+                        _ => None,
+                    };
+                    if let Some((header, len)) = too_big {
+                        debug!("Client {} on {} put a job of {} bytes", self.client_id, self.listener, len);
+                        self.output.line("JOB_TOO_BIG");
+                        self.discarding = len.saturating_add(2);
+
+                        buffer.drain(0..header);
+                        written -= header;
+                        continue;
+                    }
+
+                    // Everything pipelined so far has been answered, so send
+                    // the whole batch before blocking on the next read.
+                    if let Err(err) = self.flush() {
+                        warn!("Failed writing to client {} on {}: {:?}", self.client_id, self.listener, err);
+                        break;
+                    }
+
+                    buffer.resize(written + READ_SIZE, 0);
+
+                    let len = match self.stream.read(&mut buffer[written..]) {
+                        Ok(r) => r,
+                        Err(err) => {
+                            warn!("Failed reading from client {} on {}: {:?}", self.client_id, self.listener, err);
+                            break;
+                        },
+                    };
+                    written += len;
+
+                    if len == 0 {
+                        debug!("Client {} on {} closed connection", self.client_id, self.listener);
+                        break;
+                    }
+
+                    continue;
+                },
+                IResult::Error(err) => {
+                    debug!("Protocol error from client: {:?}", err);
+                    break;
+                }
+            };
+
+            buffer.drain(0..consumed);
+            written -= consumed;
+        }
+
+        if !self.closed {
+            if let Err(err) = self.flush() {
+                debug!("Failed writing pending responses: {:?}", err);
+            }
+        }
+
+        self.disconnect();
+    }
+
+    // Undoes whatever the connection registered with the queue and hands its
+    // reserved jobs to other workers.
+    fn disconnect(&mut self) {
+        let mut job_queue = self.server.job_queue.lock().unwrap();
+
+        job_queue.stop_using(&self.using);
+        for tube in &self.watching {
+            job_queue.stop_watching(tube);
+        }
+
+        if job_queue.client_disconnected(self.client_id) {
+            self.server.wakeup.notify_all();
+        }
+    }
+
+    // Sockets are blocking, so a write that would block is as good as failed
+    // and the connection goes away rather than spinning on it.
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.flush_to(&mut self.stream)
+    }
+
+    // Reserves a job, waiting until one is available or `timeout` seconds
+    // have passed.
+    fn reserve(&mut self, timeout: Option<u32>) {
+        // Watched tubes the client may not consume from are left alone, the
+        // default tube is watched without asking after all
+        let watching: Vec<String> = self.watching.iter()
+            .filter(|tube| self.is_allowed(tube, Operation::Consume))
+            .cloned()
+            .collect();
+        if watching.is_empty() {
+            return self.output.line("PERMISSION_DENIED");
+        }
+
+        // Earlier responses of the batch are sent before blocking. A client
+        // that can't be written to won't take the job either.
+        let server = self.server.clone();
+        match server.reserve(self.client_id, &watching, timeout, || self.flush()) {
+            Ok((id, data)) => self.output.line_with_body(&format!("RESERVED {} {}", id, data.len()), &data),
+            Err(ReserveError::TimedOut) => self.output.line("TIMED_OUT"),
+            Err(ReserveError::ShuttingDown) => {},
+            Err(ReserveError::Io(err)) => {
+                warn!("Failed writing to client {} on {}: {:?}", self.client_id, self.listener, err);
+                self.closed = true;
+            },
+        }
+    }
+
+    // Puts the job into the used tube. If that may mean waiting for room,
+    // earlier responses of the batch are sent first, like before a blocked
+    // reserve.
+    fn put(&mut self, pri: u32, delay: u32, ttr: u32, data: &[u8]) {
+        let may_wait = self.server.full_tube_timeout.is_some()
+            && !self.output.is_empty()
+            && !self.server.job_queue.lock().unwrap().has_room_in(&self.using, data.len());
+
+        if may_wait {
+            if let Err(err) = self.flush() {
+                warn!("Failed writing to client {} on {}: {:?}", self.client_id, self.listener, err);
+                self.closed = true;
+                return;
+            }
+        }
+
+        match self.server.put(&self.using, pri, delay, ttr, data) {
+            Ok(id) => self.output.line(&format!("INSERTED {}", id)),
+            Err(PutError::JobTooBig) => self.output.line("JOB_TOO_BIG"),
+            Err(PutError::Draining) => self.output.line("DRAINING"),
+            Err(PutError::OutOfMemory) => self.output.line("OUT_OF_MEMORY"),
+            Err(PutError::TubeFull) => self.output.line("TUBE_FULL"),
+            Err(PutError::Internal) => self.output.line("INTERNAL_ERROR"),
+        }
+    }
+
+    // Answers a peek, reading the body without holding the queue. Bodies that
+    // can't be read are as good as gone.
+    fn found(&mut self, job: Option<(u64, JobBody)>) {
+        let (id, body) = match job {
+            Some(job) => job,
+            None => return self.output.line("NOT_FOUND"),
+        };
+
+        match body.read() {
+            Ok(data) => self.output.line_with_body(&format!("FOUND {} {}", id, data.len()), &data),
+            Err(err) => {
+                error!("Failed reading the body of job {}: {}", id, err);
+                self.output.line("NOT_FOUND");
+            },
+        }
+    }
+
+    fn is_allowed(&self, tube: &str, operation: Operation) -> bool {
+        self.server.allows(self.identity.as_deref(), tube, operation)
+    }
+
+    // Tube and operation the ACL has to allow for the command, if it needs
+    // anything.
+    fn required_permission(&self, job_queue: &JobQueue, command: &Command) -> Option<(String, Operation)> {
+        let tube = |name: &[u8]| str::from_utf8(name).unwrap().to_string();
+        let job_tube = |id: &u64| job_queue.job_tube(id).map(|tube| tube.to_string());
+
+        match *command {
+            Command::Use {tube: name} => Some((tube(name), Operation::Produce)),
+            Command::Put { .. } => Some((self.using.clone(), Operation::Produce)),
+            Command::Watch {tube: name} => Some((tube(name), Operation::Consume)),
+            Command::Delete {id} | Command::Release {id, ..} | Command::Bury {id, ..} | Command::Touch {id} => {
+                job_tube(&id).map(|tube| (tube, Operation::Consume))
+            },
+            Command::Kick { .. } => Some((self.using.clone(), Operation::Admin)),
+            Command::KickJob {id} => job_tube(&id).map(|tube| (tube, Operation::Admin)),
+            Command::PauseTube {tube: name, ..} => Some((tube(name), Operation::Admin)),
+            // Looking at jobs is up to those who may consume them
+            Command::PeekReady {} | Command::PeekDelayed {} | Command::PeekBuried {} => {
+                Some((self.using.clone(), Operation::Consume))
+            },
+            Command::Peek {id} | Command::StatsJob {id} => job_tube(&id).map(|tube| (tube, Operation::Consume)),
+            Command::StatsTube {tube: name} => Some((tube(name), Operation::Consume)),
+            Command::Drain {} | Command::Undrain {} => Some((ALL_TUBES.to_string(), Operation::Admin)),
+            Command::Reserve | Command::ReserveWithTimeout { .. } | Command::Ignore { .. } | Command::ListTubes {}
+                | Command::ListTubesWatched {} | Command::ListTubeUsed {}
+                | Command::Stats {} | Command::Auth { .. } | Command::Quit {} => None,
+        }
+    }
+
+    fn authenticate(&mut self, user: &[u8], password: &[u8]) {
+        let user = String::from_utf8_lossy(user).into_owned();
+
+        // Failures are throttled per address, or per connection where there's
+        // none like on Unix sockets
+        let source = match self.stream.peer_ip() {
+            Some(ip) => ip.to_string(),
+            None => format!("client {}", self.client_id),
+        };
+
+        if self.server.authenticate(&source, &user, password) {
+            debug!("Client {} on {} authenticated as {}", self.client_id, self.listener, user);
+            self.identity = Some(user);
+            self.output.line("AUTHENTICATED");
+        } else {
+            info!("Client {} on {} failed authenticating as {}", self.client_id, self.listener, user);
+            self.output.line("AUTH_FAILED");
+        }
+    }
+
+    fn handle_command(&mut self, command: Command) {
+        match command {
+            Command::Auth {user, password} => return self.authenticate(user, password),
+            _ if self.server.requires_auth() && self.identity.is_none() => {
+                return self.output.line("NOT_AUTHENTICATED");
+            },
+            Command::Reserve => return self.reserve(None),
+            Command::ReserveWithTimeout {timeout} => return self.reserve(Some(timeout)),
+            _ => {},
+        };
+
+        let mut job_queue = self.server.job_queue.lock().unwrap();
+
+        if let Some((tube, operation)) = self.required_permission(&job_queue, &command) {
+            if !self.is_allowed(&tube, operation) {
+                debug!("Client {} on {} may not {:?} {}", self.client_id, self.listener, operation, tube);
+                return self.output.line("PERMISSION_DENIED");
+            }
+        }
+
+        let not_found_response = "NOT_FOUND";
+
+        match command {
+            Command::Put {pri, delay, ttr, data} => {
+                drop(job_queue);
+                self.put(pri, delay, ttr, data);
+            },
+            Command::Reserve | Command::ReserveWithTimeout { .. } | Command::Auth { .. } | Command::Quit {} => {
+                unreachable!()
+            },
+            // Jobs other clients have reserved are out of reach, as in
+            // beanstalkd
+            Command::Delete {id} | Command::Release {id, ..} | Command::Bury {id, ..} | Command::Touch {id}
+                if job_queue.reservation(&id).is_some_and(|(client, _)| client != self.client_id) =>
+            {
+                self.output.line(not_found_response);
+            },
+            Command::Delete {id} => {
+                match job_queue.delete(&id) {
+                    Some(_) => {
+                        // Puts may be waiting for room in the tube
+                        self.server.wakeup.notify_all();
+                        self.output.line("DELETED");
+                    },
+                    None => self.output.line(not_found_response),
+                };
+            },
+            Command::Release {id, pri, delay} => {
+                if job_queue.release(&id, pri, delay) {
+                    self.server.wakeup.notify_all();
+                    self.output.line("RELEASED");
+                } else {
+                    self.output.line(not_found_response);
+                }
+            },
+            Command::Watch {tube} => {
+                let tube = str::from_utf8(tube).unwrap();
+
+                if !self.watching.iter().any(|watched| watched == tube) {
+                    job_queue.start_watching(tube);
+                    self.watching.push(tube.to_string());
+                }
+
+                self.output.line(&format!("WATCHING {}", self.watching.len()));
+            },
+            Command::Ignore {tube} => {
+                let tube = str::from_utf8(tube).unwrap();
+
+                if self.watching.len() == 1 && self.watching[0] == tube {
+                    self.output.line("NOT_IGNORED");
+                } else {
+                    if let Some(pos) = self.watching.iter().position(|watched| watched == tube) {
+                        job_queue.stop_watching(tube);
+                        self.watching.remove(pos);
+                    }
+
+                    self.output.line(&format!("WATCHING {}", self.watching.len()));
+                }
+            },
+            Command::ListTubes {} => {
+                self.output.yaml(&yaml::list(job_queue.tube_names()));
+            },
+            Command::ListTubesWatched {} => {
+                self.output.yaml(&yaml::list(&self.watching));
+            },
+            Command::ListTubeUsed {} => {
+                self.output.line(&format!("USING {}", self.using));
+            },
+            Command::StatsTube {tube} => {
+                let tube = str::from_utf8(tube).unwrap();
+
+                match job_queue.stats_tube(tube) {
+                    Some(response) => self.output.yaml(&response.to_string()),
+                    None => self.output.line(not_found_response),
+                };
+            },
+            Command::Use {tube} => {
+                let tube = str::from_utf8(tube).unwrap();
+
+                job_queue.stop_using(&self.using);
+                job_queue.start_using(tube);
+                self.using = tube.to_string();
+
+                self.output.line(&format!("USING {}", tube));
+            },
+            Command::PeekReady {} => {
+                let job = job_queue.peek_ready(&self.using);
+                drop(job_queue);
+                self.found(job);
+            },
+            Command::PeekDelayed {} => {
+                let job = job_queue.peek_delayed(&self.using);
+                drop(job_queue);
+                self.found(job);
+            },
+            Command::PeekBuried {} => {
+                let job = job_queue.peek_buried(&self.using);
+                drop(job_queue);
+                self.found(job);
+            },
+            Command::Peek {id} => {
+                let job = job_queue.peek(&id);
+                drop(job_queue);
+                self.found(job);
+            },
+            Command::StatsJob {id} => {
+                match job_queue.stats_job(&id) {
+                    Some(response) => {
+                        self.output.yaml(&response.to_string());
+                    },
+                    None => {
+                        self.output.line(not_found_response);
+                    },
+                };
+            },
+            Command::Touch {id} => {
+                if job_queue.touch(&id) {
+                    self.output.line("TOUCHED");
+                } else {
+                    self.output.line(not_found_response);
+                }
+            },
+            Command::PauseTube {tube, delay} => {
+                let tube = str::from_utf8(tube).unwrap();
+
+                if job_queue.pause_tube(tube, delay) {
+                    self.server.wakeup.notify_all();
+                    self.output.line("PAUSED");
+                } else {
+                    self.output.line(not_found_response);
+                }
+            },
+            Command::Bury {id, pri} => {
+                if job_queue.bury(&id, pri) {
+                    self.output.line("BURIED");
+                } else {
+                    self.output.line(not_found_response);
+                }
+            },
+            Command::Kick {bound} => {
+                let kicked = job_queue.kick(&self.using, bound);
+                if kicked > 0 {
+                    self.server.wakeup.notify_all();
+                }
+
+                self.output.line(&format!("KICKED {}", kicked));
+            },
+            Command::Stats {} => {
+                self.output.yaml(&job_queue.stats().to_string());
+            },
+            Command::Drain {} => {
+                job_queue.set_draining(true);
+                self.output.line("DRAINING");
+            },
+            Command::Undrain {} => {
+                job_queue.set_draining(false);
+                self.output.line("NOT_DRAINING");
+            },
+            Command::KickJob {id} => {
+                if job_queue.kick_job(&id) {
+                    self.server.wakeup.notify_all();
+                    self.output.line("KICKED");
+                } else {
+                    self.output.line(not_found_response);
+                }
+            },
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Write};
+
+    use super::*;
+
+    #[test]
+    fn accepting_goes_on_after_failing_for_a_client() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = Endpoint::Tcp(listener.local_addr().unwrap());
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+
+        let server = Server::new(JobQueue::new());
+        let listening = {
+            let server = server.clone();
+            thread::spawn(move || {
+                let mut failures = vec![io::Error::from_raw_os_error(24), io::Error::from(io::ErrorKind::ConnectionAborted)];
+                let accept = || match failures.pop() {
+                    Some(err) => Err(err),
+                    None => listener.accept().map(|(stream, _)| stream),
+                };
+                server.accept(endpoint, accept, Server::run)
+            })
+        };
+
+        client.write_all(b"use emails\r\n").unwrap();
+        let mut response = String::new();
+        BufReader::new(&client).read_line(&mut response).unwrap();
+        assert_eq!(response, "USING emails\r\n");
+
+        drop(client);
+        server.shutdown();
+        listening.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn accepting_stops_once_the_listener_is_broken() {
+        let server = Server::new(JobQueue::new());
+        let endpoint = Endpoint::Tcp("127.0.0.1:0".parse().unwrap());
+        let accept = || Err::<TcpStream, _>(io::Error::from(io::ErrorKind::InvalidInput));
+
+        assert!(server.accept(endpoint, accept, Server::run).is_err());
+    }
+}
@@ -0,0 +1,44 @@
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Shutdown, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+
+// A connected client, whichever kind of socket it came in on. `Server` serves
+// all of them the same way.
+pub trait Stream: Read + Write + Send {
+    // Another handle to the same socket, so it can be shut down from outside
+    // the thread serving it.
+    fn try_clone(&self) -> io::Result<Box<dyn Stream>>;
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+
+    // Address the client connected from, if the socket has one
+    fn peer_ip(&self) -> Option<IpAddr> {
+        None
+    }
+}
+
+impl Stream for TcpStream {
+    fn try_clone(&self) -> io::Result<Box<dyn Stream>> {
+        Ok(Box::new(TcpStream::try_clone(self)?))
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        TcpStream::shutdown(self, how)
+    }
+
+    fn peer_ip(&self) -> Option<IpAddr> {
+        self.peer_addr().ok().map(|addr| addr.ip())
+    }
+}
+
+#[cfg(unix)]
+impl Stream for UnixStream {
+    fn try_clone(&self) -> io::Result<Box<dyn Stream>> {
+        Ok(Box::new(UnixStream::try_clone(self)?))
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        UnixStream::shutdown(self, how)
+    }
+}
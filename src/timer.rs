@@ -0,0 +1,121 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Source of the current time for everything that has a deadline. Injected so
+// tests can move time forward without sleeping.
+pub trait Clock: Send {
+    fn now(&self) -> Instant;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+// Clock that only moves when told to. Clones share the same time, so a test can
+// keep one handle while the queue owns another.
+#[derive(Clone)]
+pub struct ManualClock {
+    now: Arc<Mutex<Instant>>,
+}
+
+impl ManualClock {
+    pub fn new() -> ManualClock {
+        ManualClock { now: Arc::new(Mutex::new(Instant::now())) }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        let mut now = self.now.lock().unwrap();
+        *now += by;
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> ManualClock {
+        ManualClock::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+}
+
+// Something that has to happen at a point in time. Deadlines are never removed
+// when they become irrelevant (e.g. a reserved job gets deleted), the owner
+// checks whether a fired deadline still applies.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Deadline {
+    DelayEnded(u64),
+    TtrExpired(u64),
+    // A blocked command gives up waiting
+    Wakeup,
+    TubeUnpaused(String),
+}
+
+pub struct Timer {
+    deadlines: BinaryHeap<Reverse<(Instant, Deadline)>>,
+}
+
+impl Timer {
+    pub fn new() -> Timer {
+        Timer { deadlines: BinaryHeap::new() }
+    }
+
+    pub fn schedule(&mut self, at: Instant, deadline: Deadline) {
+        self.deadlines.push(Reverse((at, deadline)));
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.deadlines.peek().map(|&Reverse((at, _))| at)
+    }
+
+    // Removes and returns every deadline that is due at `now`, earliest first.
+    pub fn expired(&mut self, now: Instant) -> Vec<Deadline> {
+        let mut expired = vec![];
+
+        while self.next_deadline().is_some_and(|at| at <= now) {
+            let Reverse((_, deadline)) = self.deadlines.pop().unwrap();
+            expired.push(deadline);
+        }
+
+        expired
+    }
+}
+
+impl Default for Timer {
+    fn default() -> Timer {
+        Timer::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expired_returns_due_deadlines_in_order() {
+        let clock = ManualClock::new();
+        let mut sut = Timer::new();
+
+        sut.schedule(clock.now() + Duration::from_secs(3), Deadline::TtrExpired(1));
+        sut.schedule(clock.now() + Duration::from_secs(1), Deadline::DelayEnded(2));
+        sut.schedule(clock.now() + Duration::from_secs(5), Deadline::Wakeup);
+
+        assert!(sut.expired(clock.now()).is_empty());
+
+        clock.advance(Duration::from_secs(3));
+
+        assert_eq!(
+            sut.expired(clock.now()),
+            vec![Deadline::DelayEnded(2), Deadline::TtrExpired(1)]
+        );
+        assert_eq!(sut.next_deadline(), Some(clock.now() + Duration::from_secs(2)));
+    }
+}
@@ -0,0 +1,123 @@
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Shutdown, TcpStream};
+use std::path::Path;
+use std::sync::Arc;
+
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConnection, StreamOwned};
+
+pub use rustls::ServerConfig;
+
+use stream::Stream;
+
+// Builds the configuration `Server::listen_tls` terminates TLS with, from a
+// PEM certificate chain and private key. Given a bundle of CA certificates,
+// clients also have to present a certificate signed by one of them.
+pub fn server_config(cert: &Path, key: &Path, client_ca: Option<&Path>) -> io::Result<Arc<ServerConfig>> {
+    let chain = read_certificates(cert)?;
+    let key = PrivateKeyDer::from_pem_file(key).map_err(|err| invalid_data(key, err))?;
+
+    let builder = match client_ca {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for certificate in read_certificates(path)? {
+                roots.add(certificate).map_err(|err| invalid_data(path, err))?;
+            }
+
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+                .build()
+                .map_err(|err| invalid_data(path, err))?;
+            ServerConfig::builder().with_client_cert_verifier(verifier)
+        },
+        None => ServerConfig::builder().with_no_client_auth(),
+    };
+
+    let config = builder.with_single_cert(chain, key).map_err(|err| invalid_data(cert, err))?;
+
+    Ok(Arc::new(config))
+}
+
+fn read_certificates(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certificates = CertificateDer::pem_file_iter(path)
+        .map_err(|err| invalid_data(path, err))?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| invalid_data(path, err))?;
+
+    if certificates.is_empty() {
+        return Err(invalid_data(path, "no certificates found"));
+    }
+
+    Ok(certificates)
+}
+
+fn invalid_data<E: ToString>(path: &Path, err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), err.to_string()))
+}
+
+// A client connected through TLS. The handshake happens on the first read,
+// on the thread serving the client.
+pub struct TlsStream {
+    tls: StreamOwned<ServerConnection, TcpStream>,
+}
+
+impl TlsStream {
+    pub fn new(config: Arc<ServerConfig>, stream: TcpStream) -> io::Result<TlsStream> {
+        let connection = ServerConnection::new(config)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+
+        Ok(TlsStream { tls: StreamOwned::new(connection, stream) })
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.tls.read(buf) {
+            // Commands are framed by the protocol itself, so a client hanging
+            // up without saying goodbye is no different from a plain socket
+            // being closed
+            Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(0),
+            result => result,
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.tls.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.tls.flush()
+    }
+}
+
+impl Stream for TlsStream {
+    // The TLS session can't be shared, but the socket under it is all that
+    // shutting down needs
+    fn try_clone(&self) -> io::Result<Box<dyn Stream>> {
+        Ok(Box::new(self.tls.sock.try_clone()?))
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.tls.sock.shutdown(how)
+    }
+
+    fn peer_ip(&self) -> Option<IpAddr> {
+        self.tls.sock.peer_ip()
+    }
+}
+
+impl Drop for TlsStream {
+    // Tells the client the session ended on purpose
+    fn drop(&mut self) {
+        self.tls.conn.send_close_notify();
+
+        while self.tls.conn.wants_write() {
+            if self.tls.conn.write_tls(&mut self.tls.sock).is_err() {
+                break;
+            }
+        }
+    }
+}
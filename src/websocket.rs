@@ -0,0 +1,445 @@
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::{IpAddr, Shutdown};
+use std::str;
+use std::sync::Arc;
+
+use ring::digest;
+
+use document::Document;
+use http::{self, Response};
+use json;
+use stream::Stream;
+
+// Appended to the client's key to prove the server speaks WebSocket
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+// Messages are bounded like HTTP request bodies
+const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xa;
+
+// Control frames can't be fragmented and carry at most this much
+const MAX_CONTROL_PAYLOAD: u64 = 125;
+
+// Status codes of close frames
+const NORMAL_CLOSURE: u16 = 1000;
+const PROTOCOL_ERROR: u16 = 1002;
+const INVALID_PAYLOAD: u16 = 1007;
+const MESSAGE_TOO_BIG: u16 = 1009;
+
+// Whether a frame is the last of its message, its opcode and its payload
+type Frame = (bool, u8, Vec<u8>);
+
+// How messages carry the protocol, picked by the client through the
+// WebSocket subprotocol.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Encoding {
+    // `beanstalk`, the default: messages are protocol input as is, e.g.
+    // `put 0 0 60 5\r\nhello\r\n`, and every response comes in a message of
+    // its own, e.g. `INSERTED 1\r\n`
+    Raw,
+    // `beanstalk.json`: messages are objects like
+    // `{"command": "put 0 0 60", "body": "hello"}`, the byte count being
+    // implied by the body, and responses come as
+    // `{"response": "RESERVED 1", "body": "hello"}`. Bodies that aren't UTF-8
+    // get mangled, so this is meant for text.
+    Json,
+}
+
+// Serves the beanstalkd protocol over WebSocket, for browsers. It is the
+// connection's view of the stream that is framed, so clients are served like
+// any other once `handshake` is through.
+pub struct WebSocket<S: Read + Write> {
+    stream: S,
+    // Origins browsers may open the WebSocket from, any if not set
+    origins: Option<Arc<Vec<String>>>,
+    // Known once the handshake is done
+    encoding: Option<Encoding>,
+    // Read off the socket but not made sense of yet
+    input: Vec<u8>,
+    // Fragments of the message being received
+    message: Vec<u8>,
+    // Whether a message has been started and awaits continuation frames
+    receiving: bool,
+    // Protocol input from complete messages, not read by the connection yet
+    commands: Vec<u8>,
+    // Written by the connection, the last response possibly incomplete
+    responses: Vec<u8>,
+    closed: bool,
+}
+
+impl<S: Read + Write> WebSocket<S> {
+    pub fn new(stream: S, origins: Option<Arc<Vec<String>>>) -> WebSocket<S> {
+        WebSocket {
+            stream,
+            origins,
+            encoding: None,
+            input: vec![],
+            message: vec![],
+            receiving: false,
+            commands: vec![],
+            responses: vec![],
+            closed: false,
+        }
+    }
+
+    // Upgrades the HTTP request the client opens with, which has to be done
+    // before anything else. False if the client went away, wasn't asking for
+    // a WebSocket or came from a page whose origin isn't allowed.
+    pub fn handshake(&mut self) -> io::Result<bool> {
+        let request = match http::read_request(&mut self.stream, &mut self.input)? {
+            Ok(Some((request, _))) => request,
+            Ok(None) => return Ok(false),
+            Err(response) => {
+                response.write_to(&mut self.stream, false)?;
+                return Ok(false);
+            },
+        };
+
+        let upgrade = request.method == "GET"
+            && request.header("upgrade").is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket"))
+            && request.header("sec-websocket-version") == Some("13");
+        let key = match request.header("sec-websocket-key") {
+            Some(key) if upgrade => key,
+            _ => {
+                Response::status(400)
+                    .with_header("Sec-WebSocket-Version", "13".to_string())
+                    .write_to(&mut self.stream, false)?;
+                return Ok(false);
+            },
+        };
+
+        // Keeps pages from other sites from using the browser's access to the
+        // server. Clients other than browsers send no origin.
+        let allowed = match (request.header("origin"), &self.origins) {
+            (Some(origin), Some(origins)) => origins.iter().any(|allowed| allowed.eq_ignore_ascii_case(origin)),
+            _ => true,
+        };
+        if !allowed {
+            debug!("Refusing a WebSocket from {}", request.header("origin").unwrap());
+            Response::status(403).write_to(&mut self.stream, false)?;
+            return Ok(false);
+        }
+
+        // Whichever of ours the client likes best
+        let protocol = request.header("sec-websocket-protocol").and_then(|protocols| {
+            protocols.split(',')
+                .map(|protocol| protocol.trim())
+                .find(|&protocol| protocol == "beanstalk" || protocol == "beanstalk.json")
+        });
+
+        let accept = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, format!("{}{}", key, ACCEPT_GUID).as_bytes());
+        let mut head = format!(
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n",
+            http::encode_base64(accept.as_ref())
+        );
+        if let Some(protocol) = protocol {
+            head.push_str(&format!("Sec-WebSocket-Protocol: {}\r\n", protocol));
+        }
+        head.push_str("\r\n");
+        self.stream.write_all(head.as_bytes())?;
+        self.stream.flush()?;
+
+        self.encoding = Some(if protocol == Some("beanstalk.json") { Encoding::Json } else { Encoding::Raw });
+
+        Ok(true)
+    }
+
+    // Handles the next frame from the client. False once the client is gone
+    // or the WebSocket got closed.
+    fn receive(&mut self) -> io::Result<bool> {
+        let (fin, opcode, payload) = match self.read_frame()? {
+            Ok(Some(frame)) => frame,
+            Ok(None) => return Ok(false),
+            Err(code) => {
+                self.close(code)?;
+                return Ok(false);
+            },
+        };
+
+        match opcode {
+            // A message that was started goes on in continuation frames, which
+            // can't come otherwise
+            CONTINUATION | TEXT | BINARY if (opcode == CONTINUATION) == self.receiving => {
+                self.receiving = !fin;
+
+                if self.message.len() + payload.len() > MAX_MESSAGE_SIZE {
+                    self.close(MESSAGE_TOO_BIG)?;
+                    return Ok(false);
+                }
+                self.message.extend_from_slice(&payload);
+
+                if fin {
+                    let message = mem::take(&mut self.message);
+                    match self.decode(message) {
+                        Some(commands) => self.commands.extend_from_slice(&commands),
+                        None => {
+                            self.close(INVALID_PAYLOAD)?;
+                            return Ok(false);
+                        },
+                    }
+                }
+            },
+            PING => self.send(PONG, &payload)?,
+            PONG => {},
+            CLOSE => {
+                self.close(NORMAL_CLOSURE)?;
+                return Ok(false);
+            },
+            _ => {
+                self.close(PROTOCOL_ERROR)?;
+                return Ok(false);
+            },
+        }
+
+        Ok(true)
+    }
+
+    // Reads the next frame, unmasking its payload. Gives `None` if the client went away,
+    // or the status to close with if the frame isn't acceptable.
+    fn read_frame(&mut self) -> io::Result<Result<Option<Frame>, u16>> {
+        if !self.fill_to(2)? {
+            return Ok(Ok(None));
+        }
+
+        let fin = self.input[0] & 0x80 != 0;
+        let opcode = self.input[0] & 0x0f;
+
+        // Clients have to mask what they send
+        if self.input[1] & 0x80 == 0 {
+            return Ok(Err(PROTOCOL_ERROR));
+        }
+
+        let (len, header_len) = match self.input[1] & 0x7f {
+            126 => {
+                if !self.fill_to(4)? {
+                    return Ok(Ok(None));
+                }
+                (u16::from_be_bytes([self.input[2], self.input[3]]) as u64, 4)
+            },
+            127 => {
+                if !self.fill_to(10)? {
+                    return Ok(Ok(None));
+                }
+                let mut len = [0; 8];
+                len.copy_from_slice(&self.input[2..10]);
+                (u64::from_be_bytes(len), 10)
+            },
+            len => (len as u64, 2),
+        };
+        if len > MAX_MESSAGE_SIZE as u64 {
+            return Ok(Err(MESSAGE_TOO_BIG));
+        }
+        if opcode & 0x8 != 0 && (!fin || len > MAX_CONTROL_PAYLOAD) {
+            return Ok(Err(PROTOCOL_ERROR));
+        }
+
+        let payload_start = header_len + 4;
+        let frame_len = payload_start + len as usize;
+        if !self.fill_to(frame_len)? {
+            return Ok(Ok(None));
+        }
+
+        let mask = &self.input[header_len..payload_start];
+        let payload = self.input[payload_start..frame_len].iter()
+            .enumerate()
+            .map(|(i, byte)| byte ^ mask[i % 4])
+            .collect();
+        self.input.drain(..frame_len);
+
+        Ok(Ok(Some((fin, opcode, payload))))
+    }
+
+    // Reads until there are at least `len` bytes of input, false if the
+    // client went away before.
+    fn fill_to(&mut self, len: usize) -> io::Result<bool> {
+        while self.input.len() < len {
+            if !http::fill(&mut self.stream, &mut self.input)? {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    // Protocol input a message stands for, `None` if it isn't valid.
+    fn decode(&self, message: Vec<u8>) -> Option<Vec<u8>> {
+        if self.encoding == Some(Encoding::Raw) {
+            return Some(message);
+        }
+
+        let members = json::parse_strings(str::from_utf8(&message).ok()?)?;
+        let member = |name: &str| members.iter().find(|(key, _)| key == name).map(|(_, value)| value);
+
+        let command = member("command")?;
+        if command.contains(['\r', '\n']) {
+            return None;
+        }
+
+        let mut commands = command.clone().into_bytes();
+        if let Some(body) = member("body") {
+            commands.extend_from_slice(format!(" {}\r\n", body.len()).as_bytes());
+            commands.extend_from_slice(body.as_bytes());
+        }
+        commands.extend_from_slice(b"\r\n");
+
+        Some(commands)
+    }
+
+    // The message carrying a complete response, and its opcode.
+    fn encode(&self, response: &[u8]) -> (u8, Vec<u8>) {
+        if self.encoding == Some(Encoding::Raw) {
+            let opcode = if str::from_utf8(response).is_ok() { TEXT } else { BINARY };
+            return (opcode, response.to_vec());
+        }
+
+        let line_len = response.windows(2).position(|window| window == b"\r\n").unwrap();
+        let line = String::from_utf8_lossy(&response[..line_len]);
+
+        let object = match body_len(&response[..line_len]) {
+            Some(_) => {
+                // The byte count is left to the body
+                let line = line.rsplit_once(' ').unwrap().0;
+                let body = String::from_utf8_lossy(&response[line_len + 2..response.len() - 2]);
+                json::Object::new().string("response", line).string("body", &body)
+            },
+            None => json::Object::new().string("response", &line),
+        };
+
+        (TEXT, object.into_string().into_bytes())
+    }
+
+    fn send(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let mut frame = vec![0x80 | opcode];
+
+        match payload.len() {
+            len if len < 126 => frame.push(len as u8),
+            len if len <= u16::MAX as usize => {
+                frame.push(126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            },
+            len => {
+                frame.push(127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            },
+        }
+        frame.extend_from_slice(payload);
+
+        self.stream.write_all(&frame)?;
+        self.stream.flush()
+    }
+
+    fn close(&mut self, code: u16) -> io::Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+
+        self.closed = true;
+        self.send(CLOSE, &code.to_be_bytes())
+    }
+}
+
+impl<S: Read + Write> Read for WebSocket<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.commands.is_empty() {
+            if self.closed || !self.receive()? {
+                return Ok(0);
+            }
+        }
+
+        let len = buf.len().min(self.commands.len());
+        buf[..len].copy_from_slice(&self.commands[..len]);
+        self.commands.drain(..len);
+
+        Ok(len)
+    }
+}
+
+impl<S: Read + Write> Write for WebSocket<S> {
+    // Sends every response that is complete, each in a message of its own.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.responses.extend_from_slice(buf);
+
+        while let Some(len) = response_len(&self.responses) {
+            let response: Vec<u8> = self.responses.drain(..len).collect();
+            let (opcode, message) = self.encode(&response);
+            self.send(opcode, &message)?;
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl<S: Stream> Stream for WebSocket<S> {
+    // Shutting down only needs the socket under the WebSocket
+    fn try_clone(&self) -> io::Result<Box<dyn Stream>> {
+        self.stream.try_clone()
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.stream.shutdown(how)
+    }
+
+    fn peer_ip(&self) -> Option<IpAddr> {
+        self.stream.peer_ip()
+    }
+}
+
+impl<S: Read + Write> Drop for WebSocket<S> {
+    // Tells the client the connection ended on purpose
+    fn drop(&mut self) {
+        if self.encoding.is_some() {
+            let _ = self.close(NORMAL_CLOSURE);
+        }
+    }
+}
+
+// Length of the response the data starts with, if it is all there.
+fn response_len(data: &[u8]) -> Option<usize> {
+    let line_len = data.windows(2).position(|window| window == b"\r\n")?;
+
+    let len = match body_len(&data[..line_len]) {
+        Some(body_len) => line_len + 2 + body_len + 2,
+        None => line_len + 2,
+    };
+
+    if data.len() >= len {
+        Some(len)
+    } else {
+        None
+    }
+}
+
+// Size of the body following the response line, if it has one.
+fn body_len(line: &[u8]) -> Option<usize> {
+    let line = str::from_utf8(line).ok()?;
+    let mut words = line.split(' ');
+
+    match words.next()? {
+        "RESERVED" | "FOUND" | "OK" => words.next_back()?.parse().ok(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_responses() {
+        assert_eq!(response_len(b"INSERTED 1\r\nDELETED\r\n"), Some(12));
+        assert_eq!(response_len(b"RESERVED 1 4\r\na\r\nb\r\nDELETED\r\n"), Some(20));
+        assert_eq!(response_len(b"OK 3\r\n---\r"), None);
+        assert_eq!(response_len(b"INSERTED"), None);
+    }
+}
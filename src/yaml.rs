@@ -0,0 +1,149 @@
+use std::fmt::{Display, Write};
+
+use document::Document;
+
+// Builds the flat YAML mappings `stats`, `stats-tube` and `stats-job` answer
+// with.
+pub struct Dict {
+    yaml: String,
+}
+
+impl Dict {
+    pub fn new() -> Dict {
+        Dict { yaml: "---\n".to_string() }
+    }
+
+    pub fn into_string(self) -> String {
+        self.yaml
+    }
+}
+
+impl Document for Dict {
+    fn number<V: Display>(mut self, key: &str, value: V) -> Dict {
+        let _ = writeln!(self.yaml, "{}: {}", dict_key(key), value);
+        self
+    }
+
+    // Adds a string value, quoted if YAML would read it as anything else.
+    fn string(mut self, key: &str, value: &str) -> Dict {
+        let _ = writeln!(self.yaml, "{}: {}", dict_key(key), scalar(value));
+        self
+    }
+}
+
+// Builds a YAML sequence of strings, as `list-tubes` answers with.
+pub fn list<I, S>(items: I) -> String
+    where I: IntoIterator<Item = S>, S: AsRef<str>
+{
+    let mut yaml = "---\n".to_string();
+
+    for item in items {
+        let _ = writeln!(yaml, "- {}", scalar(item.as_ref()));
+    }
+
+    yaml
+}
+
+// Keys like `current-jobs-ready` are left as they are. Those made up from names,
+// such as listener addresses, are quoted unless they are just as plain.
+fn dict_key(key: &str) -> String {
+    if key.bytes().all(|c| c.is_ascii_alphanumeric() || c == b'-') && !needs_quotes(key) {
+        key.to_string()
+    } else {
+        quote(key)
+    }
+}
+
+fn scalar(value: &str) -> String {
+    if needs_quotes(value) {
+        quote(value)
+    } else {
+        value.to_string()
+    }
+}
+
+fn quote(value: &str) -> String {
+    let mut quoted = "\"".to_string();
+
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(quoted, "\\x{:02x}", c as u32);
+            },
+            c => quoted.push(c),
+        }
+    }
+
+    quoted.push('"');
+    quoted
+}
+
+// Plain scalars can't start with an indicator character, contain `: ` or ` #`,
+// or look like a number, boolean or null.
+fn needs_quotes(value: &str) -> bool {
+    let lowercase = value.to_ascii_lowercase();
+
+    value.is_empty()
+        || value.starts_with(|c: char| "-?:,[]{}#&*!|>'\"%@`".contains(c) || c.is_whitespace())
+        || value.ends_with(char::is_whitespace)
+        || value.ends_with(':')
+        || value.contains(": ")
+        || value.contains(" #")
+        || value.chars().any(|c| c.is_control())
+        || value.parse::<f64>().is_ok()
+        || lowercase.starts_with("0x")
+        || lowercase.starts_with("0o")
+        || ["~", "null", "true", "false", "yes", "no", "on", "off", "y", "n", ".inf", "-.inf", ".nan"]
+            .contains(&lowercase.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use document::Document;
+
+    #[test]
+    fn builds_dicts() {
+        let yaml = Dict::new()
+            .number("id", 3)
+            .string("tube", "emails")
+            .into_string();
+
+        assert_eq!(yaml, "---\nid: 3\ntube: emails\n");
+    }
+
+    #[test]
+    fn quotes_keys_made_up_from_names() {
+        let yaml = Dict::new()
+            .number("current-jobs-ready", 1)
+            .number("listener-[::1]:11300-current-connections", 2)
+            .number("listener-unix:/tmp/a: b-current-connections", 3)
+            .into_string();
+
+        assert_eq!(yaml, "---\ncurrent-jobs-ready: 1\n\"listener-[::1]:11300-current-connections\": 2\n\
+\"listener-unix:/tmp/a: b-current-connections\": 3\n");
+    }
+
+    #[test]
+    fn builds_lists() {
+        assert_eq!(list(vec!["default", "emails"]), "---\n- default\n- emails\n");
+        assert_eq!(list(Vec::<String>::new()), "---\n");
+    }
+
+    #[test]
+    fn quotes_strings_yaml_would_misread() {
+        assert_eq!(scalar("my_tube(1)"), "my_tube(1)");
+        assert_eq!(scalar("$tube"), "$tube");
+        assert_eq!(scalar("123"), "\"123\"");
+        assert_eq!(scalar("1.5"), "\"1.5\"");
+        assert_eq!(scalar("true"), "\"true\"");
+        assert_eq!(scalar("Null"), "\"Null\"");
+        assert_eq!(scalar(""), "\"\"");
+        assert_eq!(scalar("a: b"), "\"a: b\"");
+        assert_eq!(scalar("say \"hi\"\n"), "\"say \\\"hi\\\"\\n\"");
+    }
+}
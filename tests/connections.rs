@@ -0,0 +1,93 @@
+extern crate beanstalkdrs;
+
+use std::io::{self, Cursor, Read, Write};
+use std::net::Shutdown;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
+use beanstalkdrs::job_queue::TubeLimit;
+use beanstalkdrs::stream::Stream;
+use beanstalkdrs::{JobQueue, Server};
+
+// Client that sent its commands and went away before reading any response
+struct Vanished {
+    input: Cursor<Vec<u8>>,
+    writes: Arc<AtomicUsize>,
+}
+
+impl Vanished {
+    fn new(input: &[u8]) -> (Vanished, Arc<AtomicUsize>) {
+        let writes = Arc::new(AtomicUsize::new(0));
+        (Vanished {input: Cursor::new(input.to_vec()), writes: writes.clone()}, writes)
+    }
+}
+
+impl Read for Vanished {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+impl Write for Vanished {
+    fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+        self.writes.fetch_add(1, Ordering::SeqCst);
+        Err(io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Stream for Vanished {
+    fn try_clone(&self) -> io::Result<Box<dyn Stream>> {
+        Ok(Box::new(Vanished {input: Cursor::new(vec![]), writes: self.writes.clone()}))
+    }
+
+    fn shutdown(&self, _: Shutdown) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Serves the client, failing the test rather than hanging if the connection
+// doesn't end
+fn run(server: &Server, client: Vanished) {
+    let (done, finished) = mpsc::channel();
+    let server = server.clone();
+    thread::spawn(move || {
+        server.run(client, "test");
+        done.send(()).unwrap();
+    });
+
+    finished.recv_timeout(Duration::from_secs(5)).expect("connection kept going");
+}
+
+#[test]
+fn blocked_reserve_gives_up_on_a_client_it_cannot_write_to() {
+    let server = Server::new(JobQueue::new());
+    let (client, writes) = Vanished::new(b"use emails\r\nreserve\r\n");
+
+    run(&server, client);
+    assert_eq!(writes.load(Ordering::SeqCst), 1);
+
+    let stats = server.job_queue().lock().unwrap().stats();
+    assert_eq!((stats.current_connections, stats.current_waiting), (0, 0));
+
+    server.shutdown();
+}
+
+#[test]
+fn put_waiting_for_room_gives_up_on_a_client_it_cannot_write_to() {
+    let mut job_queue = JobQueue::new();
+    job_queue.limit_tubes("emails", TubeLimit {max_jobs: Some(0), max_bytes: None});
+    let server = Server::new(job_queue).wait_for_room(60);
+    let (client, writes) = Vanished::new(b"use emails\r\nput 0 0 60 2\r\nhi\r\n");
+
+    run(&server, client);
+    assert_eq!(writes.load(Ordering::SeqCst), 1);
+    assert_eq!(server.job_queue().lock().unwrap().stats().current_connections, 0);
+
+    server.shutdown();
+}
@@ -0,0 +1,72 @@
+extern crate beanstalkdrs;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use beanstalkdrs::EmbeddedServer;
+use beanstalkdrs::timer::ManualClock;
+use beanstalkdrs::JobQueue;
+
+fn send(stream: &mut TcpStream, command: &[u8], expected: &[u8]) {
+    stream.write_all(command).unwrap();
+
+    let mut response = vec![0; expected.len()];
+    stream.read_exact(&mut response).unwrap();
+
+    assert_eq!(
+        String::from_utf8_lossy(&response),
+        String::from_utf8_lossy(expected)
+    );
+}
+
+#[test]
+fn serves_the_protocol_on_an_ephemeral_port() {
+    let server = EmbeddedServer::start().unwrap();
+    let mut client = TcpStream::connect(server.addr()).unwrap();
+
+    send(&mut client, b"put 1 0 60 5\r\nhello\r\n", b"INSERTED 1\r\n");
+
+    let job_queue = server.job_queue();
+    assert_eq!(
+        job_queue.lock().unwrap().peek_ready("default").map(|(id, body)| (id, body.read().unwrap())),
+        Some((1, Arc::from(&b"hello"[..])))
+    );
+}
+
+#[test]
+fn uses_the_given_job_queue() {
+    let clock = ManualClock::new();
+    let server = EmbeddedServer::with_job_queue(JobQueue::with_clock(Box::new(clock.clone()))).unwrap();
+    let mut client = TcpStream::connect(server.addr()).unwrap();
+
+    send(&mut client, b"put 1 30 60 3\r\nabc\r\n", b"INSERTED 1\r\n");
+    send(&mut client, b"reserve-with-timeout 0\r\n", b"TIMED_OUT\r\n");
+
+    clock.advance(Duration::from_secs(30));
+    server.job_queue().lock().unwrap().tick();
+
+    send(&mut client, b"reserve-with-timeout 0\r\n", b"RESERVED 1 3\r\nabc\r\n");
+}
+
+#[test]
+fn drop_disconnects_clients_and_stops_listening() {
+    let server = EmbeddedServer::start().unwrap();
+    let addr = server.addr();
+
+    let mut idle = TcpStream::connect(addr).unwrap();
+    let mut waiting = TcpStream::connect(addr).unwrap();
+    waiting.write_all(b"reserve\r\n").unwrap();
+
+    // Give the server a moment to start the blocking reserve
+    thread::sleep(Duration::from_millis(50));
+
+    drop(server);
+
+    let mut buf = [0; 16];
+    assert_eq!(idle.read(&mut buf).unwrap(), 0);
+    assert_eq!(waiting.read(&mut buf).unwrap(), 0);
+    assert!(TcpStream::connect(addr).is_err());
+}
@@ -0,0 +1,83 @@
+extern crate beanstalkdrs;
+
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::process;
+use std::str;
+use std::thread;
+
+use beanstalkdrs::{JobQueue, Server};
+
+#[test]
+fn serves_the_protocol_on_a_unix_socket() {
+    let path = env::temp_dir().join(format!("beanstalkdrs-test-{}.sock", process::id()));
+    let _ = fs::remove_file(&path);
+
+    let listener = UnixListener::bind(&path).unwrap();
+    let server = Server::new(JobQueue::new());
+    let listening = {
+        let server = server.clone();
+        thread::spawn(move || server.listen_unix(listener))
+    };
+
+    let mut client = UnixStream::connect(&path).unwrap();
+    client.write_all(b"put 1 0 60 5\r\nhello\r\nreserve\r\n").unwrap();
+
+    let expected = b"INSERTED 1\r\nRESERVED 1 5\r\nhello\r\n";
+    let mut response = vec![0; expected.len()];
+    client.read_exact(&mut response).unwrap();
+    assert_eq!(&response[..], &expected[..]);
+
+    server.shutdown();
+    listening.join().unwrap().unwrap();
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn counts_connections_by_listener() {
+    let path = env::temp_dir().join(format!("beanstalkdrs-test-{}-stats.sock", process::id()));
+    let _ = fs::remove_file(&path);
+
+    let server = Server::new(JobQueue::new());
+    let unix = UnixListener::bind(&path).unwrap();
+    let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+    let tcp_addr = tcp.local_addr().unwrap();
+
+    let listening = vec![
+        {
+            let server = server.clone();
+            thread::spawn(move || server.listen_unix(unix))
+        },
+        {
+            let server = server.clone();
+            thread::spawn(move || server.listen(tcp))
+        },
+    ];
+
+    // Waits for the response, so the server has registered the client
+    let mut tcp_client = TcpStream::connect(tcp_addr).unwrap();
+    tcp_client.write_all(b"use default\r\n").unwrap();
+    tcp_client.read_exact(&mut [0; 15]).unwrap();
+
+    let mut unix_client = UnixStream::connect(&path).unwrap();
+    unix_client.write_all(b"stats\r\n").unwrap();
+
+    let mut stats = String::new();
+    while !stats.ends_with("\n\r\n") {
+        let mut buf = [0; 1024];
+        let len = unix_client.read(&mut buf).unwrap();
+        stats.push_str(str::from_utf8(&buf[..len]).unwrap());
+    }
+
+    assert!(stats.contains(&format!("\n\"listener-unix:{}-current-connections\": 1\n", path.display())));
+    assert!(stats.contains(&format!("\n\"listener-{}-total-connections\": 1\n", tcp_addr)));
+
+    server.shutdown();
+    for listener in listening {
+        listener.join().unwrap().unwrap();
+    }
+    fs::remove_file(&path).unwrap();
+}
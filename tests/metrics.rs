@@ -0,0 +1,59 @@
+extern crate beanstalkdrs;
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+
+use beanstalkdrs::{JobQueue, Server};
+
+fn get(addr: SocketAddr, path: &str) -> String {
+    let mut client = TcpStream::connect(addr).unwrap();
+    write!(client, "GET {} HTTP/1.0\r\n\r\n", path).unwrap();
+
+    let mut response = String::new();
+    client.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[test]
+fn serves_prometheus_metrics() {
+    let server = Server::new(JobQueue::new());
+    let beanstalk = TcpListener::bind("127.0.0.1:0").unwrap();
+    let beanstalk_addr = beanstalk.local_addr().unwrap();
+    let metrics = TcpListener::bind("127.0.0.1:0").unwrap();
+    let metrics_addr = metrics.local_addr().unwrap();
+
+    let listening = vec![
+        {
+            let server = server.clone();
+            thread::spawn(move || server.listen(beanstalk))
+        },
+        {
+            let server = server.clone();
+            thread::spawn(move || server.listen_metrics(metrics))
+        },
+    ];
+
+    let mut client = TcpStream::connect(beanstalk_addr).unwrap();
+    client.write_all(b"use emails\r\nput 1 0 60 5\r\nhello\r\n").unwrap();
+    let expected = b"USING emails\r\nINSERTED 1\r\n";
+    let mut response = vec![0; expected.len()];
+    client.read_exact(&mut response).unwrap();
+    assert_eq!(&response[..], &expected[..]);
+
+    let response = get(metrics_addr, "/metrics");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert!(response.contains("Content-Type: text/plain; version=0.0.4; charset=utf-8\r\n"));
+    assert!(response.contains("beanstalkdrs_current_jobs{state=\"ready\"} 1\n"));
+    assert!(response.contains("beanstalkdrs_tube_current_jobs{tube=\"emails\",state=\"ready\"} 1\n"));
+    assert!(response.contains("beanstalkdrs_tube_current_using{tube=\"emails\"} 1\n"));
+    assert!(response.contains("# TYPE beanstalkdrs_command_duration_seconds histogram\n"));
+    assert!(response.contains("beanstalkdrs_command_duration_seconds_count{command=\"put\"} 1\n"));
+
+    assert!(get(metrics_addr, "/").starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+    server.shutdown();
+    for listening in listening {
+        listening.join().unwrap().unwrap();
+    }
+}
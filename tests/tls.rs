@@ -0,0 +1,105 @@
+extern crate beanstalkdrs;
+extern crate rustls;
+
+use std::convert::TryFrom;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+
+use beanstalkdrs::{tls, JobQueue, Server};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+
+// Self-signed CA with a server certificate for localhost and a client
+// certificate, all made with openssl and valid for a century
+fn fixture(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/tls").join(name)
+}
+
+fn client_config(with_certificate: bool) -> Arc<ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    roots.add(CertificateDer::from_pem_file(fixture("ca.pem")).unwrap()).unwrap();
+    let builder = ClientConfig::builder().with_root_certificates(roots);
+
+    let config = if with_certificate {
+        let cert = CertificateDer::from_pem_file(fixture("client.pem")).unwrap();
+        let key = PrivateKeyDer::from_pem_file(fixture("client.key")).unwrap();
+        builder.with_client_auth_cert(vec![cert], key).unwrap()
+    } else {
+        builder.with_no_client_auth()
+    };
+
+    Arc::new(config)
+}
+
+fn serve(client_ca: Option<&Path>) -> (Server, String, thread::JoinHandle<()>) {
+    let config = tls::server_config(&fixture("server.pem"), &fixture("server.key"), client_ca).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+
+    let server = Server::new(JobQueue::new());
+    let listening = {
+        let server = server.clone();
+        thread::spawn(move || server.listen_tls(listener, config).unwrap())
+    };
+
+    (server, addr, listening)
+}
+
+fn connect(addr: &str, config: Arc<ClientConfig>) -> StreamOwned<ClientConnection, TcpStream> {
+    let name = ServerName::try_from("localhost").unwrap();
+    let connection = ClientConnection::new(config, name).unwrap();
+
+    StreamOwned::new(connection, TcpStream::connect(addr).unwrap())
+}
+
+#[test]
+fn serves_the_protocol_over_tls() {
+    let (server, addr, listening) = serve(None);
+
+    let mut client = connect(&addr, client_config(false));
+    client.write_all(b"put 1 0 60 5\r\nhello\r\nreserve\r\n").unwrap();
+
+    let expected = b"INSERTED 1\r\nRESERVED 1 5\r\nhello\r\n";
+    let mut response = vec![0; expected.len()];
+    client.read_exact(&mut response).unwrap();
+    assert_eq!(&response[..], &expected[..]);
+
+    client.write_all(b"stats\r\n").unwrap();
+    let mut header = vec![];
+    while !header.ends_with(b"\r\n") {
+        let mut byte = [0];
+        client.read_exact(&mut byte).unwrap();
+        header.push(byte[0]);
+    }
+    let len: usize = String::from_utf8(header).unwrap().trim_start_matches("OK ").trim().parse().unwrap();
+    let mut stats = vec![0; len + 2];
+    client.read_exact(&mut stats).unwrap();
+    let stats = String::from_utf8(stats).unwrap();
+    assert!(stats.contains(&format!("\"listener-tls:{}-current-connections\": 1\n", addr)), "{}", stats);
+
+    server.shutdown();
+    listening.join().unwrap();
+}
+
+#[test]
+fn verifies_client_certificates() {
+    let (server, addr, listening) = serve(Some(&fixture("ca.pem")));
+
+    let mut anonymous = connect(&addr, client_config(false));
+    let result = anonymous.write_all(b"list-tubes\r\n")
+        .and_then(|_| anonymous.read(&mut [0; 64]));
+    assert!(result.is_err());
+
+    let mut client = connect(&addr, client_config(true));
+    client.write_all(b"use emails\r\n").unwrap();
+    let mut response = vec![0; 14];
+    client.read_exact(&mut response).unwrap();
+    assert_eq!(&response[..], b"USING emails\r\n");
+
+    server.shutdown();
+    listening.join().unwrap();
+}
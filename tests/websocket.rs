@@ -0,0 +1,205 @@
+extern crate beanstalkdrs;
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+
+use beanstalkdrs::{JobQueue, Server};
+
+const TEXT: u8 = 0x1;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xa;
+
+fn serve(server: &Server) -> (SocketAddr, thread::JoinHandle<()>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = server.clone();
+
+    (addr, thread::spawn(move || server.listen_websocket(listener).unwrap()))
+}
+
+// Opens a WebSocket with the example key of RFC 6455 and gives the response
+// head along with the connection.
+fn connect(addr: SocketAddr, headers: &str) -> (TcpStream, String) {
+    let mut client = TcpStream::connect(addr).unwrap();
+    write!(
+        client,
+        "GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n{}\r\n",
+        headers
+    ).unwrap();
+
+    let mut head = vec![];
+    while !head.ends_with(b"\r\n\r\n") {
+        let mut byte = [0];
+        client.read_exact(&mut byte).unwrap();
+        head.push(byte[0]);
+    }
+
+    (client, String::from_utf8(head).unwrap())
+}
+
+fn send(client: &mut TcpStream, fin: bool, opcode: u8, payload: &[u8]) {
+    let mask = [1, 2, 3, 4];
+    let mut frame = vec![if fin { 0x80 } else { 0 } | opcode];
+    if payload.len() < 126 {
+        frame.push(0x80 | payload.len() as u8);
+    } else {
+        frame.push(0x80 | 126);
+        frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    }
+    frame.extend_from_slice(&mask);
+    frame.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+
+    client.write_all(&frame).unwrap();
+}
+
+fn receive(client: &mut TcpStream) -> (u8, String) {
+    let mut header = [0; 2];
+    client.read_exact(&mut header).unwrap();
+
+    let len = match header[1] {
+        126 => {
+            let mut len = [0; 2];
+            client.read_exact(&mut len).unwrap();
+            u16::from_be_bytes(len) as usize
+        },
+        len => len as usize,
+    };
+    let mut payload = vec![0; len];
+    client.read_exact(&mut payload).unwrap();
+
+    (header[0] & 0x0f, String::from_utf8_lossy(&payload).into_owned())
+}
+
+#[test]
+fn serves_the_protocol_in_messages() {
+    let server = Server::new(JobQueue::new());
+    let (addr, listening) = serve(&server);
+
+    let (mut client, head) = connect(addr, "Sec-WebSocket-Protocol: chat, beanstalk\r\n");
+    assert!(head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"), "{}", head);
+    assert!(head.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+    assert!(head.contains("Sec-WebSocket-Protocol: beanstalk\r\n"));
+
+    // Pipelined commands get a message per response
+    send(&mut client, true, TEXT, b"use emails\r\nput 0 0 60 5\r\nhello\r\n");
+    assert_eq!(receive(&mut client), (TEXT, "USING emails\r\n".to_string()));
+    assert_eq!(receive(&mut client), (TEXT, "INSERTED 1\r\n".to_string()));
+
+    // Commands may be split across frames and messages
+    send(&mut client, false, TEXT, b"watch em");
+    send(&mut client, true, 0x0, b"ails\r\nreserve-with");
+    send(&mut client, true, TEXT, b"-timeout 0\r\n");
+    assert_eq!(receive(&mut client), (TEXT, "WATCHING 2\r\n".to_string()));
+    assert_eq!(receive(&mut client), (TEXT, "RESERVED 1 5\r\nhello\r\n".to_string()));
+
+    send(&mut client, true, PING, b"still there?");
+    assert_eq!(receive(&mut client), (PONG, "still there?".to_string()));
+
+    send(&mut client, true, CLOSE, &1000u16.to_be_bytes());
+    assert_eq!(receive(&mut client).0, CLOSE);
+    let mut rest = vec![];
+    client.read_to_end(&mut rest).unwrap();
+    assert!(rest.is_empty());
+
+    // Closing hands back what the client had reserved
+    assert_eq!(server.job_queue().lock().unwrap().stats_job(&1).unwrap().state, "ready");
+
+    server.shutdown();
+    listening.join().unwrap();
+}
+
+#[test]
+fn speaks_json_if_asked_to() {
+    let server = Server::new(JobQueue::new());
+    let (addr, listening) = serve(&server);
+
+    let (mut client, head) = connect(addr, "Sec-WebSocket-Protocol: beanstalk.json\r\n");
+    assert!(head.contains("Sec-WebSocket-Protocol: beanstalk.json\r\n"));
+
+    send(&mut client, true, TEXT, br#"{"command": "put 0 0 60", "body": "say \"hi\""}"#);
+    assert_eq!(receive(&mut client), (TEXT, r#"{"response":"INSERTED 1"}"#.to_string()));
+
+    send(&mut client, true, TEXT, br#"{"command": "reserve"}"#);
+    assert_eq!(receive(&mut client), (TEXT, r#"{"response":"RESERVED 1","body":"say \"hi\""}"#.to_string()));
+
+    send(&mut client, true, TEXT, br#"{"command": "stats-job 1"}"#);
+    let (_, stats) = receive(&mut client);
+    assert!(stats.starts_with(r#"{"response":"OK","body":"---\nid: 1\n"#), "{}", stats);
+
+    // Messages that aren't commands end the connection
+    send(&mut client, true, TEXT, b"delete 1\r\n");
+    let (opcode, status) = receive(&mut client);
+    assert_eq!((opcode, status), (CLOSE, String::from_utf8_lossy(&1007u16.to_be_bytes()).into_owned()));
+
+    server.shutdown();
+    listening.join().unwrap();
+}
+
+#[test]
+fn refuses_plain_http_requests() {
+    let server = Server::new(JobQueue::new());
+    let (addr, listening) = serve(&server);
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    client.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    let mut response = String::new();
+    client.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", response);
+
+    // Never having been a WebSocket, it doesn't count as a connection
+    let stats = server.job_queue().lock().unwrap().stats();
+    assert_eq!((stats.total_connections, stats.listeners.len()), (0, 0));
+
+    server.shutdown();
+    listening.join().unwrap();
+}
+
+#[test]
+fn refuses_pages_of_other_origins() {
+    let server = Server::new(JobQueue::new()).allow_websocket_origins(vec!["https://example.com".to_string()]);
+    let (addr, listening) = serve(&server);
+
+    let (_, head) = connect(addr, "Origin: https://evil.example\r\n");
+    assert!(head.starts_with("HTTP/1.1 403 Forbidden\r\n"), "{}", head);
+
+    let (_, head) = connect(addr, "Origin: https://EXAMPLE.com\r\n");
+    assert!(head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"), "{}", head);
+
+    // Not a browser
+    let (_, head) = connect(addr, "");
+    assert!(head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"), "{}", head);
+
+    server.shutdown();
+    listening.join().unwrap();
+}
+
+#[test]
+fn closes_on_frames_out_of_place() {
+    let server = Server::new(JobQueue::new());
+    let (addr, listening) = serve(&server);
+    let protocol_error = String::from_utf8_lossy(&1002u16.to_be_bytes()).into_owned();
+
+    let frames: Vec<Vec<(bool, u8, &[u8])>> = vec![
+        // Fragmented ping
+        vec![(false, PING, b"a")],
+        // Control frame payload over 125 bytes
+        vec![(true, PING, &[b'a'; 126])],
+        // Continuation of nothing
+        vec![(true, 0x0, b"stats\r\n")],
+        // New message before the last one ended
+        vec![(false, TEXT, b"sta"), (true, TEXT, b"ts\r\n")],
+    ];
+    for frames in frames {
+        let (mut client, _) = connect(addr, "");
+        for &(fin, opcode, payload) in &frames {
+            send(&mut client, fin, opcode, payload);
+        }
+        assert_eq!(receive(&mut client), (CLOSE, protocol_error.clone()), "{:?}", frames);
+    }
+
+    server.shutdown();
+    listening.join().unwrap();
+}